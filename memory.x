@@ -2,7 +2,8 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 2M
+  /* the last 256K sector (0x081C0000) is reserved for the settings, see src/settings.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 2M - 256K
   RAM : ORIGIN = 0x20000000, LENGTH = 368K + 16K
 }
 
@@ -0,0 +1,408 @@
+//! Continuous acquisition by the circular DMA
+//!
+//! ADC1 converts the configured channel continuously at its own rate
+//! (sample time + 12 cycles of the ADC clock per sample), DMA2 stream 0 (channel 0) moves the samples
+//! into the circular buffer of two halves:
+//! ```not_rust
+//! | first half | second half |
+//!   ^ half transfer flag ^ transfer complete flag
+//! ```
+//! Each half is taken as soon as the DMA has passed it, while the DMA fills the other one,
+//! so the samples wait one half instead of the whole buffer before they are sent.
+//! The stream interrupts at the half transfer and at the transfer complete, the handler latches the half
+//! into `READY` and wakes `next`, the waiting task isn't polled in between.
+//! The halves are taken in order, the taken half is copied out before the DMA wraps back to it,
+//! if it's late (the other half is already complete when `next` is called or by the end of the copy)
+//! the overrun is counted in STATS.
+//!
+//! Any software conversion (see `Inputs::read`) stops the DMA acquisition first,
+//! the owner restarts it after reconfiguring the channel.
+//!
+//! With the `adc-dual` feature (`withDual`) ADC2 converts the same pin interleaved with ADC1 (the dual
+//! interleaved mode), doubling the sample rate: ADC2 starts `DELAY` ADC clocks after each ADC1 conversion start.
+//! The inputs are wired to both ADCs: PA3 - ADC123_IN3, PC0 - ADC123_IN10, PC3 - ADC123_IN13,
+//! so ADC2 takes the channel, its sample time, the resolution and the alignment from ADC1 at the start.
+//! The DMA moves the common data register (DMA mode 2), one 32-bit word per pair - ADC1 in the low half,
+//! ADC2 in the high one, so the halves of the buffer hold the samples in the time order.
+//! The timing constraints (checked by `main` at compile time):
+//! - DELAY is 5..=20 ADC clocks
+//! - the sample time is shorter than DELAY, the ADCs never sample the shared pin at once
+//! - DELAY is shorter than the conversion (the sample time + 12 clocks), ADC2 starts before ADC1 restarts,
+//!   the samples are spaced DELAY and the conversion - DELAY apart in turn, uniformly at the half of the conversion
+//!
+//! With the `dma-stamps` feature (`withStamps`, ADC1 only) the conversions aren't continuous: TIM4 TRGO triggers
+//! each one every `PACE_MARGIN_CYCLES` ADC clocks above the conversion (the trigger coming while the ADC converts
+//! would be ignored, the stamps would run ahead of the samples), the same edge is captured by TIM5
+//! into the stamps buffer paired with the samples one, see `timestamp`.
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::Poll;
+
+use defmt::*;
+use embassy_stm32::pac;
+use embassy_stm32::pac::dma::vals;
+#[cfg(feature = "adc-dual")]
+use embassy_stm32::peripherals::ADC2;
+use embassy_stm32::interrupt;
+use embassy_stm32::peripherals::DMA2_CH0;
+#[cfg(feature = "dma-stamps")]
+use embassy_stm32::peripherals::TIM4;
+#[cfg(feature = "dma-stamps")]
+use embassy_stm32::rcc::low_level::RccPeripheral;
+use embassy_sync::waitqueue::AtomicWaker;
+
+#[cfg(feature = "dma-stamps")]
+use crate::clocks;
+#[cfg(feature = "dma-stamps")]
+use crate::ext_trigger::ExtTrigger;
+use crate::log_level::Level;
+#[cfg(feature = "dma-stamps")]
+use crate::packet::TIMESTAMP_STRIDE;
+use crate::ring_log;
+use crate::stats;
+#[cfg(feature = "dma-stamps")]
+use crate::timestamp::{self, StampCapture};
+
+/// DMA2 stream serving ADC1
+const STREAM: usize = 0;
+/// ADC1 request of the stream
+const CHANNEL: u8 = 0;
+/// end of conversion status polls while the last continuous conversion completes
+const STOP_SPINS: u32 = 20_000;
+/// the smallest and the largest ADC2 delay of the dual interleaved mode, ADC clocks
+#[cfg(feature = "adc-dual")]
+pub const MIN_DUAL_DELAY: u32 = 5;
+#[cfg(feature = "adc-dual")]
+pub const MAX_DUAL_DELAY: u32 = 20;
+/// CCR MULTI, the dual interleaved mode of ADC1 and ADC2
+#[cfg(feature = "adc-dual")]
+const MULTI_DUAL_INTERLEAVED: u8 = 0b00111;
+/// CCR MULTI, the independent ADCs
+#[cfg(feature = "adc-dual")]
+const MULTI_INDEPENDENT: u8 = 0;
+/// the ADC clocks of the stamped conversion trigger period above the conversion
+#[cfg(feature = "dma-stamps")]
+pub const PACE_MARGIN_CYCLES: u32 = 1;
+/// TIM4 CR2 MMS, the update event is the trigger output
+#[cfg(feature = "dma-stamps")]
+const MMS_UPDATE: u8 = 0b010;
+
+/// the DMA acquisition is running
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// the complete halves not taken yet, bit 0 - the first half, bit 1 - the second one
+static READY: AtomicU8 = AtomicU8::new(0);
+/// the task waiting in `next`, woken by the stream interrupt and by `stop`
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// The circular buffer of the DMA acquisition
+pub struct AdcDma {
+    buf: &'static mut [u16],
+    /// the length of the half
+    half: usize,
+    /// the samples taken from each half, the half of the dual mode is rounded up to the pair
+    requested: usize,
+    /// the half to be taken next, 0 or 1
+    next: usize,
+    /// the ADC2 delay of the dual interleaved mode in ADC clocks, None - ADC1 only
+    dualDelay: Option<u32>,
+    /// the capture of the stamps and the TIM4 period of the conversion trigger, None - the continuous conversions
+    #[cfg(feature = "dma-stamps")]
+    stamps: Option<(StampCapture, u32)>,
+}
+
+impl AdcDma {
+    /// `buf` - two halves of the longest block
+    pub fn new(_dma: DMA2_CH0, buf: &'static mut [u16]) -> Self {
+        unsafe {
+            pac::RCC.ahb1enr().modify(|w| w.set_dma2en(true));
+            cortex_m::peripheral::NVIC::unmask(pac::Interrupt::DMA2_STREAM0);
+        }
+        Self {
+            buf,
+            half: 0,
+            requested: 0,
+            next: 0,
+            dualDelay: None,
+            #[cfg(feature = "dma-stamps")]
+            stamps: None,
+        }
+    }
+    /// interleaves the conversions of ADC2 `delay` ADC clocks after the ones of ADC1,
+    /// the `delay` is validated against the sample time by the caller
+    #[cfg(feature = "adc-dual")]
+    pub fn withDual(mut self, _adc2: ADC2, delay: u32) -> Self {
+        unsafe { pac::RCC.apb2enr().modify(|w| w.set_adc2en(true)) };
+        self.dualDelay = Some(delay.clamp(MIN_DUAL_DELAY, MAX_DUAL_DELAY));
+        self
+    }
+    /// triggers each conversion by TIM4 every `conversionCycles` + `PACE_MARGIN_CYCLES` ADC clocks
+    /// and stamps it by the `capture`, see `timestamp`
+    #[cfg(feature = "dma-stamps")]
+    pub fn withStamps(mut self, _tim4: TIM4, capture: StampCapture, conversionCycles: u32) -> Self {
+        TIM4::enable();
+        let timerHz = TIM4::frequency().0 as u64;
+        let period = (conversionCycles + PACE_MARGIN_CYCLES) as u64 * timerHz / clocks::adcHz() as u64;
+        self.stamps = Some((capture, period as u32));
+        self
+    }
+    /// return true if the conversions are stamped by the hardware, see `withStamps`
+    pub fn isStamped(&self) -> bool {
+        #[cfg(feature = "dma-stamps")]
+        return self.stamps.is_some();
+        #[cfg(not(feature = "dma-stamps"))]
+        false
+    }
+    /// samples per ADC1 conversion, 2 in the dual interleaved mode
+    pub fn samplesPerConversion(&self) -> u32 {
+        match self.dualDelay {
+            Some(_) => 2,
+            None => 1,
+        }
+    }
+    /// the samples taken from each half being acquired, as requested by `start`
+    pub fn half(&self) -> usize {
+        self.requested
+    }
+    /// starts the continuous conversion of the configured channel into the halves of `half` samples
+    pub fn start(&mut self, half: usize) {
+        let half = half.min(self.buf.len() / 2);
+        self.half = half;
+        self.requested = half;
+        self.next = 0;
+        let dma = pac::DMA2;
+        let stream = dma.st(STREAM);
+        // the dual mode moves the pair of samples per transfer, the odd half is rounded up,
+        // its last sample is dropped
+        let (source, size, transfers) = match self.dualDelay {
+            #[cfg(feature = "adc-dual")]
+            Some(delay) => {
+                let half = (half + 1).min(self.buf.len() / 2) & !1;
+                self.half = half;
+                startDual(delay);
+                (pac::ADC_COMMON.cdr().ptr() as u32, vals::Size::BITS32, half)
+            }
+            _ => (pac::ADC1.dr().ptr() as u32, vals::Size::BITS16, half * 2),
+        };
+        // the DMA requests come from the common data register in the dual mode
+        let adc1Dma = self.dualDelay.is_none();
+        let paced = self.isStamped();
+        #[cfg(feature = "dma-stamps")]
+        if let Some((capture, _)) = self.stamps.as_mut() {
+            capture.start(half);
+        }
+        unsafe {
+            stream.cr().write(|w| w.set_en(false));
+            while stream.cr().read().en() {}
+            clearFlags();
+            READY.store(0, Ordering::Relaxed);
+            stream.par().write_value(source);
+            stream.m0ar().write_value(self.buf.as_mut_ptr() as u32);
+            stream.ndtr().write_value(pac::dma::regs::Ndtr(transfers as u32));
+            stream.cr().write(|w| {
+                w.set_chsel(CHANNEL);
+                w.set_dir(vals::Dir::PERIPHERALTOMEMORY);
+                w.set_psize(size);
+                w.set_msize(size);
+                w.set_pinc(vals::Inc::FIXED);
+                w.set_minc(vals::Inc::INCREMENTED);
+                w.set_circ(vals::Circ::ENABLED);
+                w.set_pl(vals::Pl::VERYHIGH);
+                w.set_htie(true);
+                w.set_tcie(true);
+                w.set_en(true);
+            });
+            pac::ADC1.cr2().modify(|w| {
+                w.set_dma(adc1Dma);
+                w.set_dds(pac::adc::vals::Dds::CONTINUOUS);
+                w.set_cont(!paced);
+                #[cfg(feature = "dma-stamps")]
+                if paced {
+                    w.set_extsel(ExtTrigger::Tim4Trgo.extsel());
+                    w.set_exten(pac::adc::vals::Exten::RISINGEDGE);
+                }
+            });
+            RUNNING.store(true, Ordering::Relaxed);
+            if !paced {
+                pac::ADC1.cr2().modify(|w| w.set_swstart(true));
+            }
+        }
+        #[cfg(feature = "dma-stamps")]
+        if let Some((_, period)) = self.stamps {
+            pace(period);
+        }
+        info!("[adc dma] started, {} samples per half", self.half);
+    }
+    /// copies the stamps of every `TIMESTAMP_STRIDE`-th sample of the half taken last by `next` into `out`,
+    /// returns the stamp of its first sample, None if the conversions aren't stamped
+    pub fn takeStamps(&self, out: &mut [u32]) -> Option<u32> {
+        #[cfg(feature = "dma-stamps")]
+        if let Some((capture, _)) = self.stamps.as_ref() {
+            return Some(capture.take(self.next ^ 1, TIMESTAMP_STRIDE, out));
+        }
+        let _ = out;
+        None
+    }
+    /// waits for the next half and copies it into `out`, returns false if the acquisition is stopped
+    pub async fn next(&mut self, out: &mut [u16]) -> bool {
+        // the other half complete as well, the DMA has wrapped back into this one before the call
+        let late = isReady(self.next) && isReady(self.next ^ 1);
+        poll_fn(|cx| {
+            WAKER.register(cx.waker());
+            match isReady(self.next) || !isRunning() {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        })
+        .await;
+        if !isReady(self.next) {
+            return false;
+        }
+        clearReady(self.next);
+        let start = self.next * self.half;
+        out[..self.requested].copy_from_slice(&self.buf[start..start + self.requested]);
+        self.next ^= 1;
+        // the DMA has completed the other half before the copy ended, it's writing into the copied one
+        if late || isReady(self.next) {
+            warn!("[adc dma] half {} overrun", self.next ^ 1);
+            ring_log::push(Level::Warn, format_args!("adc dma half {} overrun", self.next ^ 1));
+            stats::inc(&stats::DMA_OVERRUNS);
+        }
+        true
+    }
+}
+
+/// return true if the DMA acquisition is running
+pub fn isRunning() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// stops the DMA acquisition if it's running, the ADC is ready for the software conversions
+pub fn stop() {
+    if !RUNNING.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    #[cfg(feature = "dma-stamps")]
+    unsafe {
+        pac::TIM4.cr1().modify(|w| w.set_cen(false));
+        timestamp::stopCapture();
+    }
+    let regs = pac::ADC1;
+    unsafe {
+        regs.cr2().modify(|w| {
+            w.set_cont(false);
+            w.set_dma(false);
+            #[cfg(feature = "dma-stamps")]
+            w.set_exten(pac::adc::vals::Exten::DISABLED);
+        });
+        // the conversion in progress completes, its data is dropped
+        for _ in 0..STOP_SPINS {
+            if regs.sr().read().eoc() {
+                break;
+            }
+        }
+        regs.dr().read();
+        pac::DMA2.st(STREAM).cr().modify(|w| w.set_en(false));
+        clearFlags();
+    }
+    READY.store(0, Ordering::Relaxed);
+    // `next` waiting for the half returns
+    WAKER.wake();
+    #[cfg(feature = "adc-dual")]
+    stopDual();
+    info!("[adc dma] stopped");
+}
+
+/// starts TIM4 triggering the conversions (and the stamp captures) every `period` timer ticks
+#[cfg(feature = "dma-stamps")]
+fn pace(period: u32) {
+    let regs = pac::TIM4;
+    unsafe {
+        regs.cr1().modify(|w| w.set_cen(false));
+        regs.psc().write(|w| w.set_psc(0));
+        regs.arr().write(|w| w.set_arr((period - 1) as u16));
+        regs.cr2().modify(|w| w.set_mms(MMS_UPDATE));
+        // load the period, the update of UG itself is the first trigger
+        regs.egr().write(|w| w.set_ug(true));
+        regs.cr1().modify(|w| w.set_cen(true));
+    }
+}
+
+/// configures ADC2 as ADC1 (the channel, the sample time, the resolution), powers it up
+/// and switches the pair into the dual interleaved mode with the `delay`
+#[cfg(feature = "adc-dual")]
+fn startDual(delay: u32) {
+    let (master, slave) = (pac::ADC1, pac::ADC2);
+    unsafe {
+        slave.cr1().write_value(master.cr1().read());
+        slave.cr2().modify(|w| w.set_align(master.cr2().read().align()));
+        slave.smpr1().write_value(master.smpr1().read());
+        slave.smpr2().write_value(master.smpr2().read());
+        slave.sqr1().write_value(master.sqr1().read());
+        slave.sqr3().write_value(master.sqr3().read());
+        if !slave.cr2().read().adon() {
+            slave.cr2().modify(|w| w.set_adon(true));
+            // ADC stabilization time, 3 us at 216 MHz
+            cortex_m::asm::delay(650);
+        }
+        slave.cr2().modify(|w| w.set_cont(true));
+        pac::ADC_COMMON.ccr().modify(|w| {
+            w.set_multi(MULTI_DUAL_INTERLEAVED);
+            w.set_delay((delay - MIN_DUAL_DELAY) as u8);
+            w.set_dma(pac::adc::vals::Dma::MODE2);
+            w.set_dds(pac::adc::vals::Dds::CONTINUOUS);
+        });
+    }
+}
+
+/// returns the ADCs into the independent mode, ADC1 converts alone again
+#[cfg(feature = "adc-dual")]
+fn stopDual() {
+    unsafe {
+        pac::ADC2.cr2().modify(|w| w.set_cont(false));
+        pac::ADC_COMMON.ccr().modify(|w| {
+            w.set_multi(MULTI_INDEPENDENT);
+            w.set_dma(pac::adc::vals::Dma::DISABLED);
+        });
+    }
+}
+
+/// latches the half transfer (the first half) and the transfer complete (the second one) into `READY`,
+/// the stream is driven by this module only
+#[interrupt]
+unsafe fn DMA2_STREAM0() {
+    let isr = pac::DMA2.isr(STREAM / 4).read();
+    let mut ready = 0;
+    if isr.htif(STREAM % 4) {
+        ready |= 1 << 0;
+    }
+    if isr.tcif(STREAM % 4) {
+        ready |= 1 << 1;
+    }
+    pac::DMA2.ifcr(STREAM / 4).write(|w| {
+        w.set_htif(STREAM % 4, true);
+        w.set_tcif(STREAM % 4, true);
+    });
+    READY.fetch_or(ready, Ordering::Release);
+    WAKER.wake();
+}
+
+/// return true if the `half` is complete and not taken yet
+fn isReady(half: usize) -> bool {
+    READY.load(Ordering::Acquire) & (1 << half) != 0
+}
+
+fn clearReady(half: usize) {
+    READY.fetch_and(!(1 << half), Ordering::AcqRel);
+}
+
+fn clearFlags() {
+    unsafe {
+        pac::DMA2.ifcr(STREAM / 4).write(|w| {
+            w.set_htif(STREAM % 4, true);
+            w.set_tcif(STREAM % 4, true);
+            w.set_teif(STREAM % 4, true);
+            w.set_dmeif(STREAM % 4, true);
+            w.set_feif(STREAM % 4, true);
+        });
+    }
+}
@@ -0,0 +1,237 @@
+//! Duplicate address detection of the static IP
+//!
+//! Two boards flashed with the same static IP answer ARP for it in turn, the peers flip between their MACs
+//! and the sessions break erratically. The stack doesn't expose ARP, so `ArpGuard` wraps the Ethernet driver:
+//! - at the startup `probe` sends the ARP probes (RFC 5227: the sender IP 0.0.0.0, the target - the guarded IP),
+//!   the host owning the address replies
+//! - every received ARP packet is checked, the one claiming the guarded IP from the other MAC
+//!   (the reply to the probe, the request or the announcement of that host) records the conflict,
+//!   logged once, also at any time later
+//! - the probe of the other host for the guarded IP (the request with the sender IP 0.0.0.0) records it too,
+//!   the two boards booting together with the same address only see each other's probes
+//! ```not_rust
+//! | dst: [u8; 6] | src: [u8; 6] | 0x0806 | htype: 1 | ptype: 0x0800 | 6 | 4 | oper: u16 |
+//! | sender MAC: [u8; 6] | sender IP: [u8; 4] | target MAC: [u8; 6] | target IP: [u8; 4] |
+//! ```
+//! The DHCP address isn't guarded, the server hands it out once.
+//!
+//! The same way `resolve` sends the ARP requests for the given IP (the gateway probe at the startup)
+//! and waits for its reply, independent of the stack's own ARP cache.
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use core::task::Context;
+
+use defmt::*;
+use embassy_net_driver::{Capabilities, Driver, LinkState, RxToken, TxToken};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_sync::waitqueue::AtomicWaker;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+use crate::log_level::Level;
+use crate::ring_log;
+
+/// the ARP frame without the padding
+const FRAME_SIZE: usize = 42;
+const ETHERTYPE_ARP: [u8; 2] = [0x08, 0x06];
+/// | htype | ptype | hlen | plen | of Ethernet / IPv4
+const ARP_IPV4: [u8; 6] = [0x00, 0x01, 0x08, 0x00, 6, 4];
+const OPER_REQUEST: [u8; 2] = [0x00, 0x01];
+
+/// What the board does when its static IP is taken by the other host
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum DuplicateIp {
+    /// logs the conflict and goes on
+    Warn,
+    /// doesn't bind the data socket until the address is free
+    Refuse,
+}
+
+/// the guarded IP, 0 - none
+static GUARDED: AtomicU32 = AtomicU32::new(0);
+/// the probes to be sent
+static PROBES: AtomicU8 = AtomicU8::new(0);
+/// the MAC of the host claiming the guarded IP
+static CONFLICT: Mutex<CriticalSectionRawMutex, Cell<Option<[u8; 6]>>> = Mutex::new(Cell::new(None));
+/// the stack polling the driver, woken to send the probe
+static WAKER: AtomicWaker = AtomicWaker::new();
+/// the IP being resolved, 0 - none
+static RESOLVING: AtomicU32 = AtomicU32::new(0);
+/// the sender IP of the resolve request
+static RESOLVE_SENDER: AtomicU32 = AtomicU32::new(0);
+/// the resolve requests to be sent
+static REQUESTS: AtomicU8 = AtomicU8::new(0);
+/// the MAC answering for the resolved IP
+static RESOLVED: Signal<CriticalSectionRawMutex, [u8; 6]> = Signal::new();
+
+/// guards the static `ip`
+pub fn watch(ip: [u8; 4]) {
+    GUARDED.store(u32::from_be_bytes(ip), Ordering::Relaxed);
+}
+
+/// the MAC of the host claiming the guarded IP if any
+pub fn conflict() -> Option<[u8; 6]> {
+    CONFLICT.lock(|conflict| conflict.get())
+}
+
+/// sends `count` probes `period` apart, the recorded conflict is dropped first,
+/// returns the MAC of the host claiming the guarded IP if any
+pub async fn probe(count: u8, period: Duration) -> Option<[u8; 6]> {
+    CONFLICT.lock(|conflict| conflict.set(None));
+    for _ in 0..count {
+        PROBES.fetch_add(1, Ordering::Relaxed);
+        WAKER.wake();
+        Timer::after(period).await;
+        if conflict().is_some() {
+            break;
+        }
+    }
+    conflict()
+}
+
+/// sends the ARP request for the `target` IP from the `sender` one every `period`,
+/// returns the MAC of the reply or None if there is none within `timeout`
+pub async fn resolve(target: [u8; 4], sender: [u8; 4], timeout: Duration, period: Duration) -> Option<[u8; 6]> {
+    RESOLVED.reset();
+    RESOLVE_SENDER.store(u32::from_be_bytes(sender), Ordering::Relaxed);
+    RESOLVING.store(u32::from_be_bytes(target), Ordering::Relaxed);
+    let deadline = Instant::now() + timeout;
+    let mut resolved = None;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        REQUESTS.fetch_add(1, Ordering::Relaxed);
+        WAKER.wake();
+        if let Ok(mac) = with_timeout(remaining.min(period), RESOLVED.wait()).await {
+            resolved = Some(mac);
+            break;
+        }
+    }
+    RESOLVING.store(0, Ordering::Relaxed);
+    REQUESTS.store(0, Ordering::Relaxed);
+    resolved
+}
+
+/// The Ethernet driver checking the ARP traffic for the guarded IP
+pub struct ArpGuard<D> {
+    inner: D,
+    mac: [u8; 6],
+}
+
+impl<D: Driver> ArpGuard<D> {
+    pub fn new(inner: D) -> Self {
+        let mac = inner.ethernet_address();
+        Self { inner, mac }
+    }
+    /// sends the pending probe if the TX descriptor is free
+    fn sendProbe(&mut self, cx: &mut Context) {
+        let ip = GUARDED.load(Ordering::Relaxed);
+        if ip == 0 || PROBES.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        if let Some(tx) = self.inner.transmit(cx) {
+            tx.consume(FRAME_SIZE, |frame| writeRequest(frame, &self.mac, [0; 4], ip.to_be_bytes()));
+            PROBES.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+    /// sends the pending resolve request if the TX descriptor is free
+    fn sendRequest(&mut self, cx: &mut Context) {
+        let target = RESOLVING.load(Ordering::Relaxed);
+        if target == 0 || REQUESTS.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let sender = RESOLVE_SENDER.load(Ordering::Relaxed).to_be_bytes();
+        if let Some(tx) = self.inner.transmit(cx) {
+            tx.consume(FRAME_SIZE, |frame| writeRequest(frame, &self.mac, sender, target.to_be_bytes()));
+            REQUESTS.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<D: Driver> Driver for ArpGuard<D> {
+    type RxToken<'a> = GuardedRx<D::RxToken<'a>> where Self: 'a;
+    type TxToken<'a> = D::TxToken<'a> where Self: 'a;
+
+    fn receive(&mut self, cx: &mut Context) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        WAKER.register(cx.waker());
+        self.sendProbe(cx);
+        self.sendRequest(cx);
+        let mac = self.mac;
+        self.inner.receive(cx).map(|(rx, tx)| (GuardedRx { inner: rx, mac }, tx))
+    }
+    fn transmit(&mut self, cx: &mut Context) -> Option<Self::TxToken<'_>> {
+        self.inner.transmit(cx)
+    }
+    fn link_state(&mut self, cx: &mut Context) -> LinkState {
+        self.inner.link_state(cx)
+    }
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+    fn ethernet_address(&self) -> [u8; 6] {
+        self.mac
+    }
+}
+
+/// The received frame checked before the stack takes it
+pub struct GuardedRx<T> {
+    inner: T,
+    mac: [u8; 6],
+}
+
+impl<T: RxToken> RxToken for GuardedRx<T> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mac = self.mac;
+        self.inner.consume(|frame| {
+            check(frame, &mac);
+            f(frame)
+        })
+    }
+}
+
+/// writes the ARP request for the `target` IP from `mac` and the `sender` IP into `frame`,
+/// the probe has the sender IP 0.0.0.0
+fn writeRequest(frame: &mut [u8], mac: &[u8; 6], sender: [u8; 4], target: [u8; 4]) {
+    frame[0..6].fill(0xFF);
+    frame[6..12].copy_from_slice(mac);
+    frame[12..14].copy_from_slice(&ETHERTYPE_ARP);
+    frame[14..20].copy_from_slice(&ARP_IPV4);
+    frame[20..22].copy_from_slice(&OPER_REQUEST);
+    frame[22..28].copy_from_slice(mac);
+    frame[28..32].copy_from_slice(&sender);
+    frame[32..38].fill(0);
+    frame[38..42].copy_from_slice(&target);
+}
+
+/// records the conflict if the ARP `frame` claims or probes the guarded IP from the MAC other than `own`,
+/// passes the MAC of the IP being resolved to `resolve`
+fn check(frame: &[u8], own: &[u8; 6]) {
+    if frame.len() < FRAME_SIZE || frame[12..14] != ETHERTYPE_ARP || frame[14..20] != ARP_IPV4 {
+        return;
+    }
+    let resolving = RESOLVING.load(Ordering::Relaxed);
+    if resolving != 0 && frame[28..32] == resolving.to_be_bytes() && frame[22..28] != *own {
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&frame[22..28]);
+        RESOLVED.signal(mac);
+    }
+    let ip = GUARDED.load(Ordering::Relaxed);
+    let (senderMac, senderIp, targetIp) = (&frame[22..28], &frame[28..32], &frame[38..42]);
+    if ip == 0 || senderMac == own {
+        return;
+    }
+    let probe = frame[20..22] == OPER_REQUEST && senderIp == [0; 4] && targetIp == ip.to_be_bytes();
+    if senderIp != ip.to_be_bytes() && !probe {
+        return;
+    }
+    let mut mac = [0; 6];
+    mac.copy_from_slice(senderMac);
+    let first = CONFLICT.lock(|conflict| conflict.replace(Some(mac)).is_none());
+    if first {
+        let ip = ip.to_be_bytes();
+        error!("[arp] address {} is also used by {:02x}, give the boards their own addresses", ip, mac);
+        ring_log::push(Level::Error, format_args!("IP conflict with {:02x?}", mac));
+    }
+}
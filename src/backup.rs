@@ -0,0 +1,101 @@
+//! Last session client kept in the RTC backup registers
+//!
+//! The registers survive the reset (watchdog, REBOOT) but not the power loss unless VBAT is supplied.
+//! Layout:
+//! ```not_rust
+//! | BKP0R: MAGIC | BKP1R: ip | BKP2R: port |
+//! | BKP0R: MAGIC_V6 | BKP1R: - | BKP2R: port | BKP3R..=BKP6R: ipv6 |
+//! | BKP7R: watchdog resets |
+//! ```
+//! the IPv6 client is kept with the `ipv6` feature only, the watchdog resets are counted for `health`.
+//! The record is written when the session starts and cleared when the client stops it,
+//! so only the sessions interrupted by the reset are restarted.
+//! The client read back is validated, the invalid one (the corrupted registers) is cleared.
+#[cfg(feature = "ipv6")]
+use embassy_net::Ipv6Address;
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address};
+use embassy_stm32::pac;
+
+use crate::client;
+use crate::session::isValidClient;
+
+const MAGIC: u32 = 0x434C_4E54;
+#[cfg(feature = "ipv6")]
+const MAGIC_V6: u32 = 0x434C_4E36;
+const REG_MAGIC: usize = 0;
+const REG_IP: usize = 1;
+const REG_PORT: usize = 2;
+/// the first of the 4 registers of the IPv6 address, the most significant word first
+#[cfg(feature = "ipv6")]
+const REG_IP6: usize = 3;
+const REG_WATCHDOG_RESETS: usize = 7;
+
+/// enables the write access to the backup domain
+pub fn init() {
+    unsafe {
+        pac::RCC.apb1enr().modify(|w| w.set_pwren(true));
+        pac::PWR.cr1().modify(|w| w.set_dbp(true));
+    }
+}
+
+/// stores the session client `remote`, the IPv6 ones with the `ipv6` feature only
+pub fn store(remote: IpEndpoint) {
+    match remote.addr {
+        IpAddress::Ipv4(addr) => {
+            write(REG_IP, u32::from_be_bytes(addr.0));
+            write(REG_PORT, remote.port as u32);
+            write(REG_MAGIC, MAGIC);
+        }
+        #[cfg(feature = "ipv6")]
+        IpAddress::Ipv6(addr) => {
+            for (i, word) in addr.0.chunks(4).enumerate() {
+                write(REG_IP6 + i, u32::from_be_bytes([word[0], word[1], word[2], word[3]]));
+            }
+            write(REG_PORT, remote.port as u32);
+            write(REG_MAGIC, MAGIC_V6);
+        }
+        #[allow(unreachable_patterns)]
+        _ => clear(),
+    }
+}
+
+/// returns the stored client, None if nothing is stored
+pub fn load() -> Option<IpEndpoint> {
+    let addr: IpAddress = match read(REG_MAGIC) {
+        MAGIC => Ipv4Address(read(REG_IP).to_be_bytes()).into(),
+        #[cfg(feature = "ipv6")]
+        MAGIC_V6 => {
+            let mut addr = [0; 16];
+            for (i, word) in addr.chunks_mut(4).enumerate() {
+                word.copy_from_slice(&read(REG_IP6 + i).to_be_bytes());
+            }
+            Ipv6Address(addr).into()
+        }
+        _ => return None,
+    };
+    // the corrupted registers are cleared, never autostarted to
+    client::prune(Some(IpEndpoint::new(addr, read(REG_PORT) as u16)), |client| isValidClient(*client), clear)
+}
+
+/// the watchdog resets counted since the backup domain was powered
+pub fn watchdogResets() -> u32 {
+    read(REG_WATCHDOG_RESETS)
+}
+
+/// counts the watchdog reset
+pub fn countWatchdogReset() {
+    write(REG_WATCHDOG_RESETS, watchdogResets().wrapping_add(1));
+}
+
+/// forgets the stored client
+pub fn clear() {
+    write(REG_MAGIC, 0);
+}
+
+fn read(index: usize) -> u32 {
+    unsafe { pac::RTC.bkpr(index).read().bkp() }
+}
+
+fn write(index: usize, value: u32) {
+    unsafe { pac::RTC.bkpr(index).write(|w| w.set_bkp(value)) }
+}
@@ -0,0 +1,48 @@
+//! Discovery beacon broadcast while no session is running
+//!
+//! The board sends the control reply to the limited broadcast `255.255.255.255:PORT` periodically,
+//! so the host tool finds the freshly booted board without knowing its address:
+//! ```not_rust
+//! | SYN | BEACON | ip: [u8; 4] | mac: [u8; 6] | control port: u16 | version len: u8 | version: ascii |
+//! ```
+//! The beacon pauses while the session runs and resumes after it ends.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_net::Ipv4Address;
+
+use crate::control;
+
+/// the port the host tool listens on
+pub const PORT: u16 = 15181;
+pub const BROADCAST: Ipv4Address = Ipv4Address([255, 255, 255, 255]);
+/// the firmware version carried by the beacon
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// the session is running, the beacon is paused
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// pauses the beacon while the session runs
+pub fn pause() {
+    PAUSED.store(true, Ordering::Relaxed);
+}
+
+/// resumes the beacon after the session
+pub fn resume() {
+    PAUSED.store(false, Ordering::Relaxed);
+}
+
+/// return true if the beacon is paused
+pub fn isPaused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// writes the beacon into `buf`, returns its length
+pub fn write(buf: &mut [u8], addr: Ipv4Address, mac: &[u8; 6], port: u16) -> usize {
+    let mut payload = [0; 13 + VERSION.len()];
+    payload[0..4].copy_from_slice(addr.as_bytes());
+    payload[4..10].copy_from_slice(mac);
+    payload[10..12].copy_from_slice(&port.to_be_bytes());
+    payload[12] = VERSION.len() as u8;
+    payload[13..].copy_from_slice(VERSION.as_bytes());
+    control::reply(buf, control::BEACON, &payload)
+}
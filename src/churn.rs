@@ -0,0 +1,108 @@
+//! Handshake rate limit
+//!
+//! Each handshake restarts the session (and logs it), so the client reconnecting in a tight loop
+//! thrashes the session state and floods the log. The handshakes are counted per address in the small table,
+//! more than `MAX_HANDSHAKES` within `WINDOW` put the address into the `COOLDOWN`, its handshakes are dropped
+//! (counted in STATS, logged once per cooldown) until it passes. The table holds the recent `TABLE_SIZE` addresses,
+//! the least recent one not cooling down gives its slot to the new one.
+//! The retransmitted handshake of the `CONFIRM_ACK` session isn't counted, it's answered with the same acknowledgment.
+use core::cell::RefCell;
+
+use defmt::warn;
+use embassy_net::IpAddress;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+use crate::log_level::Level;
+use crate::ring_log;
+use crate::stats;
+
+/// handshakes of one address accepted within `WINDOW`
+pub const MAX_HANDSHAKES: u8 = 5;
+pub const WINDOW: Duration = Duration::from_secs(1);
+/// the handshakes of the address over the limit are dropped this long
+pub const COOLDOWN: Duration = Duration::from_secs(5);
+/// addresses tracked at once
+const TABLE_SIZE: usize = 4;
+
+/// Recent handshakes of the address
+#[derive(Clone, Copy)]
+struct Entry {
+    addr: IpAddress,
+    /// the start of the counting window
+    since: Instant,
+    count: u8,
+    /// the handshakes are dropped until, None - not cooling down
+    blockedUntil: Option<Instant>,
+}
+
+static TABLE: Mutex<CriticalSectionRawMutex, RefCell<[Option<Entry>; TABLE_SIZE]>> =
+    Mutex::new(RefCell::new([None; TABLE_SIZE]));
+
+/// Result of counting the handshake
+enum Verdict {
+    Admitted,
+    Dropped,
+    /// the address is over the limit from this handshake on
+    CooldownStarted,
+}
+
+/// counts the handshake of `addr` received `now`, returns false if it has to be dropped
+pub fn admit(addr: IpAddress, now: Instant) -> bool {
+    let verdict = TABLE.lock(|table| count(&mut table.borrow_mut(), addr, now));
+    match verdict {
+        Verdict::Admitted => return true,
+        Verdict::CooldownStarted => {
+            warn!(
+                "[churn] over {} handshakes per {} ms from {:?}, dropped for {} ms",
+                MAX_HANDSHAKES,
+                WINDOW.as_millis(),
+                addr,
+                COOLDOWN.as_millis()
+            );
+            ring_log::push(Level::Warn, format_args!("churn cooldown of {}", addr));
+        }
+        Verdict::Dropped => {}
+    }
+    stats::inc(&stats::HANDSHAKES_REJECTED);
+    false
+}
+
+/// counts the handshake of `addr` in the `table`
+fn count(table: &mut [Option<Entry>; TABLE_SIZE], addr: IpAddress, now: Instant) -> Verdict {
+    let tracked = table.iter().position(|entry| entry.map_or(false, |entry| entry.addr == addr));
+    let free = || table.iter().position(|entry| entry.is_none());
+    let leastRecent = || {
+        let candidates = table.iter().enumerate().filter_map(|(slot, entry)| entry.map(|entry| (slot, entry)));
+        candidates
+            .filter(|(_, entry)| !isBlocked(entry, now))
+            .min_by_key(|(_, entry)| entry.since)
+            .map(|(slot, _)| slot)
+    };
+    let Some(slot) = tracked.or_else(free).or_else(leastRecent) else {
+        // every tracked address is cooling down, the new one is let through untracked
+        return Verdict::Admitted;
+    };
+    let entry = match (tracked, &mut table[slot]) {
+        (Some(_), Some(entry)) => entry,
+        (_, entry) => entry.insert(Entry { addr, since: now, count: 0, blockedUntil: None }),
+    };
+    if isBlocked(entry, now) {
+        return Verdict::Dropped;
+    }
+    if now.duration_since(entry.since) > WINDOW {
+        (entry.since, entry.count) = (now, 0);
+    }
+    entry.count += 1;
+    if entry.count > MAX_HANDSHAKES {
+        entry.blockedUntil = Some(now + COOLDOWN);
+        return Verdict::CooldownStarted;
+    }
+    Verdict::Admitted
+}
+
+/// return true if the `entry` is cooling down `now`
+fn isBlocked(entry: &Entry, now: Instant) -> bool {
+    entry.blockedUntil.map_or(false, |until| now < until)
+}
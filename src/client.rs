@@ -0,0 +1,106 @@
+//! Validity of the session client endpoints
+//!
+//! The data is sent to the unicast address and the non-zero port only: the client coming from the handshake
+//! or read back from the backup registers is checked before the session starts sending to it,
+//! the invalid stored one is pruned (the record cleared), the valid ones are kept as they are.
+//! The module depends on `core` only, so its tests run on the host apart from the firmware:
+//! `rustc --edition 2021 --test src/client.rs -o /tmp/client && /tmp/client`
+
+/// return true if the data can be sent to the address `addr` (IPv4 or IPv6 bytes) and the `port`:
+/// not the unspecified, broadcast or multicast address, not the zero port
+pub fn isValid(addr: &[u8], port: u16) -> bool {
+    let unicast = match addr {
+        [0, 0, 0, 0] | [255, 255, 255, 255] => false,
+        [first, _, _, _] => first & 0xF0 != 0xE0,
+        [0xFF, ..] => false,
+        _ => addr.len() == 16 && addr.iter().any(|byte| *byte != 0),
+    };
+    port != 0 && unicast
+}
+
+/// returns the `stored` client if `valid`, otherwise forgets it by `clear` and returns None
+pub fn prune<T>(stored: Option<T>, valid: impl FnOnce(&T) -> bool, clear: impl FnOnce()) -> Option<T> {
+    match stored {
+        Some(client) if valid(&client) => Some(client),
+        Some(_) => {
+            clear();
+            None
+        }
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    fn valid(client: &([u8; 4], u16)) -> bool {
+        isValid(&client.0, client.1)
+    }
+
+    #[test]
+    fn unicast_clients_are_valid() {
+        assert!(isValid(&[192, 168, 100, 10], 15180));
+        assert!(isValid(&[10, 0, 0, 1], 1));
+        let mut v6 = [0; 16];
+        v6[0] = 0xFE;
+        v6[1] = 0x80;
+        v6[15] = 1;
+        assert!(isValid(&v6, 15180));
+    }
+
+    #[test]
+    fn bogus_clients_are_invalid() {
+        assert!(!isValid(&[192, 168, 100, 10], 0));
+        assert!(!isValid(&[0, 0, 0, 0], 15180));
+        assert!(!isValid(&[255, 255, 255, 255], 15180));
+        assert!(!isValid(&[224, 0, 0, 251], 15180));
+        assert!(!isValid(&[239, 1, 2, 3], 15180));
+        assert!(!isValid(&[0; 16], 15180));
+        let mut v6 = [0; 16];
+        v6[0] = 0xFF;
+        v6[1] = 0x02;
+        v6[15] = 0xFB;
+        assert!(!isValid(&v6, 15180));
+        assert!(!isValid(&[], 15180));
+        assert!(!isValid(&[192, 168, 100], 15180));
+    }
+
+    #[test]
+    fn bogus_stored_client_is_pruned() {
+        let stored = Cell::new(Some(([0, 0, 0, 0], 0)));
+        let client = prune(stored.get(), valid, || stored.set(None));
+        assert_eq!(client, None);
+        assert_eq!(stored.get(), None);
+    }
+
+    #[test]
+    fn valid_stored_client_is_kept() {
+        let stored = Cell::new(Some(([192, 168, 100, 10], 15180)));
+        let client = prune(stored.get(), valid, || stored.set(None));
+        assert_eq!(client, Some(([192, 168, 100, 10], 15180)));
+        assert_eq!(stored.get(), client);
+    }
+
+    #[test]
+    fn bogus_clients_are_pruned_valid_ones_kept() {
+        let mut stored = [
+            Some(([192, 168, 100, 10], 15180)),
+            Some(([224, 0, 0, 1], 15180)),
+            Some(([192, 168, 100, 11], 0)),
+            None,
+            Some(([192, 168, 100, 12], 15181)),
+        ];
+        for slot in stored.iter_mut() {
+            let kept = Cell::new(true);
+            let client = prune(*slot, valid, || kept.set(false));
+            assert_eq!(kept.get(), client.is_some() || slot.is_none());
+            *slot = client;
+        }
+        assert_eq!(
+            stored,
+            [Some(([192, 168, 100, 10], 15180)), None, None, None, Some(([192, 168, 100, 12], 15181))]
+        );
+    }
+}
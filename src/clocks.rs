@@ -0,0 +1,73 @@
+//! Clock profile check
+//!
+//! `embassy_stm32::init` configures the RCC from the requested `sys_ck`, the profile the peripherals
+//! don't tolerate isn't reported by it: the Ethernet MAC needs HCLK of 25..=216 MHz for the 100M RMII
+//! (the 50 MHz RMII reference itself comes from the PHY), the ADC clock is limited to 36 MHz.
+//! `validate` checks the applied frequencies right after the init and panics naming the broken one,
+//! so the wrong profile is the startup error instead of the link which never comes up.
+//! Both profiles of the binaries pass the check:
+//! - 216 MHz - HCLK 216 MHz, APB2 108 MHz, ADC 27 MHz
+//! - 200 MHz - HCLK 200 MHz, APB2 100 MHz, ADC 25 MHz
+//!
+//! The Ethernet works at either: the RMII reference (50 MHz, PA1) comes from the PHY, not from the RCC,
+//! and the MAC divides HCLK by 102 for MDC (2.1 or 1.96 MHz, within 2.5 MHz), nothing in the Ethernet
+//! needs the other frequency. The difference is on the acquisition side, so `main` runs at 216 MHz:
+//! - the ADC clock is APB2 / 4 - 27 MHz instead of 25 MHz, the 8 % higher conversion rate at the same sample time
+//! - the timestamps (see `timestamp`) count at 27 MHz, a quarter of the 108 MHz APB1 timer clock,
+//!   at 200 MHz the 100 MHz timer clock isn't its multiple, `timestamp::init` refuses it
+//!
+//! 200 MHz is the profile of the early interrupt experiment (`main_int.rs`, not built), it needs one flash
+//! wait state less (6 instead of 7) and the over-drive as well, `main` doesn't accept it.
+//! Both the ADC clock and the Ethernet clocks (the reference and MDC) are logged at the startup.
+use defmt::*;
+use embassy_stm32::pac;
+use embassy_stm32::rcc::get_freqs;
+
+const ETH_MIN_HCLK: u32 = 25_000_000;
+const ETH_MAX_HCLK: u32 = 216_000_000;
+const ADC_MAX_CLOCK: u32 = 36_000_000;
+/// the RMII reference clock provided by the PHY (LAN8742A of the Nucleo) on PA1
+const RMII_REF_HZ: u32 = 50_000_000;
+
+/// panics if the applied SYSCLK differs from `sysHz` or the Ethernet and ADC clocks are out of their limits,
+/// called after the ADC is initialized (it selects the ADC prescaler)
+pub fn validate(sysHz: u32) {
+    let clocks = unsafe { get_freqs() };
+    let sys = clocks.sys.0;
+    let hclk = clocks.ahb1.0;
+    let adc = adcHz();
+    if sys != sysHz {
+        panic!("[clocks] SYSCLK is {} Hz instead of the requested {} Hz", sys, sysHz);
+    }
+    if !(ETH_MIN_HCLK..=ETH_MAX_HCLK).contains(&hclk) {
+        panic!("[clocks] HCLK {} Hz is out of {}..={} Hz required by the Ethernet", hclk, ETH_MIN_HCLK, ETH_MAX_HCLK);
+    }
+    if adc > ADC_MAX_CLOCK {
+        panic!("[clocks] ADC clock {} Hz is above {} Hz", adc, ADC_MAX_CLOCK);
+    }
+    info!("[clocks] SYSCLK {} Hz, HCLK {} Hz, ADC {} Hz", sys, hclk, adc);
+    info!("[clocks] Ethernet RMII reference {} Hz (PHY), MDC {} Hz", RMII_REF_HZ, mdcHz(hclk));
+}
+
+/// the MDC clock the MAC derives from `hclk` (ETH_MACMIIAR CR ranges)
+fn mdcHz(hclk: u32) -> u32 {
+    let divider = match hclk {
+        0..=34_999_999 => 16,
+        35_000_000..=59_999_999 => 26,
+        60_000_000..=99_999_999 => 42,
+        100_000_000..=149_999_999 => 62,
+        _ => 102,
+    };
+    hclk / divider
+}
+
+/// the applied ADC clock
+pub fn adcHz() -> u32 {
+    unsafe { get_freqs() }.apb2.0 / adcPrescaler()
+}
+
+/// the APB2 divider of the ADC clock, ADCPRE: 0 - 2, 1 - 4, 2 - 6, 3 - 8
+fn adcPrescaler() -> u32 {
+    let adcpre = unsafe { pac::ADC_COMMON.ccr().read().adcpre() };
+    (adcpre.0 as u32 + 1) * 2
+}
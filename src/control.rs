@@ -0,0 +1,630 @@
+//! Control packets received on the UDP port
+//!
+//! Each packet starts with the `MAGIC` followed by the `SYN` byte, the opcode and the opcode specific payload:
+//! ```not_rust
+//! | MAGIC | SYN | opcode | payload... |
+//! ```
+//! The packets without the magic are cross-traffic on the port, they are dropped unparsed.
+//! The board replies with the `SYN` + opcode prefix (no magic).
+//! All multibyte values are big endian.
+use defmt::{info, warn, Format};
+use heapless::Vec;
+
+use crate::crc;
+use crate::inputs::{self, ChannelList, Inputs, MAX_CHANNELS, MAX_DIVIDER};
+use crate::log_level::Level;
+use crate::packet::{SampleFormat, Summary, MIN_INJECTED_EVERY};
+use crate::tlv;
+
+/// required prefix of the received control packets, empty - any packet is parsed
+pub const MAGIC: &[u8] = b"ADC0";
+pub const SYN: u8 = 22;
+/// handshake, starts streaming to the sender,
+/// payload (optional):
+/// | mode: u8 | format: u8 | block: u16 | blocks: u8 | [start delay: u32] | mode specific... | TLV records... |,
+/// `0` - new session, default format, default block size, blocks per datagram as needed
+pub const EOT: u8 = 4;
+/// handshake acknowledgment, sent by the board,
+/// payload: | seq: u32 | missed: u32 | format: u8 | block: u16 | blocks per datagram: u16 | page: u16 |
+/// requested: see `NegotiatedParams` | left aligned: u8 | byte shift: u8 | mux channels: u8 | mask bits: u8 |
+/// encoding: u8 | [crc: u16] |, the CRC of the `CONFIRM_ACK` handshake, byte shift - see `FORMAT_BYTE`,
+/// mux channels - the positions of the external mux (0 - none), the datagrams carry the mux positions,
+/// mask bits - the lowest bits of the counts zeroed (0 - none), see `TLV_MASK`,
+/// encoding - the sample encoding picked (`packet::CAP_*`), see `TLV_FORMATS`,
+/// the handshake which can't be served is answered | status: u8 | instead (`ERR_INVALID`)
+pub const ACK: u8 = 6;
+/// stops streaming, accepted from the client of the running session only,
+/// with `FLUSH_ON_STOP` the board replies after the last data packet (the end marker): | seq: u32 |,
+/// seq - the sequence following the last packet sent
+pub const CAN: u8 = 24;
+/// sets the runtime log level, payload: | level: u8 |
+pub const LOG_LEVEL: u8 = 0x10;
+/// requests the board state, reply: | stream format: see `StreamFormat` | see `stats` |
+pub const STATS: u8 = 0x11;
+/// requests the Ethernet link state, reply: | up: u8 | mode: u8 | mac: [u8; 6] |,
+/// mode: `0` - unknown (link down), `1` - 10M half, `2` - 10M full, `3` - 100M half, `4` - 100M full duplex
+pub const LINK_STATUS: u8 = 0x12;
+/// the local address obtained by DHCP has changed, sent by the board to the client of the running session,
+/// which has to handshake again with the new address, payload: | old: [u8; 4] | new: [u8; 4] |, zeros - no address
+pub const ADDR_CHANGED: u8 = 0x13;
+/// requests the acquisition configuration, offset 0xFFFF - not tared,
+/// reply: | stream format: see `StreamFormat` | resolution bits: u8 | sequence: u8 |
+/// count: u8 | channel: u8 | tare offset: u16 | ... |
+pub const GET_CONFIG: u8 = 0x14;
+/// measures the zero offsets of the channels, payload: | count: u8 | channel: u8 ... |, count 0 - all channels,
+/// reply: | count: u8 | channel: u8 | offset: u16 | ... |
+pub const TARE: u8 = 0x15;
+/// arms the capture of the triggered session, reply: | status: u8 |, `ERR_INVALID` - no triggered session,
+/// accepted from the client of the running session only, see `trigger`
+pub const ARM: u8 = 0x16;
+/// disarms the capture of the triggered session, the capture in progress completes, reply: | status: u8 |
+pub const DISARM: u8 = 0x17;
+/// fires the trigger at the next sample, arming the capture if needed, reply: | status: u8 |
+pub const FORCE_TRIGGER: u8 = 0x18;
+/// requests the ADC registers, built with the `adc-dump` feature only,
+/// reply: | sr | cr1 | cr2 | smpr1 | smpr2 | sqr1 | sqr2 | sqr3 | jsqr | ccr |, each u32
+pub const DUMP_ADC: u8 = 0x19;
+/// discovery beacon broadcast by the board while no session is running, see `beacon`
+pub const BEACON: u8 = 0x1A;
+/// sets the RTC to the Unix time, payload: | seconds: u32 |, `ERR_INVALID` - out of 2000..=2099,
+/// reply: | status: u8 | time: u32 | stamp: u32 |, time - the RTC read back (0 - unreadable),
+/// stamp - the sample timestamp counter at that moment, see `wall_clock`
+pub const SET_TIME: u8 = 0x1B;
+/// confirms the acknowledgment of the `CONFIRM_ACK` handshake, payload: | seq: u32 |, seq - of the acknowledgment
+pub const CONFIRM: u8 = 0x1C;
+/// converts the burst of the internal reference, pausing the stream,
+/// reply: | samples: u16 | timeouts: u16 | mean: u32 | std dev: u32 | min: u16 | max: u16 | cal: u16 | vdda: u16 |,
+/// see `selfcheck`
+pub const SELFCHECK: u8 = 0x1D;
+/// switches the session samples between the counts and the millivolts from the next datagram,
+/// payload: | units: u8 |, `0` - counts, `1` - millivolts, reply: | status: u8 |,
+/// `ERR_INVALID` - not streaming, `ERR_BUSY` - not the session client
+pub const SET_UNITS: u8 = 0x1E;
+/// reads the recent events back, payload: | from: u32 | (absent - 0), the replies: one per line from `from`
+/// (the oldest kept one if it's overwritten) | seq: u32 | uptime: u32 | level: u8 | text |,
+/// ended by the reply | next: u32 |, see `ring_log`
+pub const LOG: u8 = 0x1F;
+/// sets and persists the static IP (all zeros - DHCP), the board restarts to apply it,
+/// payload: | token: u32 | ip: [u8; 4] | prefix: u8 | gateway: [u8; 4] |, reply: | status: u8 |,
+/// while streaming accepted from the client of the running session only (`ERR_BUSY`), the token
+/// and the address are checked before the session is ended, the rejected one leaves it streaming
+pub const SET_IP: u8 = 0x20;
+/// resets the board, payload: | token: u32 |, reply: | status: u8 |,
+/// while streaming accepted from the client of the running session only (`ERR_BUSY`), the token is checked
+/// before the session is ended, the rejected one leaves it streaming
+pub const REBOOT: u8 = 0x21;
+/// holds the running session, the datagrams aren't sent until `RESUME`, payload: | acquiring: u8 | (absent - 0),
+/// `1` - the acquisition keeps running, the datagrams are dropped, `0` - halted, reply: | status: u8 |,
+/// `ERR_INVALID` - not streaming, `ERR_BUSY` - not the session client
+pub const PAUSE: u8 = 0x22;
+/// continues the paused session, the first datagram carries `FLAG_RESUMED`, reply: | status: u8 | as `PAUSE`
+pub const RESUME: u8 = 0x23;
+/// uploads the chunk of the loopback reference (the `loopback` feature), payload:
+/// | total: u16 | offset: u16 | samples: [u16; n] |, n up to `REFERENCE_CHUNK`, offset 0 starts the new reference,
+/// reply: | status: u8 |, `ERR_INVALID` - longer than `loopback::CAPACITY` or beyond `total`, see `loopback`
+#[cfg(feature = "loopback")]
+pub const REFERENCE: u8 = 0x24;
+/// the most samples of the `REFERENCE` chunk fitting the command buffer
+#[cfg(feature = "loopback")]
+pub const REFERENCE_CHUNK: usize = 27;
+/// sent by the board to the client when the session ends, payload: | reason: u8 | seq: u32 |,
+/// reason - see `session::StreamEndReason`, seq - the sequence following the last packet sent
+pub const ENDED: u8 = 0x25;
+/// changes the samples per block of the running session from the next logical block, payload: | samples: u16 |,
+/// reply: | status: u8 | samples: u16 | max: u16 |, samples - the accepted value (0 - rejected),
+/// the valid range `1..=max`, max 0 - not streaming, `ERR_BUSY` - not the session client, see `session`
+pub const SET_BLOCK_SIZE: u8 = 0x26;
+/// requests the board health summary, reply: | version: u8 | flags: u16 | link mode: u8 | adc faults: u32 |
+/// dma overruns: u32 | watchdog resets: u32 | uptime: u32 | temperature: i16 | tx fill: u8 | stack fill: u8 |,
+/// see `health`
+pub const HEALTH: u8 = 0x27;
+/// the periodic report sent by the board to the monitoring collector, payload:
+/// | seq: u32 | ip: [u8; 4] | mac: [u8; 6] | health... | stats... |, see `telemetry`
+pub const TELEMETRY: u8 = 0x28;
+
+/// reply status, command accepted
+pub const OK: u8 = 0;
+/// reply status, wrong token
+pub const ERR_TOKEN: u8 = 1;
+/// reply status, invalid command parameters
+pub const ERR_INVALID: u8 = 2;
+/// reply status, settings can't be stored
+pub const ERR_STORE: u8 = 3;
+/// reply status, the command would break the session of the other client
+pub const ERR_BUSY: u8 = 4;
+
+/// handshake mode modifier, the start delay field follows the fixed fields,
+/// the first sample is taken the delay (microseconds) after the handshake is received
+pub const START_DELAYED: u8 = 0x80;
+/// handshake mode modifier, the client confirms the acknowledgment by `CONFIRM`, the board resends it until then
+/// and starts streaming after, the acknowledgment ends with the CRC-16 of its preceding bytes
+pub const CONFIRM_ACK: u8 = 0x40;
+/// handshake mode modifier, the session starts with the metadata packet on the data path, see `packet::Metadata`
+pub const METADATA_FIRST: u8 = 0x20;
+/// handshake mode, continues the sequence of the session ended within the resume window
+pub const RESUME: u8 = 1;
+/// handshake mode, threshold triggered capture, payload: | threshold: u16 | pretrigger: u16 |
+pub const TRIGGERED: u8 = 2;
+/// handshake mode, channel sweep, payload: | dwell: u16 | count: u8 | channel: u8 ... |
+pub const SWEEP: u8 = 3;
+
+/// handshake TLV record, the channel configuration: | channel: u8 | gain: u16 | [divider: u8] |,
+/// divider - the sweep takes the channel every `divider`-th cycle, 1..=`MAX_DIVIDER`, 1 if missing
+pub const TLV_CHANNEL: u8 = 1;
+/// handshake TLV record, the injected channel converted every `every` samples: | channel: u8 | every: u16 |,
+/// every - at least `MIN_INJECTED_EVERY`
+pub const TLV_INJECTED: u8 = 2;
+/// handshake TLV record, the min / max envelope of each `group` samples is sent instead of them: | group: u16 |,
+/// group - at least 2
+pub const TLV_ENVELOPE: u8 = 3;
+/// handshake TLV record, the RMS / peak-to-peak summary is sent with each block: | only: u8 |,
+/// only `1` - the samples (the envelope) are left out, `0` - sent after the summary
+pub const TLV_SUMMARY: u8 = 4;
+/// handshake TLV record, the data bytes sent per second are capped: | bytes per second: u32 |,
+/// 0 - unlimited, see `pacer`
+pub const TLV_RATE_LIMIT: u8 = 5;
+/// handshake TLV record, the lowest bits of the samples are zeroed: | bits: u8 |, 1..=`MAX_MASK_BITS`,
+/// the bits of one conversion, the mask of the summed sequence grows by ceil(log2) of its length
+pub const TLV_MASK: u8 = 6;
+/// the most of the low bits masked
+pub const MAX_MASK_BITS: u8 = 8;
+/// handshake TLV record, the sample encodings the client decodes: | formats: u8 |, the `packet::CAP_*` bits,
+/// the board picks one, see `packet::negotiate`
+pub const TLV_FORMATS: u8 = 7;
+/// the shapes of the handshake records, only the channel config repeats (for the different channels)
+const HANDSHAKE_RULES: [tlv::Rule; 7] = [
+    tlv::Rule { kind: TLV_CHANNEL, len: 3..=4, repeated: true },
+    tlv::Rule { kind: TLV_INJECTED, len: 3..=3, repeated: false },
+    tlv::Rule { kind: TLV_ENVELOPE, len: 2..=2, repeated: false },
+    tlv::Rule { kind: TLV_SUMMARY, len: 1..=1, repeated: false },
+    tlv::Rule { kind: TLV_RATE_LIMIT, len: 4..=4, repeated: false },
+    tlv::Rule { kind: TLV_MASK, len: 1..=1, repeated: false },
+    tlv::Rule { kind: TLV_FORMATS, len: 1..=1, repeated: false },
+];
+
+/// Acquisition mode requested by the handshake
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Mode {
+    /// continuous streaming from zero sequence
+    Stream,
+    /// continuous streaming continuing the suspended session
+    Resume,
+    /// one block per trigger, `pretrigger` samples before the threshold crossing
+    Triggered { threshold: u16, pretrigger: u16 },
+    /// continuous streaming cycling over the `channels` each `dwell` datagrams
+    Sweep { dwell: u16, channels: ChannelList },
+}
+
+/// Per channel settings of the handshake
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct ChannelConfig {
+    pub channel: u8,
+    /// multiplier of the samples relative to the zero, `UNITY_GAIN` - 1.0
+    pub gain: u16,
+    /// the sweep takes the channel every `divider`-th cycle over the list, 1 - every cycle
+    pub divider: u8,
+}
+
+/// The channel converted by the injected group interleaved with the stream
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct Injected {
+    pub channel: u8,
+    /// regular samples per injected one
+    pub every: u16,
+}
+
+/// Session parameters requested by the handshake
+#[derive(Clone, Debug, PartialEq, Eq, Format)]
+pub struct Handshake {
+    pub mode: Mode,
+    pub format: SampleFormat,
+    /// samples per acquisition block, 0 - default
+    pub blockSamples: u16,
+    /// acquisition blocks per datagram, 0 - as needed to reach the minimum datagram size
+    pub blocksPerDatagram: u8,
+    /// microseconds from receiving the handshake to the first sample, None - start at once
+    pub startDelayUs: Option<u32>,
+    /// the client confirms the acknowledgment, see `CONFIRM_ACK`
+    pub confirmAck: bool,
+    /// the metadata packet precedes the first data packet, see `METADATA_FIRST`
+    pub metadataFirst: bool,
+    /// settings of the channels differing from the defaults
+    pub channelConfigs: Vec<ChannelConfig, MAX_CHANNELS>,
+    pub injected: Option<Injected>,
+    /// samples per min / max pair, None - all samples are sent
+    pub envelope: Option<u16>,
+    pub summary: Option<Summary>,
+    /// data bytes sent per second, None - unlimited
+    pub rateLimit: Option<u32>,
+    /// the lowest bits of the counts zeroed, 0 - none
+    pub maskBits: u8,
+    /// the sample encodings the client decodes, None - big endian only (the client predating the record)
+    pub formats: Option<u8>,
+}
+
+impl Default for Handshake {
+    /// new streaming session with the default settings, as the handshake without payload
+    fn default() -> Self {
+        Self {
+            mode: Mode::Stream,
+            format: SampleFormat::default(),
+            blockSamples: 0,
+            blocksPerDatagram: 0,
+            startDelayUs: None,
+            confirmAck: false,
+            metadataFirst: false,
+            channelConfigs: Vec::new(),
+            injected: None,
+            envelope: None,
+            summary: None,
+            rateLimit: None,
+            maskBits: 0,
+            formats: None,
+        }
+    }
+}
+
+impl Handshake {
+    /// parses the handshake payload, missing fields are defaults
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let format = SampleFormat::fromFlags(buf.get(1).copied().unwrap_or(0));
+        let blockSamples = match buf.get(2..4) {
+            Some([b0, b1]) => u16::from_be_bytes([*b0, *b1]),
+            _ => 0,
+        };
+        let blocksPerDatagram = buf.get(4).copied().unwrap_or(0);
+        let modeByte = buf.first().copied();
+        let (startDelayUs, specific) = match modeByte {
+            Some(mode) if mode & START_DELAYED != 0 => match buf.get(5..9)? {
+                [d0, d1, d2, d3] => (Some(u32::from_be_bytes([*d0, *d1, *d2, *d3])), &buf[9..]),
+                _ => return None,
+            },
+            _ => (None, buf.get(5..).unwrap_or(&[])),
+        };
+        let confirmAck = modeByte.map_or(false, |mode| mode & CONFIRM_ACK != 0);
+        let metadataFirst = modeByte.map_or(false, |mode| mode & METADATA_FIRST != 0);
+        let modifiers = START_DELAYED | CONFIRM_ACK | METADATA_FIRST;
+        let (mode, records) = match (modeByte.map(|mode| mode & !modifiers), specific) {
+            (Some(RESUME), rest) => (Mode::Resume, rest),
+            (Some(TRIGGERED), [t0, t1, p0, p1, rest @ ..]) => (
+                Mode::Triggered {
+                    threshold: u16::from_be_bytes([*t0, *t1]),
+                    pretrigger: u16::from_be_bytes([*p0, *p1]),
+                },
+                rest,
+            ),
+            (Some(TRIGGERED), _) => return None,
+            (Some(SWEEP), [d0, d1, count, rest @ ..]) => {
+                let count = *count as usize;
+                let mode = Mode::Sweep {
+                    dwell: u16::from_be_bytes([*d0, *d1]),
+                    channels: ChannelList::fromSlice(rest.get(..count)?)?,
+                };
+                (mode, &rest[count..])
+            }
+            (Some(SWEEP), _) => return None,
+            (_, rest) => (Mode::Stream, rest),
+        };
+        let mut handshake = Self {
+            mode,
+            format,
+            blockSamples,
+            blocksPerDatagram,
+            startDelayUs,
+            confirmAck,
+            metadataFirst,
+            ..Self::default()
+        };
+        parseRecords(records, &mut handshake)?;
+        Some(handshake)
+    }
+}
+
+/// parses the TLV records of the handshake into its fields, returns None if the stream is malformed,
+/// a record is invalid or repeated (the channel config - for the same channel),
+/// the shapes of the records are checked by `HANDSHAKE_RULES` first
+fn parseRecords(buf: &[u8], handshake: &mut Handshake) -> Option<()> {
+    tlv::validate(buf, &HANDSHAKE_RULES).ok()?;
+    let configs = &mut handshake.channelConfigs;
+    let injected = &mut handshake.injected;
+    let envelope = &mut handshake.envelope;
+    let summary = &mut handshake.summary;
+    let rateLimit = &mut handshake.rateLimit;
+    let maskBits = &mut handshake.maskBits;
+    let formats = &mut handshake.formats;
+    for record in tlv::records(buf) {
+        match record.ok()? {
+            (TLV_CHANNEL, [channel, g0, g1, divider @ ..]) => {
+                if !Inputs::isValid(*channel) || configs.iter().any(|config| config.channel == *channel) {
+                    return None;
+                }
+                let divider = divider.first().copied().unwrap_or(1);
+                if !(1..=MAX_DIVIDER).contains(&divider) {
+                    return None;
+                }
+                let config = ChannelConfig {
+                    channel: *channel,
+                    gain: u16::from_be_bytes([*g0, *g1]),
+                    divider,
+                };
+                configs.push(config).ok()?;
+            }
+            (TLV_INJECTED, [channel, e0, e1]) => {
+                let every = u16::from_be_bytes([*e0, *e1]);
+                if !Inputs::isValid(*channel) || (every as usize) < MIN_INJECTED_EVERY {
+                    return None;
+                }
+                *injected = Some(Injected { channel: *channel, every });
+            }
+            (TLV_ENVELOPE, [g0, g1]) => {
+                let group = u16::from_be_bytes([*g0, *g1]);
+                if group < 2 {
+                    return None;
+                }
+                *envelope = Some(group);
+            }
+            (TLV_SUMMARY, [only]) => {
+                *summary = match *only {
+                    0 => Some(Summary::WithSamples),
+                    1 => Some(Summary::Only),
+                    _ => return None,
+                };
+            }
+            (TLV_RATE_LIMIT, [r0, r1, r2, r3]) => {
+                *rateLimit = match u32::from_be_bytes([*r0, *r1, *r2, *r3]) {
+                    0 => None,
+                    rate => Some(rate),
+                };
+            }
+            (TLV_MASK, [bits]) => {
+                if !(1..=MAX_MASK_BITS).contains(bits) {
+                    return None;
+                }
+                *maskBits = *bits;
+            }
+            (TLV_FORMATS, [caps]) => {
+                *formats = Some(*caps);
+            }
+            // left for the newer firmware, the known records of the wrong length are rejected by the rules
+            _ => {}
+        }
+    }
+    Some(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Format)]
+pub enum Command {
+    Handshake(Handshake),
+    Stop,
+    LogLevel(Level),
+    Stats,
+    LinkStatus,
+    GetConfig,
+    Tare(ChannelList),
+    Arm,
+    Disarm,
+    ForceTrigger,
+    #[cfg(feature = "adc-dump")]
+    DumpAdc,
+    SetTime(u32),
+    /// the acknowledgment with the sequence is received
+    Confirm(u32),
+    SelfCheck,
+    /// true - millivolts, false - counts
+    SetUnits(bool),
+    /// the ring log lines from the number
+    Log(u32),
+    /// true - the acquisition keeps running
+    Pause(bool),
+    Resume,
+    SetBlockSize(u16),
+    Health,
+    #[cfg(feature = "loopback")]
+    Reference { total: u16, offset: u16, chunk: ReferenceChunk },
+    SetIp { token: u32, ip: [u8; 4], prefix: u8, gateway: [u8; 4] },
+    Reboot { token: u32 },
+}
+
+impl Command {
+    /// parses the received packet, returns None if packet is not a valid command
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        match buf {
+            [SYN, EOT, payload @ ..] => Handshake::parse(payload).map(Command::Handshake),
+            [SYN, CAN, ..] => Some(Command::Stop),
+            [SYN, LOG_LEVEL, level, ..] => Level::fromU8(*level).map(Command::LogLevel),
+            [SYN, STATS, ..] => Some(Command::Stats),
+            [SYN, LINK_STATUS, ..] => Some(Command::LinkStatus),
+            [SYN, GET_CONFIG, ..] => Some(Command::GetConfig),
+            [SYN, TARE] | [SYN, TARE, 0, ..] => Some(Command::Tare(ChannelList::fromSlice(&inputs::CHANNELS)?)),
+            [SYN, TARE, count, ids @ ..] => Some(Command::Tare(ChannelList::fromSlice(ids.get(..*count as usize)?)?)),
+            [SYN, ARM, ..] => Some(Command::Arm),
+            [SYN, DISARM, ..] => Some(Command::Disarm),
+            [SYN, FORCE_TRIGGER, ..] => Some(Command::ForceTrigger),
+            #[cfg(feature = "adc-dump")]
+            [SYN, DUMP_ADC, ..] => Some(Command::DumpAdc),
+            [SYN, SET_TIME, s0, s1, s2, s3, ..] => Some(Command::SetTime(u32::from_be_bytes([*s0, *s1, *s2, *s3]))),
+            [SYN, CONFIRM, s0, s1, s2, s3, ..] => Some(Command::Confirm(u32::from_be_bytes([*s0, *s1, *s2, *s3]))),
+            [SYN, SELFCHECK, ..] => Some(Command::SelfCheck),
+            [SYN, SET_UNITS, units @ (0 | 1), ..] => Some(Command::SetUnits(*units == 1)),
+            [SYN, LOG, f0, f1, f2, f3, ..] => Some(Command::Log(u32::from_be_bytes([*f0, *f1, *f2, *f3]))),
+            [SYN, LOG, ..] => Some(Command::Log(0)),
+            [SYN, PAUSE, acquiring @ (0 | 1), ..] => Some(Command::Pause(*acquiring == 1)),
+            [SYN, PAUSE] => Some(Command::Pause(false)),
+            [SYN, RESUME, ..] => Some(Command::Resume),
+            [SYN, SET_BLOCK_SIZE, s0, s1, ..] => Some(Command::SetBlockSize(u16::from_be_bytes([*s0, *s1]))),
+            [SYN, HEALTH, ..] => Some(Command::Health),
+            #[cfg(feature = "loopback")]
+            [SYN, REFERENCE, t0, t1, o0, o1, samples @ ..] => Some(Command::Reference {
+                total: u16::from_be_bytes([*t0, *t1]),
+                offset: u16::from_be_bytes([*o0, *o1]),
+                chunk: ReferenceChunk::parse(samples)?,
+            }),
+            [SYN, SET_IP, t0, t1, t2, t3, a0, a1, a2, a3, prefix, g0, g1, g2, g3, ..] => Some(Command::SetIp {
+                token: u32::from_be_bytes([*t0, *t1, *t2, *t3]),
+                ip: [*a0, *a1, *a2, *a3],
+                prefix: *prefix,
+                gateway: [*g0, *g1, *g2, *g3],
+            }),
+            [SYN, REBOOT, t0, t1, t2, t3, ..] => Some(Command::Reboot {
+                token: u32::from_be_bytes([*t0, *t1, *t2, *t3]),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Samples of the `REFERENCE` chunk
+#[cfg(feature = "loopback")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct ReferenceChunk {
+    samples: [u16; REFERENCE_CHUNK],
+    len: u8,
+}
+
+#[cfg(feature = "loopback")]
+impl ReferenceChunk {
+    /// parses the big endian samples, None if the length is odd or over `REFERENCE_CHUNK`
+    fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() % 2 != 0 || buf.len() / 2 > REFERENCE_CHUNK {
+            return None;
+        }
+        let mut chunk = Self { samples: [0; REFERENCE_CHUNK], len: (buf.len() / 2) as u8 };
+        for (sample, bytes) in chunk.samples.iter_mut().zip(buf.chunks_exact(2)) {
+            *sample = u16::from_be_bytes([bytes[0], bytes[1]]);
+        }
+        Some(chunk)
+    }
+    pub fn samples(&self) -> &[u16] {
+        &self.samples[..self.len as usize]
+    }
+}
+
+/// returns the `packet` following the magic, None if it's missing
+pub fn stripMagic(packet: &[u8]) -> Option<&[u8]> {
+    packet.strip_prefix(MAGIC)
+}
+
+/// writes the reply with `opcode` and `payload` into `buf`, returns its length
+pub fn reply(buf: &mut [u8], opcode: u8, payload: &[u8]) -> usize {
+    buf[0] = SYN;
+    buf[1] = opcode;
+    buf[2..2 + payload.len()].copy_from_slice(payload);
+    2 + payload.len()
+}
+
+/// Requested and applied value of the session parameter
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Format)]
+pub struct Negotiated<T> {
+    pub requested: T,
+    pub applied: T,
+}
+
+impl<T: PartialEq> Negotiated<T> {
+    pub fn new(requested: T, applied: T) -> Self {
+        Self { requested, applied }
+    }
+    /// return true if the parameter is applied as requested
+    pub fn isExact(&self) -> bool {
+        self.requested == self.applied
+    }
+}
+
+/// Session parameters the board may not honor exactly (capped, quantized or unavailable in the mode),
+/// the acknowledgment reports both values, so the host doesn't assume it got what it asked for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Format)]
+pub struct NegotiatedParams {
+    pub format: Negotiated<SampleFormat>,
+    /// samples per logical block, requested 0 - the default
+    pub blockSamples: Negotiated<u16>,
+    /// acquisition blocks per datagram, requested 0 - as needed
+    pub blocksPerDatagram: Negotiated<u16>,
+    /// samples preceding the trigger, 0 - not triggered
+    pub pretrigger: Negotiated<u16>,
+    /// regular samples per injected one, 0 - no injected channel
+    pub injectedEvery: Negotiated<u16>,
+}
+
+impl NegotiatedParams {
+    /// logs the parameters applied differently from the request
+    pub fn log(&self) {
+        if !self.format.isExact() {
+            warn!("format {:?} applied as {:?}", self.format.requested, self.format.applied);
+        }
+        let counts = [
+            ("block samples", self.blockSamples),
+            ("blocks per datagram", self.blocksPerDatagram),
+            ("pretrigger", self.pretrigger),
+            ("injected every", self.injectedEvery),
+        ];
+        for (name, value) in counts.iter().filter(|(_, value)| !value.isExact()) {
+            info!("{} {} applied as {}", name, value.requested, value.applied);
+        }
+    }
+    /// writes the requested values, the applied ones not carried by the acknowledgment already
+    /// into `buf`, returns the written length:
+    /// | format: u8 | block: u16 | blocks: u8 | pretrigger: u16 | applied: u16 | injected every: u16 | applied: u16 |
+    fn writeRequested(&self, buf: &mut [u8]) -> usize {
+        buf[0] = self.format.requested.flags();
+        buf[1..3].copy_from_slice(&self.blockSamples.requested.to_be_bytes());
+        buf[3] = self.blocksPerDatagram.requested as u8;
+        buf[4..6].copy_from_slice(&self.pretrigger.requested.to_be_bytes());
+        buf[6..8].copy_from_slice(&self.pretrigger.applied.to_be_bytes());
+        buf[8..10].copy_from_slice(&self.injectedEvery.requested.to_be_bytes());
+        buf[10..12].copy_from_slice(&self.injectedEvery.applied.to_be_bytes());
+        12
+    }
+}
+
+/// Handshake acknowledgment
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HandshakeAck {
+    /// sequence number of the first packet
+    pub seq: u32,
+    /// blocks skipped since the resumed session was ended
+    pub missed: u32,
+    /// requested and applied sample format, samples per block and blocks per datagram...
+    pub params: NegotiatedParams,
+    /// samples per datagram of the paged block, the applied block samples if the block isn't paged
+    pub pageSamples: u16,
+    /// the samples are left aligned, see `inputs::Alignment`
+    pub leftAligned: bool,
+    /// the bits the samples of `FORMAT_BYTE` are shifted right by
+    pub byteShift: u8,
+    /// the positions of the external mux, 0 - none, see `ext_mux`
+    pub muxChannels: u8,
+    /// the lowest bits of the counts zeroed, 0 - none, see `TLV_MASK`
+    pub maskBits: u8,
+    /// the sample encoding picked, one of `packet::CAP_*`, see `TLV_FORMATS`
+    pub encoding: u8,
+    /// the acknowledgment ends with the CRC-16, see `CONFIRM_ACK`
+    pub crc: bool,
+}
+
+impl HandshakeAck {
+    /// writes the acknowledgment into `buf`, the applied values first, returns its length
+    pub fn write(&self, buf: &mut [u8]) -> usize {
+        buf[0] = SYN;
+        buf[1] = ACK;
+        buf[2..6].copy_from_slice(&self.seq.to_be_bytes());
+        buf[6..10].copy_from_slice(&self.missed.to_be_bytes());
+        buf[10] = self.params.format.applied.flags();
+        buf[11..13].copy_from_slice(&self.params.blockSamples.applied.to_be_bytes());
+        buf[13..15].copy_from_slice(&self.params.blocksPerDatagram.applied.to_be_bytes());
+        buf[15..17].copy_from_slice(&self.pageSamples.to_be_bytes());
+        let len = 17 + self.params.writeRequested(&mut buf[17..]);
+        buf[len] = self.leftAligned as u8;
+        buf[len + 1] = self.byteShift;
+        buf[len + 2] = self.muxChannels;
+        buf[len + 3] = self.maskBits;
+        buf[len + 4] = self.encoding;
+        let len = len + 5;
+        match self.crc {
+            true => {
+                let crc = crc::crc16(&buf[..len]);
+                buf[len..len + 2].copy_from_slice(&crc.to_be_bytes());
+                len + 2
+            }
+            false => len,
+        }
+    }
+}
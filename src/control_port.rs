@@ -0,0 +1,69 @@
+//! Control port served by its own task concurrently with the streaming loop
+//!
+//! The data port is polled for the control packets between the datagrams only (see `pollControl` in `main`),
+//! so the command waits for the datagram being acquired and sent, for the trigger in the armed wait.
+//! `control_task` listens on `PORT` meanwhile and answers the commands which need no acquisition resources
+//! at once: `LOG_LEVEL`, `STATS`, `LINK_STATUS`, `LOG`, `ARM`, `DISARM`, `FORCE_TRIGGER`, `PAUSE`, `RESUME`,
+//! `SET_BLOCK_SIZE` (the trigger, the pause and the block size requests are atomic).
+//! `STOP` of the session client is passed to the streaming loop by the `STOP` signal,
+//! the loop takes it at its next poll (each datagram, each slice of the armed or the delayed start wait).
+//! The other commands (handshake, tare, self-check, configuration) own the ADC, the RTC or the data socket,
+//! they're accepted on the data port only.
+//!
+//! The streaming loop publishes the running session (`begin` / `end`), the task reads it:
+//! ```not_rust
+//! streaming loop --begin(remote, stream)--> SESSION <--running()-- control_task --requestStop()--> STOP
+//!       ^                                                                                          |
+//!       +------------------------------------takeStop() at each poll-------------------------------+
+//! ```
+//! Both are guarded by the critical section, the task and the loop may run on the different executors.
+//! The blocking acquisition (`BlockingSource`, the sample by sample modes) doesn't yield while the datagram
+//! is acquired, the task answers right after it, still before the datagram is sent.
+use core::cell::Cell;
+
+use embassy_net::IpEndpoint;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+
+use crate::packet::StreamFormat;
+
+/// the port of the control task, the data port accepts all the commands as well
+pub const PORT: u16 = 15182;
+
+/// The session published by the streaming loop
+#[derive(Clone, Copy)]
+pub struct Running {
+    pub remote: IpEndpoint,
+    pub stream: StreamFormat,
+}
+
+static SESSION: Mutex<CriticalSectionRawMutex, Cell<Option<Running>>> = Mutex::new(Cell::new(None));
+/// the stop requested by the session client on the control port
+static STOP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// publishes the session started to `remote` in the `stream` format, the stale stop request is dropped
+pub fn begin(remote: IpEndpoint, stream: StreamFormat) {
+    STOP.reset();
+    SESSION.lock(|session| session.set(Some(Running { remote, stream })));
+}
+
+/// the session has ended
+pub fn end() {
+    SESSION.lock(|session| session.set(None));
+}
+
+/// the running session, None if not streaming
+pub fn running() -> Option<Running> {
+    SESSION.lock(|session| session.get())
+}
+
+/// passes the stop to the streaming loop
+pub fn requestStop() {
+    STOP.signal(());
+}
+
+/// return true if the stop was requested since the last call, called by the streaming loop
+pub fn takeStop() -> bool {
+    STOP.try_take().is_some()
+}
@@ -0,0 +1,40 @@
+//! CRC-16/CCITT-FALSE (poly 0x1021, init 0xFFFF, no reflection, no final xor)
+//! of the packets needing the end-to-end check beyond the UDP checksum,
+//! e.g. `crc16(b"123456789") == 0x29B1`
+//! The module depends on `core` only, so its tests run on the host apart from the firmware:
+//! `rustc --edition 2021 --test src/crc.rs -o /tmp/crc && /tmp/crc`
+
+const POLY: u16 = 0x1021;
+const INIT: u16 = 0xFFFF;
+
+/// the CRC of the `data`
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(INIT, |crc, byte| {
+        (0..8).fold(crc ^ (*byte as u16) << 8, |crc, _| match crc & 0x8000 {
+            0 => crc << 1,
+            _ => (crc << 1) ^ POLY,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn empty_is_init() {
+        assert_eq!(crc16(&[]), INIT);
+    }
+
+    #[test]
+    fn single_bit_flip_changes_crc() {
+        let mut data = *b"123456789";
+        data[4] ^= 0x10;
+        assert_ne!(crc16(&data), 0x29B1);
+    }
+}
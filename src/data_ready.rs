@@ -0,0 +1,47 @@
+//! Data-ready output for the companion device (FPGA, capture MCU) taking the stream out of band
+//!
+//! With the `data-ready` feature the pin pulses once per datagram as soon as its samples are complete:
+//! ```not_rust
+//! | acquisition of the datagram | processor | pulse | packing | send |
+//!                           last sample ^   ^ ~1 us
+//! ```
+//! - blocking acquisition - the pulse follows the last conversion of the datagram by the processing hook time
+//! - DMA acquisition - the half is complete when the DMA flags it, the pulse follows by the flag poll latency
+//!   (up to one time driver tick, ~31 us) and the copy of the half, the DMA already fills the next one
+//! - triggered capture - the pulse follows the last block of the datagram, not each trigger
+//!
+//! The pulse marks the data availability only, it isn't aligned to the block start (the sample clock)
+//! and isn't emitted for the datagram dropped by the stopped acquisition.
+use embassy_stm32::gpio::{AnyPin, Level, Output, Speed};
+
+/// the pulse width, ~1 us at 216 MHz
+const PULSE_CYCLES: u32 = 216;
+
+/// The data-ready pin
+pub struct DataReady {
+    pin: Output<'static, AnyPin>,
+    activeHigh: bool,
+}
+
+impl DataReady {
+    /// drives the `pin` inactive, `activeHigh` - the polarity of the pulse
+    pub fn new(pin: AnyPin, activeHigh: bool) -> Self {
+        let idle = match activeHigh {
+            true => Level::Low,
+            false => Level::High,
+        };
+        Self { pin: Output::new(pin, idle, Speed::VeryHigh), activeHigh }
+    }
+    /// pulses the pin, the new datagram is ready
+    pub fn pulse(&mut self) {
+        self.set(true);
+        cortex_m::asm::delay(PULSE_CYCLES);
+        self.set(false);
+    }
+    fn set(&mut self, active: bool) {
+        match active == self.activeHigh {
+            true => self.pin.set_high(),
+            false => self.pin.set_low(),
+        }
+    }
+}
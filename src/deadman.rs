@@ -0,0 +1,56 @@
+//! Dead-man timer of the network path
+//!
+//! Every send of the firmware (the data, the replies, the beacon, the telemetry) is awaited by `guard`,
+//! marking the network operation as pending until it completes or is dropped,
+//! the supervisor checks periodically how long the pending operations make no progress.
+//! The operations of the tasks overlap: the mark is kept while any is pending,
+//! each one completing is the progress, so only the network stack stalling all the sends is the stall.
+//! Nothing pending (waiting for a handshake) is never a stall.
+//! The update is a couple of atomic stores.
+use core::future::Future;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_time::Instant;
+
+/// the network operations pending
+static PENDING: AtomicU32 = AtomicU32::new(0);
+/// millis since boot of the last progress of the pending operations, 0 - nothing pending
+static PENDING_SINCE: AtomicU32 = AtomicU32::new(0);
+
+fn nowMillis() -> u32 {
+    (Instant::now().as_millis() as u32).max(1)
+}
+
+/// The network operation pending while it's alive
+pub struct Pending(());
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        match PENDING.fetch_sub(1, Ordering::Relaxed) {
+            1 => PENDING_SINCE.store(0, Ordering::Relaxed),
+            _ => PENDING_SINCE.store(nowMillis(), Ordering::Relaxed),
+        }
+    }
+}
+
+/// marks the network operation as started until the returned mark is dropped
+pub fn begin() -> Pending {
+    if PENDING.fetch_add(1, Ordering::Relaxed) == 0 {
+        PENDING_SINCE.store(nowMillis(), Ordering::Relaxed);
+    }
+    Pending(())
+}
+
+/// awaits the network operation `op` marked as pending, see `begin`
+pub async fn guard<F: Future>(op: F) -> F::Output {
+    let _pending = begin();
+    op.await
+}
+
+/// returns how long in millis the pending operations make no progress, None if nothing pending
+pub fn pendingMillis() -> Option<u32> {
+    match PENDING_SINCE.load(Ordering::Relaxed) {
+        0 => None,
+        since => Some(nowMillis().wrapping_sub(since)),
+    }
+}
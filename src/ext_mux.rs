@@ -0,0 +1,60 @@
+//! External analog mux in front of the ADC input
+//!
+//! With the `ext-mux` feature the channel count is expanded by the analog mux (e.g. CD74HC4067, ADG708)
+//! feeding one ADC channel, its select lines are driven by the GPIOs (the binary position, the low bit first).
+//! The mux is stepped to the next position after each sample of its channel is converted (`Inputs::read`),
+//! so the consecutive samples of the channel cycle over the positions 0, 1 .. `channels` - 1, 0...
+//! After the switch the settle time of the mux and the source is busy-waited before the next conversion,
+//! the sample rate of the channel drops by it.
+//! ```not_rust
+//! | convert pos 0 | switch | settle | convert pos 1 | switch | settle | ...
+//! ```
+//! The summed sequence converts one position, the warm-up samples don't step the mux.
+//! The DMA acquisition converts continuously with no room for the switch, the `adc-dma` build has no mux.
+use embassy_stm32::gpio::{AnyPin, Level, Output, Speed};
+
+/// the select lines, up to 16 positions
+pub const SELECT_LINES: usize = 4;
+
+/// The mux stepped between the conversions
+pub struct ExtMux {
+    select: [Output<'static, AnyPin>; SELECT_LINES],
+    channels: u8,
+    /// the CPU cycles waited after the switch
+    settleCycles: u32,
+    /// the position of the next conversion
+    position: u8,
+}
+
+impl ExtMux {
+    /// drives the `select` lines (the low bit first) to the position 0,
+    /// `channels` - the positions cycled over (2..=16), `settleCycles` - the CPU cycles waited after each switch
+    pub fn new(select: [AnyPin; SELECT_LINES], channels: u8, settleCycles: u32) -> Self {
+        let select = select.map(|pin| Output::new(pin, Level::Low, Speed::VeryHigh));
+        let mut mux = Self { select, channels: channels.clamp(2, 1 << SELECT_LINES), settleCycles, position: 0 };
+        mux.switch(0);
+        mux
+    }
+    /// the positions cycled over
+    pub fn channels(&self) -> u8 {
+        self.channels
+    }
+    /// the position of the next conversion
+    pub fn position(&self) -> u8 {
+        self.position
+    }
+    /// switches to the next position and waits for it to settle, called after the conversion
+    pub fn step(&mut self) {
+        self.switch((self.position + 1) % self.channels);
+    }
+    fn switch(&mut self, position: u8) {
+        self.position = position;
+        for (bit, line) in self.select.iter_mut().enumerate() {
+            match position >> bit & 1 {
+                1 => line.set_high(),
+                _ => line.set_low(),
+            }
+        }
+        cortex_m::asm::delay(self.settleCycles);
+    }
+}
@@ -0,0 +1,83 @@
+//! External trigger sources of the ADC1 regular group
+//!
+//! The source is selected by the EXTSEL field of ADC_CR2 (RM0410, the ADC external trigger table),
+//! the variants carry the field values:
+//! ```not_rust
+//! | 0 TIM1_CH1   | 1 TIM1_CH2  | 2 TIM1_CH3    | 3 TIM2_CH2   | 4 TIM5_TRGO  | 5 TIM4_CH4   |
+//! | 6 TIM3_CH4   | 7 TIM8_TRGO | 8 TIM8_TRGO2  | 9 TIM1_TRGO  | 10 TIM1_TRGO2 | 11 TIM2_TRGO |
+//! | 12 TIM4_TRGO | 13 TIM6_TRGO | 14 reserved  | 15 EXTI11    |
+//! ```
+//! TIM2 runs the time driver and TIM5 the sample timestamps (see `timestamp`), their sources are never available,
+//! neither is TIM4 pacing the stamped DMA acquisition (`dma-stamps`, see `adc_dma`).
+//! The selected timer is configured by its owner, the ADC only listens to its rising edge,
+//! the software start keeps working.
+use defmt::Format;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+#[repr(u8)]
+pub enum ExtTrigger {
+    Tim1Ch1 = 0,
+    Tim1Ch2 = 1,
+    Tim1Ch3 = 2,
+    Tim2Ch2 = 3,
+    Tim5Trgo = 4,
+    Tim4Ch4 = 5,
+    Tim3Ch4 = 6,
+    Tim8Trgo = 7,
+    Tim8Trgo2 = 8,
+    Tim1Trgo = 9,
+    Tim1Trgo2 = 10,
+    Tim2Trgo = 11,
+    Tim4Trgo = 12,
+    Tim6Trgo = 13,
+    Exti11 = 15,
+}
+
+impl ExtTrigger {
+    /// returns the source by its EXTSEL value, None if the value is reserved or out of range
+    pub fn fromU8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Tim1Ch1),
+            1 => Some(Self::Tim1Ch2),
+            2 => Some(Self::Tim1Ch3),
+            3 => Some(Self::Tim2Ch2),
+            4 => Some(Self::Tim5Trgo),
+            5 => Some(Self::Tim4Ch4),
+            6 => Some(Self::Tim3Ch4),
+            7 => Some(Self::Tim8Trgo),
+            8 => Some(Self::Tim8Trgo2),
+            9 => Some(Self::Tim1Trgo),
+            10 => Some(Self::Tim1Trgo2),
+            11 => Some(Self::Tim2Trgo),
+            12 => Some(Self::Tim4Trgo),
+            13 => Some(Self::Tim6Trgo),
+            15 => Some(Self::Exti11),
+            _ => None,
+        }
+    }
+    /// the EXTSEL value
+    pub const fn extsel(self) -> u8 {
+        self as u8
+    }
+    /// the number of the timer producing the trigger, None for the EXTI line
+    pub const fn timer(self) -> Option<u8> {
+        match self {
+            Self::Tim1Ch1 | Self::Tim1Ch2 | Self::Tim1Ch3 | Self::Tim1Trgo | Self::Tim1Trgo2 => Some(1),
+            Self::Tim2Ch2 | Self::Tim2Trgo => Some(2),
+            Self::Tim3Ch4 => Some(3),
+            Self::Tim4Ch4 | Self::Tim4Trgo => Some(4),
+            Self::Tim5Trgo => Some(5),
+            Self::Tim6Trgo => Some(6),
+            Self::Tim8Trgo | Self::Tim8Trgo2 => Some(8),
+            Self::Exti11 => None,
+        }
+    }
+    /// return false if the source is the timer already used by the firmware
+    pub const fn isAvailable(self) -> bool {
+        match self.timer() {
+            Some(2 | 5) => false,
+            Some(4) => !cfg!(feature = "dma-stamps"),
+            _ => true,
+        }
+    }
+}
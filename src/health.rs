@@ -0,0 +1,116 @@
+//! Board health summary reported by the `HEALTH` command
+//!
+//! The one-stop snapshot of all the subsystems for the fleet monitoring, besides the throughput of `STATS`:
+//! ```not_rust
+//! | version: u8 | flags: u16 | link mode: u8 | adc faults: u32 | dma overruns: u32 | watchdog resets: u32 |
+//! uptime: u32 | temperature: i16 | tx fill: u8 | stack fill: u8 |
+//! ```
+//! flags - the raised `FLAG_*`, none - healthy, link mode - see `phy::LinkMode`, uptime in seconds,
+//! temperature - the die temperature in 0.1 °C, `TEMPERATURE_UNKNOWN` - not measured,
+//! tx fill - the largest datagram sent of the TX buffer, %, stack fill - the stack high-water mark of its size, %.
+//! The fields are never changed, the new ones are appended and `VERSION` is raised.
+//!
+//! The temperature sensor shares the ADC with the stream, it's converted at the boot and by `HEALTH`
+//! received while idle, the reply while streaming carries the last measured one.
+//! The watchdog resets are counted in the backup register (see `backup`), they survive the resets
+//! but not the power loss unless VBAT is supplied.
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering};
+
+use embassy_stm32::pac;
+use embassy_time::Instant;
+
+use crate::inputs::Inputs;
+use crate::{arp_guard, backup, phy, stack, stats};
+
+/// version of the reply layout
+pub const VERSION: u8 = 1;
+/// size of the reply
+pub const SIZE: usize = 24;
+/// the temperature isn't measured
+pub const TEMPERATURE_UNKNOWN: i16 = i16::MIN;
+/// the Ethernet link is down
+pub const FLAG_LINK_DOWN: u16 = 1 << 0;
+/// ADC conversions timed out since the boot
+pub const FLAG_ADC_FAULTS: u16 = 1 << 1;
+/// DMA acquisition halves overrun since the boot
+pub const FLAG_DMA_OVERRUNS: u16 = 1 << 2;
+/// the RNG failed, the network stack seed is weak
+pub const FLAG_RNG_WEAK: u16 = 1 << 3;
+/// the last reset was done by the watchdog
+pub const FLAG_WATCHDOG_RESET: u16 = 1 << 4;
+/// the measured sample rate deviates from the expected one
+pub const FLAG_RATE_DEVIATION: u16 = 1 << 5;
+/// the static IP is used by the other host, see `arp_guard`
+pub const FLAG_IP_CONFLICT: u16 = 1 << 6;
+
+/// TS_CAL1, the sensor counts at 30 °C and 3.3 V, 12 bit
+const TS_CAL1: *const u16 = 0x1FF0_F44C as *const u16;
+/// TS_CAL2, the sensor counts at 110 °C and 3.3 V, 12 bit
+const TS_CAL2: *const u16 = 0x1FF0_F44E as *const u16;
+
+/// the last measured temperature, 0.1 °C
+static TEMPERATURE: AtomicI32 = AtomicI32::new(TEMPERATURE_UNKNOWN as i32);
+/// the largest datagram sent, bytes
+static TX_PEAK: AtomicU32 = AtomicU32::new(0);
+/// the last reset was done by the watchdog
+static WATCHDOG_RESET: AtomicBool = AtomicBool::new(false);
+
+/// checks the reset cause, counts the watchdog reset, called at the boot after `backup::init`
+pub fn init() {
+    let csr = unsafe { pac::RCC.csr().read() };
+    if csr.iwdgrstf() || csr.wwdgrstf() {
+        WATCHDOG_RESET.store(true, Ordering::Relaxed);
+        backup::countWatchdogReset();
+    }
+    // the flags are kept until cleared, the next reset is told apart
+    unsafe { pac::RCC.csr().modify(|w| w.set_rmvf(true)) };
+}
+
+/// converts the temperature sensor, keeps the result for the reply
+pub fn measureTemperature(inputs: &mut Inputs) {
+    inputs.startTemperature();
+    let value = inputs.readReference();
+    inputs.stopReference();
+    let Some(value) = value else {
+        return;
+    };
+    let (cal1, cal2) = unsafe { (TS_CAL1.read_volatile() as i32, TS_CAL2.read_volatile() as i32) };
+    // the calibration points at 30 and 110 °C, of 12 bits as the self-check one
+    let tenths = 300 + (value as i32 - cal1) * 800 / (cal2 - cal1).max(1);
+    TEMPERATURE.store(tenths, Ordering::Relaxed);
+}
+
+/// records the `size` of the datagram sent
+pub fn sent(size: usize) {
+    TX_PEAK.fetch_max(size as u32, Ordering::Relaxed);
+}
+
+/// writes the reply payload into `buf`, `linkUp` - the stack state, `txCapacity` - the TX buffer size,
+/// returns its length
+pub fn write(buf: &mut [u8], linkUp: bool, txCapacity: usize) -> usize {
+    let adcFaults = stats::ADC_FAULTS.load(Ordering::Relaxed);
+    let dmaOverruns = stats::DMA_OVERRUNS.load(Ordering::Relaxed);
+    let statsFlags = stats::flags();
+    let conditions = [
+        (!linkUp, FLAG_LINK_DOWN),
+        (adcFaults > 0, FLAG_ADC_FAULTS),
+        (dmaOverruns > 0, FLAG_DMA_OVERRUNS),
+        (statsFlags & stats::FLAG_RNG_WEAK != 0, FLAG_RNG_WEAK),
+        (WATCHDOG_RESET.load(Ordering::Relaxed), FLAG_WATCHDOG_RESET),
+        (statsFlags & stats::FLAG_RATE_DEVIATION != 0, FLAG_RATE_DEVIATION),
+        (arp_guard::conflict().is_some(), FLAG_IP_CONFLICT),
+    ];
+    let flags = conditions.iter().filter(|(raised, _)| *raised).fold(0, |flags, (_, flag)| flags | flag);
+    let percent = |part: u32, whole: u32| (part as u64 * 100 / whole.max(1) as u64).min(100) as u8;
+    buf[0] = VERSION;
+    buf[1..3].copy_from_slice(&flags.to_be_bytes());
+    buf[3] = phy::linkMode() as u8;
+    buf[4..8].copy_from_slice(&adcFaults.to_be_bytes());
+    buf[8..12].copy_from_slice(&dmaOverruns.to_be_bytes());
+    buf[12..16].copy_from_slice(&backup::watchdogResets().to_be_bytes());
+    buf[16..20].copy_from_slice(&(Instant::now().as_secs() as u32).to_be_bytes());
+    buf[20..22].copy_from_slice(&(TEMPERATURE.load(Ordering::Relaxed) as i16).to_be_bytes());
+    buf[22] = percent(TX_PEAK.load(Ordering::Relaxed), txCapacity as u32);
+    buf[23] = percent(stack::used(), stack::size());
+    SIZE
+}
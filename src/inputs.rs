@@ -0,0 +1,719 @@
+//! Analog inputs of the ADC1
+//!
+//! Inputs on the Nucleo-F767ZI Zio connector not used by the Ethernet RMII,
+//! identified by the ADC1 channel number:
+//! - `3` - PA3 (A0), default
+//! - `10` - PC0 (A1)
+//! - `13` - PC3 (A2)
+//!
+//! The pins are put into the analog mode without the pull at creation, see `configureAnalogInput`,
+//! and checked again at each channel switch, so a leftover GPIO configuration never reaches the conversion.
+//!
+//! The first conversion after the channel switch is done by the `Adc` driver, configuring the pin
+//! and the channel sample time, its result is dropped. Then the regular sequence is set to convert
+//! the channel `sequence` times per start and each sample is the sum of the sequence,
+//! the F7 ADC has no hardware accumulator, the conversions are summed as they complete.
+//! The sample rate is reduced by the sequence length, the full scale is multiplied by it.
+//! The end of each conversion is waited for at most `CONVERSION_TIMEOUT_SPINS`,
+//! on timeout the fault is counted in STATS and the ADC is re-enabled.
+//!
+//! The first conversions after the channel switch or the power up are settling:
+//! the sample-and-hold capacitor still holds the charge of the previous channel and the source
+//! of a high impedance (or the short sample time) doesn't recharge it within one sample time,
+//! the reference settles after the power up as well. So `warmup` samples (the sums of the sequence)
+//! are converted and dropped before the first one returned, at the channel switch, the power up
+//! and the session start (`resettle`), the steady conversions of the same channel aren't delayed.
+//!
+//! The internal reference (VREFINT, channel 17) is converted by the self-check only, see `selfcheck`,
+//! the temperature sensor (channel 18) by the health check, see `health`,
+//! the streamed channel is reconfigured by the next conversion after them, the tare offsets are kept.
+//!
+//! The injected group converts the single channel on demand between the regular conversions,
+//! its samples are the raw counts of one conversion.
+//!
+//! The result is right aligned in the data register (the counts) or left aligned (`setAlignment`),
+//! the 12 bit full scale reads as the 16 bit one, the samples, the tare offsets and the injected samples
+//! are all in the aligned units then. The summed sequence would overflow, the left alignment takes one conversion
+//! per sample. The internal reference is returned in the counts regardless, the self-check compares it
+//! with the factory calibration.
+//!
+//! The regular conversions may be started by the timer as well, see `ext_trigger`,
+//! or run continuously into the DMA buffer (the `adc-dma` feature, see `adc_dma`),
+//! the software conversion stops the DMA acquisition.
+//! `readAligned` doesn't start the conversion, it waits for the trigger edge to start it,
+//! the conversion begins within one ADC clock (37 ns) of the edge.
+//!
+//! The ADC is kept enabled between the sessions unless the idle timeout is configured, `powerDown` is called
+//! after it, `powerUp` at the handshake (or the next conversion) powers it up again paying the stabilization time,
+//! the configuration is retained while it's off, the F7 ADC has no calibration to redo.
+//!
+//! The source charges the sampling capacitor through its own and the ADC switch resistance within the sample time,
+//! too short sample time for the high impedance source leaves the systematic error. The datasheet bounds
+//! the source impedance by `R_AIN <= (k - 0.5) / (f_ADC * C_ADC * ln(2^(N + 2))) - R_ADC` (k - the sample cycles),
+//! `minSampleTimeForImpedance` inverts it for the 12 bit accuracy (1/4 LSB) with the maximum C_ADC and R_ADC.
+//!
+//! The tare offset of the channel is the average of `TARE_SAMPLES` conversions,
+//! it's the zero the streamed samples are reported relative to, kept until restart.
+//!
+//! With the `ext-mux` feature one channel is fed by the external analog mux stepped after each of its samples,
+//! see `ext_mux`, its tare offset averages all the mux positions.
+use defmt::*;
+use embassy_stm32::adc::{Adc, SampleTime};
+use embassy_stm32::gpio::Pin;
+use embassy_stm32::pac;
+use embassy_stm32::pac::gpio::{vals, Gpio};
+use embassy_stm32::peripherals::{ADC1, PA3, PC0, PC3};
+
+#[cfg(feature = "adc-dma")]
+use crate::adc_dma;
+use crate::clocks;
+#[cfg(feature = "ext-mux")]
+use crate::ext_mux::ExtMux;
+use crate::ext_trigger::ExtTrigger;
+use crate::log_level::{log, Level};
+use crate::packet::MUX_NONE;
+use crate::ring_log;
+use crate::stats;
+
+/// the input used if nothing else is requested
+pub const DEFAULT_CHANNEL: u8 = 3;
+/// available inputs
+pub const CHANNELS: [u8; 3] = [3, 10, 13];
+/// length limit of the ADC regular sequence
+pub const MAX_SEQUENCE: u8 = 16;
+/// conversions averaged by the tare
+const TARE_SAMPLES: u32 = 64;
+/// maximum length of the channel list
+pub const MAX_CHANNELS: usize = 8;
+/// the largest sweep rate divider, the schedule period (LCM of the dividers) fits `u32`
+pub const MAX_DIVIDER: u8 = 16;
+/// the sample times of the ADC and their ADC clock cycles, the shortest first
+const SAMPLE_TIMES: [(SampleTime, u32); 8] = [
+    (SampleTime::Cycles3, 3),
+    (SampleTime::Cycles15, 15),
+    (SampleTime::Cycles28, 28),
+    (SampleTime::Cycles56, 56),
+    (SampleTime::Cycles84, 84),
+    (SampleTime::Cycles112, 112),
+    (SampleTime::Cycles144, 144),
+    (SampleTime::Cycles480, 480),
+];
+/// the sampling capacitor, max, fF
+const ADC_CAPACITANCE_FF: u64 = 7_000;
+/// the sampling switch resistance, max, ohm
+const ADC_SWITCH_OHMS: u64 = 6_000;
+/// ln(2^(12 + 2)) * 1000, the settling to 1/4 LSB of 12 bits
+const SETTLING_LN_MILLI: u64 = 9_704;
+/// ADC1 channel of the internal reference voltage
+const VREFINT_CHANNEL: u8 = 17;
+/// ADC1 channel of the temperature sensor
+const TEMPERATURE_CHANNEL: u8 = 18;
+/// end of conversion status polls before the conversion is considered as stalled,
+/// a few times the longest conversion (480 cycles at 27 MHz)
+const CONVERSION_TIMEOUT_SPINS: u32 = 20_000;
+/// start status polls waiting for the external trigger edge before the conversion is started by the software,
+/// ~10 ms at 216 MHz, longer than the trigger period
+const TRIGGER_TIMEOUT_SPINS: u32 = 500_000;
+
+/// The ADC1 inputs owned by the acquisition
+pub struct Inputs {
+    pa3: PA3,
+    pc0: PC0,
+    pc3: PC3,
+    /// conversions summed into one sample
+    sequence: u8,
+    /// the channel the ADC is configured for
+    configured: Option<u8>,
+    /// false if the ADC is powered down
+    powered: bool,
+    /// samples dropped after the channel switch or the power up
+    warmup: u8,
+    /// samples still to be dropped before the next conversion
+    settling: u8,
+    /// tare offsets in the order of `CHANNELS`
+    offsets: [Option<u16>; CHANNELS.len()],
+    /// the left shift of the counts in the data register, see `Alignment`
+    alignShift: u32,
+    /// the external mux and the channel it feeds
+    #[cfg(feature = "ext-mux")]
+    mux: Option<(u8, ExtMux)>,
+}
+
+impl Inputs {
+    /// `sequence` - conversions summed into one sample, 1..=`MAX_SEQUENCE`
+    pub fn new(pa3: PA3, pc0: PC0, pc3: PC3, sequence: u8) -> Self {
+        configureAnalogInput(&pa3);
+        configureAnalogInput(&pc0);
+        configureAnalogInput(&pc3);
+        Self {
+            pa3,
+            pc0,
+            pc3,
+            sequence: sequence.clamp(1, MAX_SEQUENCE),
+            configured: None,
+            powered: true,
+            warmup: 0,
+            settling: 0,
+            offsets: [None; CHANNELS.len()],
+            alignShift: 0,
+            #[cfg(feature = "ext-mux")]
+            mux: None,
+        }
+    }
+    /// return true if the `channel` is one of the available inputs
+    pub fn isValid(channel: u8) -> bool {
+        CHANNELS.contains(&channel)
+    }
+    /// powers the ADC down until `powerUp` or the next conversion
+    pub fn powerDown(&mut self) {
+        if self.powered {
+            info!("[adc] power down");
+            setPower(false);
+            self.powered = false;
+        }
+    }
+    /// powers the ADC up if it's down, waiting for the stabilization
+    pub fn powerUp(&mut self) {
+        if !self.powered {
+            info!("[adc] power up");
+            setPower(true);
+            self.powered = true;
+            self.settling = self.warmup;
+        }
+    }
+    /// the samples dropped after the channel switch or the power up
+    pub fn setWarmup(&mut self, samples: u8) {
+        self.warmup = samples;
+    }
+    /// aligns the results of the resolution of `bits` in the data register
+    pub fn setAlignment(&mut self, alignment: Alignment, bits: u32) {
+        self.alignShift = alignment.shift(bits);
+        let align = match alignment {
+            Alignment::Right => pac::adc::vals::Align::RIGHT,
+            Alignment::Left => pac::adc::vals::Align::LEFT,
+        };
+        unsafe { pac::ADC1.cr2().modify(|w| w.set_align(align)) };
+        info!("[adc] {:?} aligned, shifted by {}", alignment, self.alignShift);
+    }
+    /// steps the external `mux` feeding the `channel` after each of its samples
+    #[cfg(feature = "ext-mux")]
+    pub fn setMux(&mut self, channel: u8, mux: ExtMux) {
+        info!("[adc] {} positions mux on channel {}", mux.channels(), channel);
+        self.mux = Some((channel, mux));
+    }
+    /// the positions of the external mux, 0 - no mux
+    pub fn muxChannels(&self) -> u8 {
+        #[cfg(feature = "ext-mux")]
+        if let Some((_, mux)) = &self.mux {
+            return mux.channels();
+        }
+        0
+    }
+    /// the mux position the next sample of the `channel` is taken at, `MUX_NONE` - the channel isn't muxed
+    pub fn muxPosition(&self, _channel: u8) -> u8 {
+        #[cfg(feature = "ext-mux")]
+        if let Some((_, mux)) = self.mux.as_ref().filter(|(muxed, _)| *muxed == _channel) {
+            return mux.position();
+        }
+        MUX_NONE
+    }
+    /// drops the warm-up samples before the next conversion, the input has been left idle
+    pub fn resettle(&mut self) {
+        self.settling = self.warmup;
+    }
+    /// return true if the ADC is powered
+    pub fn isPowered(&self) -> bool {
+        self.powered
+    }
+    /// the tare offset of the `channel`, None if not tared
+    pub fn offset(&self, channel: u8) -> Option<u16> {
+        let index = CHANNELS.iter().position(|id| *id == channel)?;
+        self.offsets[index]
+    }
+    /// measures and stores the tare offset of the `channel`, returns it
+    pub fn tare(&mut self, adc: &mut Adc<'_, ADC1>, channel: u8) -> u16 {
+        let sum: u32 = (0..TARE_SAMPLES).map(|_| self.read(adc, channel) as u32).sum();
+        let offset = (sum / TARE_SAMPLES) as u16;
+        if let Some(index) = CHANNELS.iter().position(|id| *id == channel) {
+            self.offsets[index] = Some(offset);
+        }
+        offset
+    }
+    /// writes `| count: u8 | channel: u8 | offset: u16 | ... |` of the `channels` into `buf`,
+    /// offset 0xFFFF - not tared, returns the written length
+    pub fn writeOffsets(&self, channels: &[u8], buf: &mut [u8]) -> usize {
+        buf[0] = channels.len() as u8;
+        for (i, channel) in channels.iter().enumerate() {
+            let entry = &mut buf[1 + i * 3..4 + i * 3];
+            entry[0] = *channel;
+            entry[1..3].copy_from_slice(&self.offset(*channel).unwrap_or(u16::MAX).to_be_bytes());
+        }
+        1 + channels.len() * 3
+    }
+    /// selects the external `trigger` of the regular conversions (rising edge), None - the software start only
+    pub fn setExtTrigger(&mut self, trigger: Option<ExtTrigger>) {
+        if let Some(trigger) = trigger {
+            info!("[adc] external trigger {:?}", trigger);
+        }
+        unsafe {
+            pac::ADC1.cr2().modify(|w| match trigger {
+                Some(trigger) => {
+                    w.set_extsel(trigger.extsel());
+                    w.set_exten(pac::adc::vals::Exten::RISINGEDGE);
+                }
+                None => w.set_exten(pac::adc::vals::Exten::DISABLED),
+            });
+        }
+    }
+    /// configures the injected group to convert the `channel` (the default input if it isn't available)
+    pub fn setInjected(&mut self, adc: &mut Adc<'_, ADC1>, channel: u8) {
+        self.powerUp();
+        let id = self.driverRead(adc, channel);
+        // the driver has reconfigured the regular sequence
+        self.configured = None;
+        unsafe {
+            pac::ADC1.jsqr().write(|w| {
+                // the single conversion uses the last slot
+                w.set_jl(0);
+                w.set_jsq(3, id);
+            });
+        }
+    }
+    /// switches the regular sequence to the single conversions of the internal reference
+    /// with the longest sample time (it needs 10 us), the warm-up samples are dropped
+    pub fn startReference(&mut self) {
+        self.startInternal(VREFINT_CHANNEL);
+    }
+    /// switches the regular sequence to the single conversions of the temperature sensor as `startReference`,
+    /// read by `readReference`
+    pub fn startTemperature(&mut self) {
+        self.startInternal(TEMPERATURE_CHANNEL);
+    }
+    /// switches the regular sequence to the internal `channel` (the sensor or the reference)
+    fn startInternal(&mut self, channel: u8) {
+        #[cfg(feature = "adc-dma")]
+        adc_dma::stop();
+        self.powerUp();
+        self.configured = None;
+        unsafe {
+            pac::ADC_COMMON.ccr().modify(|w| w.set_tsvrefe(true));
+            pac::ADC1
+                .smpr1()
+                .modify(|w| w.set_smp((channel - 10) as usize, pac::adc::vals::Smp::CYCLES480));
+        }
+        // VREFINT and the sensor start-up time, 10 us at 216 MHz
+        cortex_m::asm::delay(2200);
+        configureSequence(channel, 1);
+        for _ in 0..self.warmup {
+            convert(1, false);
+        }
+    }
+    /// converts the internal reference (the sensor), raw counts, None if the conversion is timed out
+    pub fn readReference(&mut self) -> Option<u16> {
+        let value = convert(1, false).map(|value| value >> self.alignShift);
+        if value.is_none() {
+            error!("[adc] reference conversion timeout, re-enabling ADC");
+            ring_log::push(Level::Error, format_args!("adc reference timeout"));
+            stats::inc(&stats::ADC_FAULTS);
+            reenable();
+        }
+        value
+    }
+    /// switches the internal reference off, the next conversion reconfigures the channel
+    pub fn stopReference(&mut self) {
+        unsafe { pac::ADC_COMMON.ccr().modify(|w| w.set_tsvrefe(false)) };
+    }
+    /// converts the injected channel between the regular conversions, returns 0 if timed out
+    pub fn readInjected(&mut self) -> u16 {
+        let regs = pac::ADC1;
+        unsafe {
+            regs.sr().modify(|w| w.set_jeoc(false));
+            regs.cr2().modify(|w| w.set_jswstart(true));
+            for _ in 0..CONVERSION_TIMEOUT_SPINS {
+                if regs.sr().read().jeoc() {
+                    return regs.jdr(0).read().jdata();
+                }
+            }
+        }
+        error!("[adc] injected conversion timeout");
+        ring_log::push(Level::Error, format_args!("adc injected timeout"));
+        stats::inc(&stats::ADC_FAULTS);
+        0
+    }
+    /// converts the `channel` by the driver, configuring its pin and sample time, the result is dropped,
+    /// returns the converted channel, the default one if the `channel` isn't available
+    fn driverRead(&mut self, adc: &mut Adc<'_, ADC1>, channel: u8) -> u8 {
+        match channel {
+            10 => {
+                ensureAnalogInput(&self.pc0, 10);
+                adc.read(&mut self.pc0);
+                10
+            }
+            13 => {
+                ensureAnalogInput(&self.pc3, 13);
+                adc.read(&mut self.pc3);
+                13
+            }
+            _ => {
+                ensureAnalogInput(&self.pa3, DEFAULT_CHANNEL);
+                adc.read(&mut self.pa3);
+                DEFAULT_CHANNEL
+            }
+        }
+    }
+    /// converts the `channel`, the default input if the channel isn't available,
+    /// returns 0 if the conversion is timed out
+    pub fn read(&mut self, adc: &mut Adc<'_, ADC1>, channel: u8) -> u16 {
+        self.convertChannel(adc, channel, false)
+    }
+    /// converts the `channel` as `read` does, started by the next edge of the external trigger,
+    /// by the software if the edge doesn't come in `TRIGGER_TIMEOUT_SPINS`
+    pub fn readAligned(&mut self, adc: &mut Adc<'_, ADC1>, channel: u8) -> u16 {
+        self.convertChannel(adc, channel, true)
+    }
+    fn convertChannel(&mut self, adc: &mut Adc<'_, ADC1>, channel: u8, aligned: bool) -> u16 {
+        #[cfg(feature = "adc-dma")]
+        adc_dma::stop();
+        self.powerUp();
+        if self.configured != Some(channel) {
+            self.configured = Some(channel);
+            let id = self.driverRead(adc, channel);
+            configureSequence(id, self.sequence);
+            self.settling = self.warmup;
+        }
+        // the timeout of the dropped sample is caught by the next one
+        while self.settling > 0 {
+            self.settling -= 1;
+            convert(self.sequence, false);
+        }
+        let value = convert(self.sequence, aligned);
+        #[cfg(feature = "ext-mux")]
+        if let Some((_, mux)) = self.mux.as_mut().filter(|(muxed, _)| *muxed == channel) {
+            mux.step();
+        }
+        match value {
+            Some(value) => value,
+            None => {
+                error!("[adc] conversion timeout on channel {}, re-enabling ADC", channel);
+                ring_log::push(Level::Error, format_args!("adc timeout on channel {}", channel));
+                stats::inc(&stats::ADC_FAULTS);
+                reenable();
+                self.configured = None;
+                0
+            }
+        }
+    }
+}
+
+/// the ADC clock cycles of the `time`
+pub fn sampleCycles(time: SampleTime) -> u32 {
+    SAMPLE_TIMES.iter().find(|(t, _)| *t == time).map_or(0, |(_, cycles)| *cycles)
+}
+
+/// the shortest sample time charging the sampling capacitor through the source of `ohms` at the applied ADC clock,
+/// `k >= (R_AIN + R_ADC) * f_ADC * C_ADC * ln(2^14) + 0.5`, the longest one if none is enough (above ~250 kOhm
+/// at 27 MHz, buffer such a source)
+pub fn minSampleTimeForImpedance(ohms: u32) -> SampleTime {
+    // the time constant in ADC clock cycles * 1000: ohm * MHz * fF is scaled by 10^6 * 10^-15
+    let tauMilli = (ohms as u64 + ADC_SWITCH_OHMS) * (clocks::adcHz() / 1_000_000) as u64 * ADC_CAPACITANCE_FF / 1_000;
+    let cyclesMilli = tauMilli * SETTLING_LN_MILLI / 1_000_000 + 500;
+    SAMPLE_TIMES
+        .iter()
+        .find(|(_, cycles)| *cycles as u64 * 1000 >= cyclesMilli)
+        .map_or(SampleTime::Cycles480, |(time, _)| *time)
+}
+
+/// checks the sample time `time` against the source of `ohms`, if it's too short: panics if `strict`,
+/// otherwise warns with the recommended one
+pub fn checkSampleTime(time: SampleTime, ohms: u32, strict: bool) {
+    let (cycles, required) = (sampleCycles(time), sampleCycles(minSampleTimeForImpedance(ohms)));
+    if cycles >= required {
+        info!("[adc] sample time of {} cycles is enough for {} Ohm source", cycles, ohms);
+    } else if strict {
+        panic!("[adc] sample time of {} cycles is too short for {} Ohm source, {} required", cycles, ohms, required);
+    } else {
+        warn!("[adc] sample time of {} cycles is too short for {} Ohm source, {} required", cycles, ohms, required);
+    }
+}
+
+/// writes the ADC1 control and status registers into `buf`, returns the written length:
+/// | sr | cr1 | cr2 | smpr1 | smpr2 | sqr1 | sqr2 | sqr3 | jsqr | ccr |, each u32,
+/// only the registers without read side effects are read (not the data ones), the conversion isn't disturbed
+#[cfg(feature = "adc-dump")]
+pub fn dumpRegisters(buf: &mut [u8]) -> usize {
+    let regs = pac::ADC1;
+    let values = unsafe {
+        [
+            regs.sr().read().0,
+            regs.cr1().read().0,
+            regs.cr2().read().0,
+            regs.smpr1().read().0,
+            regs.smpr2().read().0,
+            regs.sqr1().read().0,
+            regs.sqr2().read().0,
+            regs.sqr3().read().0,
+            regs.jsqr().read().0,
+            pac::ADC_COMMON.ccr().read().0,
+        ]
+    };
+    for (i, value) in values.iter().enumerate() {
+        buf[i * 4..i * 4 + 4].copy_from_slice(&value.to_be_bytes());
+    }
+    values.len() * 4
+}
+
+/// puts the `pin` into the analog mode with the pull-up / pull-down disabled.
+/// The internal pull (~40 kOhm) forms the divider with the source impedance, shifting the measured
+/// voltage by the amount depending on the source, and the digital input buffer left enabled outside
+/// the analog mode leaks current into the sampling capacitor - both are offsets the tare can't follow
+pub fn configureAnalogInput(pin: &impl Pin) {
+    let (block, n) = (gpioBlock(pin.port()), pin.pin() as usize);
+    unsafe {
+        block.pupdr().modify(|w| w.set_pupdr(n, vals::Pupdr::FLOATING));
+        block.moder().modify(|w| w.set_moder(n, vals::Moder::ANALOG));
+    }
+}
+
+/// return true if the `pin` is in the analog mode without the pull
+pub fn isAnalogInput(pin: &impl Pin) -> bool {
+    let (block, n) = (gpioBlock(pin.port()), pin.pin() as usize);
+    unsafe {
+        block.moder().read().moder(n) == vals::Moder::ANALOG && block.pupdr().read().pupdr(n) == vals::Pupdr::FLOATING
+    }
+}
+
+/// reconfigures the `pin` of the `channel` if it isn't the analog input without pull
+fn ensureAnalogInput(pin: &impl Pin, channel: u8) {
+    if !isAnalogInput(pin) {
+        warn!("[adc] channel {} pin isn't the analog input without pull, reconfigured", channel);
+        configureAnalogInput(pin);
+    }
+}
+
+/// the GPIO port registers by the port number, 0 - GPIOA
+fn gpioBlock(port: u8) -> Gpio {
+    match port {
+        0 => pac::GPIOA,
+        1 => pac::GPIOB,
+        2 => pac::GPIOC,
+        3 => pac::GPIOD,
+        4 => pac::GPIOE,
+        5 => pac::GPIOF,
+        6 => pac::GPIOG,
+        7 => pac::GPIOH,
+        8 => pac::GPIOI,
+        9 => pac::GPIOJ,
+        _ => pac::GPIOK,
+    }
+}
+
+/// sets the regular sequence to `length` conversions of the `channel`,
+/// the end of conversion is flagged after each one
+fn configureSequence(channel: u8, length: u8) {
+    let regs = pac::ADC1;
+    unsafe {
+        regs.sqr1().modify(|w| w.set_l(length - 1));
+        for i in 0..length as usize {
+            match i {
+                0..=5 => regs.sqr3().modify(|w| w.set_sq(i, channel)),
+                6..=11 => regs.sqr2().modify(|w| w.set_sq(i - 6, channel)),
+                _ => regs.sqr1().modify(|w| w.set_sq(i - 12, channel)),
+            }
+        }
+        regs.cr1().modify(|w| w.set_scan(length > 1));
+        regs.cr2().modify(|w| w.set_eocs(pac::adc::vals::Eocs::EACHCONVERSION));
+    }
+}
+
+/// converts the configured sequence of `length`, started by the external trigger if `aligned`, returns the sum,
+/// None if any conversion isn't completed in time
+fn convert(length: u8, aligned: bool) -> Option<u16> {
+    let regs = pac::ADC1;
+    let mut sum = 0u16;
+    unsafe {
+        regs.sr().modify(|w| {
+            w.set_eoc(false);
+            w.set_strt(false);
+        });
+        if !aligned || !waitStart() {
+            regs.cr2().modify(|w| w.set_swstart(true));
+        }
+        for _ in 0..length {
+            let mut spins = 0;
+            while !regs.sr().read().eoc() {
+                spins += 1;
+                if spins >= CONVERSION_TIMEOUT_SPINS {
+                    return None;
+                }
+            }
+            // reading the data clears the end of conversion
+            sum += regs.dr().read().0 as u16;
+        }
+    }
+    Some(sum)
+}
+
+/// waits for the regular conversion started by the external trigger, returns false if it isn't started in time
+fn waitStart() -> bool {
+    for _ in 0..TRIGGER_TIMEOUT_SPINS {
+        if unsafe { pac::ADC1.sr().read().strt() } {
+            return true;
+        }
+    }
+    log!(Warn, "[adc] no external trigger edge, started by the software");
+    false
+}
+
+/// powers the ADC off and on, dropping the stalled conversion
+fn reenable() {
+    setPower(false);
+    setPower(true);
+}
+
+/// switches the ADC on, waiting for the stabilization, or off, the configuration is retained
+fn setPower(on: bool) {
+    unsafe { pac::ADC1.cr2().modify(|w| w.set_adon(on)) };
+    if on {
+        // ADC stabilization time, 3 us at 216 MHz
+        cortex_m::asm::delay(650);
+    }
+}
+
+/// Position of the conversion result in the 16 bit data register
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Alignment {
+    /// the counts, 0..2^bits
+    Right,
+    /// the counts at the top of the register, the 6 bit ones are aligned to the byte
+    Left,
+}
+
+impl Alignment {
+    /// the left shift of the counts of the resolution of `bits` in the register
+    pub const fn shift(self, bits: u32) -> u32 {
+        match (self, bits) {
+            (Self::Right, _) => 0,
+            (Self::Left, 6) => 2,
+            (Self::Left, bits) => 16 - bits,
+        }
+    }
+}
+
+/// Ordered list of the valid channels
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct ChannelList {
+    ids: [u8; MAX_CHANNELS],
+    len: u8,
+}
+
+impl ChannelList {
+    /// returns None if the list is empty, too long or contains an unavailable channel
+    pub fn fromSlice(ids: &[u8]) -> Option<Self> {
+        if ids.is_empty() || ids.len() > MAX_CHANNELS || !ids.iter().all(|id| Inputs::isValid(*id)) {
+            return None;
+        }
+        let mut list = Self {
+            ids: [0; MAX_CHANNELS],
+            len: ids.len() as u8,
+        };
+        list.ids[..ids.len()].copy_from_slice(ids);
+        Some(list)
+    }
+    pub fn single(channel: u8) -> Self {
+        let mut list = Self {
+            ids: [0; MAX_CHANNELS],
+            len: 1,
+        };
+        list.ids[0] = channel;
+        list
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        &self.ids[..self.len as usize]
+    }
+}
+
+/// Cycles over the channel list spending `dwell` datagrams on each channel,
+/// the single channel list never switches.
+///
+/// The channel with the rate divider `N` is taken in every `N`-th cycle over the list only,
+/// the cycles without any due channel are skipped. The schedule repeats every LCM of the dividers cycles,
+/// the first cycle of the period takes all the channels.
+pub struct Sweep {
+    channels: ChannelList,
+    /// rate dividers in the order of `channels`
+    dividers: [u8; MAX_CHANNELS],
+    dwell: u16,
+    index: usize,
+    count: u16,
+    /// the cycle over the list within the schedule period
+    cycle: u32,
+    /// the schedule period in cycles, the LCM of the dividers
+    period: u32,
+}
+
+impl Sweep {
+    pub fn new(channels: ChannelList, dwell: u16) -> Self {
+        Self {
+            channels,
+            dividers: [1; MAX_CHANNELS],
+            dwell: dwell.max(1),
+            index: 0,
+            count: 0,
+            cycle: 0,
+            period: 1,
+        }
+    }
+    /// sets the rate `divider` (1..=`MAX_DIVIDER`) of the `channel` if it's in the list
+    pub fn setDivider(&mut self, channel: u8, divider: u8) {
+        if let Some(index) = self.channels.as_slice().iter().position(|id| *id == channel) {
+            self.dividers[index] = divider.clamp(1, MAX_DIVIDER);
+        }
+        let dividers = &self.dividers[..self.channelCount()];
+        self.period = dividers.iter().fold(1, |period, divider| lcm(period, *divider as u32));
+        self.cycle = 0;
+    }
+    /// the schedule period in cycles over the list
+    pub fn period(&self) -> u32 {
+        self.period
+    }
+    /// number of the channels cycled over
+    pub fn channelCount(&self) -> usize {
+        self.channels.as_slice().len()
+    }
+    /// the channel to be sampled now
+    pub fn channel(&self) -> u8 {
+        self.channels.as_slice()[self.index]
+    }
+    /// counts the datagram sent, moves to the next due channel when the dwell is reached
+    pub fn next(&mut self) {
+        self.count += 1;
+        if self.count < self.dwell {
+            return;
+        }
+        self.count = 0;
+        // the first cycle of the period takes every channel, the search ends within the period
+        loop {
+            self.index += 1;
+            if self.index == self.channelCount() {
+                self.index = 0;
+                self.cycle = (self.cycle + 1) % self.period;
+            }
+            if self.cycle % self.dividers[self.index] as u32 == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// the least common multiple of `a` and `b`
+fn lcm(a: u32, b: u32) -> u32 {
+    a / gcd(a, b) * b
+}
+
+/// the greatest common divisor of `a` and `b`
+fn gcd(a: u32, b: u32) -> u32 {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    }
+}
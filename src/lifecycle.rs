@@ -0,0 +1,213 @@
+//! Transitions of the data socket lifecycle
+//!
+//! `session::SessionState` carries the payloads of the phases (the handshake, the client, the pending command),
+//! the phase it moves to is decided here, free of the sockets and the payloads:
+//! ```not_rust
+//! Unbound --Bound--> Idle --Handshake--> Streaming --Ended--> Idle
+//!    ^ |               |                     |
+//!    +-+ BindFailed    +---AddressChanged----+--> Unbound
+//! ```
+//! The socket entering `Unbound` is closed (see `step`), so it's bound on the current address again.
+//! The handshake received while streaming isn't a transition of its own: the running session ends
+//! (`Ended`, the handshake carried to `Idle` as pending), then the pending handshake starts the new one,
+//! so `Streaming --Handshake-->` is rejected and the replaced session always passes `Idle`.
+//! The module depends on `core` only, so its tests run on the host apart from the firmware:
+//! `rustc --edition 2021 --test src/lifecycle.rs -o /tmp/lifecycle && /tmp/lifecycle`
+
+/// The phase of the data socket
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(test), derive(defmt::Format))]
+pub enum Phase {
+    Unbound,
+    Idle,
+    Streaming,
+}
+
+/// What moves the data socket between the phases
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(test), derive(defmt::Format))]
+pub enum Event {
+    /// the socket is bound to the address
+    Bound,
+    /// no address yet or the bind failed
+    BindFailed,
+    /// the handshake is received (or the autostart one is pending) while idle
+    Handshake,
+    /// the session ended: stopped, closed, replaced or the command pending
+    Ended,
+    /// the local address changed, the socket has to be bound again
+    AddressChanged,
+}
+
+/// the phase after the `event` in the `phase`, None - the event can't happen there
+pub fn next(phase: Phase, event: Event) -> Option<Phase> {
+    match (phase, event) {
+        (Phase::Unbound, Event::Bound) => Some(Phase::Idle),
+        (Phase::Unbound, Event::BindFailed) => Some(Phase::Unbound),
+        (Phase::Idle, Event::Handshake) => Some(Phase::Streaming),
+        (Phase::Streaming, Event::Ended) => Some(Phase::Idle),
+        (Phase::Idle | Phase::Streaming, Event::AddressChanged) => Some(Phase::Unbound),
+        _ => None,
+    }
+}
+
+/// The data socket as the lifecycle drives it
+pub trait Socket {
+    /// drops the binding, if any
+    fn close(&mut self);
+}
+
+/// the phase after the `event` in the `phase` (see `next`), the `socket` entering `Unbound` is closed,
+/// None - the event can't happen there, the socket is left as it is
+pub fn step(socket: &mut impl Socket, phase: Phase, event: Event) -> Option<Phase> {
+    let next = next(phase, event)?;
+    if next == Phase::Unbound {
+        socket.close();
+    }
+    Some(next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bound_socket_goes_idle() {
+        assert_eq!(next(Phase::Unbound, Event::Bound), Some(Phase::Idle));
+    }
+
+    #[test]
+    fn failed_bind_stays_unbound() {
+        assert_eq!(next(Phase::Unbound, Event::BindFailed), Some(Phase::Unbound));
+    }
+
+    #[test]
+    fn handshake_starts_streaming() {
+        assert_eq!(next(Phase::Idle, Event::Handshake), Some(Phase::Streaming));
+    }
+
+    #[test]
+    fn ended_session_goes_idle() {
+        assert_eq!(next(Phase::Streaming, Event::Ended), Some(Phase::Idle));
+    }
+
+    #[test]
+    fn address_change_rebinds() {
+        assert_eq!(next(Phase::Idle, Event::AddressChanged), Some(Phase::Unbound));
+        assert_eq!(next(Phase::Streaming, Event::AddressChanged), Some(Phase::Unbound));
+    }
+
+    #[test]
+    fn unexpected_events_are_rejected() {
+        assert_eq!(next(Phase::Unbound, Event::Handshake), None);
+        assert_eq!(next(Phase::Unbound, Event::Ended), None);
+        assert_eq!(next(Phase::Unbound, Event::AddressChanged), None);
+        assert_eq!(next(Phase::Idle, Event::Bound), None);
+        assert_eq!(next(Phase::Idle, Event::Ended), None);
+        assert_eq!(next(Phase::Streaming, Event::Bound), None);
+        assert_eq!(next(Phase::Streaming, Event::Handshake), None);
+    }
+
+    #[test]
+    fn rehandshake_passes_idle() {
+        assert_eq!(next(Phase::Streaming, Event::Handshake), None);
+        let events = [Event::Ended, Event::Handshake];
+        let phase = events.iter().try_fold(Phase::Streaming, |phase, event| next(phase, *event));
+        assert_eq!(phase, Some(Phase::Streaming));
+    }
+
+    #[test]
+    fn full_cycle() {
+        let events = [Event::BindFailed, Event::Bound, Event::Handshake, Event::Ended, Event::Handshake];
+        let phase = events.iter().try_fold(Phase::Unbound, |phase, event| next(phase, *event));
+        assert_eq!(phase, Some(Phase::Streaming));
+        assert_eq!(next(Phase::Streaming, Event::AddressChanged), Some(Phase::Unbound));
+    }
+
+    /// the socket of the port, bound once, the binds failing while `failing`
+    #[derive(Default)]
+    struct MockSocket {
+        bound: bool,
+        binds: u32,
+        closes: u32,
+        failing: u32,
+    }
+
+    impl MockSocket {
+        /// the event of binding the socket as `main` does
+        fn bind(&mut self) -> Event {
+            if self.bound {
+                // the port already bound
+                return Event::BindFailed;
+            }
+            if self.failing > 0 {
+                self.failing -= 1;
+                return Event::BindFailed;
+            }
+            self.bound = true;
+            self.binds += 1;
+            Event::Bound
+        }
+    }
+
+    impl Socket for MockSocket {
+        fn close(&mut self) {
+            self.bound = false;
+            self.closes += 1;
+        }
+    }
+
+    #[test]
+    fn failed_bind_retried_until_bound() {
+        let mut socket = MockSocket { failing: 2, ..MockSocket::default() };
+        let mut phase = Phase::Unbound;
+        while phase == Phase::Unbound {
+            let event = socket.bind();
+            phase = step(&mut socket, phase, event).unwrap();
+        }
+        assert_eq!(phase, Phase::Idle);
+        assert!(socket.bound);
+        assert_eq!((socket.binds, socket.closes), (1, 2));
+    }
+
+    #[test]
+    fn session_keeps_socket_bound() {
+        let mut socket = MockSocket::default();
+        let event = socket.bind();
+        let phase = step(&mut socket, Phase::Unbound, event).unwrap();
+        let phase = step(&mut socket, phase, Event::Handshake).unwrap();
+        assert_eq!(phase, Phase::Streaming);
+        let phase = step(&mut socket, phase, Event::Ended).unwrap();
+        let phase = step(&mut socket, phase, Event::Handshake).unwrap();
+        assert_eq!(phase, Phase::Streaming);
+        assert!(socket.bound);
+        assert_eq!(socket.closes, 0);
+    }
+
+    #[test]
+    fn address_change_rebinds_socket() {
+        for from in [Phase::Idle, Phase::Streaming] {
+            let mut socket = MockSocket::default();
+            let event = socket.bind();
+            step(&mut socket, Phase::Unbound, event).unwrap();
+            let phase = step(&mut socket, from, Event::AddressChanged).unwrap();
+            assert_eq!(phase, Phase::Unbound);
+            assert!(!socket.bound);
+            // bound again on the new address, never failing on the old binding
+            let event = socket.bind();
+            assert_eq!(step(&mut socket, phase, event), Some(Phase::Idle));
+            assert_eq!((socket.binds, socket.closes), (2, 1));
+        }
+    }
+
+    #[test]
+    fn rejected_event_leaves_socket() {
+        let mut socket = MockSocket::default();
+        let event = socket.bind();
+        step(&mut socket, Phase::Unbound, event).unwrap();
+        assert_eq!(step(&mut socket, Phase::Streaming, Event::Handshake), None);
+        assert_eq!(step(&mut socket, Phase::Idle, Event::Ended), None);
+        assert!(socket.bound);
+        assert_eq!(socket.closes, 0);
+    }
+}
@@ -0,0 +1,63 @@
+//! Datagrams held over the brief link flap
+//!
+//! The cable wiggle drops the link for milliseconds, with DHCP the stack drops the address with it.
+//! Instead of ending the session the loop keeps acquiring and packing, the datagrams are appended
+//! to the hold buffer as they are:
+//! ```not_rust
+//! | len: u16 | datagram... | len: u16 | datagram... | ... free |
+//! ```
+//! When the link (and the address) is back they are sent in order before the next one,
+//! so the client sees the sequence continue, only delayed. The datagram not fitting the rest of the buffer
+//! is dropped and counted, the sequence has the gap then. The buffer is drained at once,
+//! nothing is appended while it's sent, so it's linear, not the ring.
+/// size of the length prefix of each held datagram
+const LEN_SIZE: usize = 2;
+
+/// The datagrams waiting for the link
+pub struct LinkHold {
+    buf: &'static mut [u8],
+    /// the bytes used
+    len: usize,
+    /// the datagrams held
+    count: u32,
+}
+
+impl LinkHold {
+    pub fn new(buf: &'static mut [u8]) -> Self {
+        Self { buf, len: 0, count: 0 }
+    }
+    /// appends the `datagram`, returns false if it doesn't fit
+    pub fn push(&mut self, datagram: &[u8]) -> bool {
+        let end = self.len + LEN_SIZE + datagram.len();
+        if end > self.buf.len() {
+            return false;
+        }
+        self.buf[self.len..self.len + LEN_SIZE].copy_from_slice(&(datagram.len() as u16).to_be_bytes());
+        self.buf[self.len + LEN_SIZE..end].copy_from_slice(datagram);
+        self.len = end;
+        self.count += 1;
+        true
+    }
+    /// the datagrams held
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+    /// the held datagrams in order
+    pub fn datagrams(&self) -> impl Iterator<Item = &[u8]> {
+        let mut rest = &self.buf[..self.len];
+        core::iter::from_fn(move || {
+            if rest.len() < LEN_SIZE {
+                return None;
+            }
+            let (len, tail) = rest.split_at(LEN_SIZE);
+            let (datagram, tail) = tail.split_at(u16::from_be_bytes([len[0], len[1]]) as usize);
+            rest = tail;
+            Some(datagram)
+        })
+    }
+    /// drops the held datagrams, returns their count
+    pub fn clear(&mut self) -> u32 {
+        self.len = 0;
+        core::mem::take(&mut self.count)
+    }
+}
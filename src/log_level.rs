@@ -0,0 +1,72 @@
+//! Runtime log level
+//!
+//! The `defmt` level is fixed at compile time by `DEFMT_LOG`,
+//! this one can be changed on the running board with the `LOG_LEVEL` control packet.
+//! Hot path logs are checked against it before formatting by `log!`, e.g. `log!(Info, "sent {}", len)`,
+//! `error!` messages are never gated.
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use defmt::Format;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Format)]
+#[repr(u8)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl Level {
+    /// returns the level by its wire value, None if value is unknown
+    pub fn fromU8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Level::Error),
+            1 => Some(Level::Warn),
+            2 => Some(Level::Info),
+            3 => Some(Level::Debug),
+            _ => None,
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Debug as u8);
+
+/// sets the current runtime log level
+pub fn set(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// returns the current runtime log level
+pub fn get() -> Level {
+    Level::fromU8(LEVEL.load(Ordering::Relaxed)).unwrap_or(Level::Debug)
+}
+
+/// return true if message of the `level` has to be logged,
+/// error level is always enabled
+pub fn enabled(level: Level) -> bool {
+    level == Level::Error || (level as u8) <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// logs the defmt message of the `Level` variant if it's `enabled`, formatted only then
+macro_rules! log {
+    (Error, $($arg:tt)+) => {
+        defmt::error!($($arg)+)
+    };
+    (Warn, $($arg:tt)+) => {
+        if $crate::log_level::enabled($crate::log_level::Level::Warn) {
+            defmt::warn!($($arg)+)
+        }
+    };
+    (Info, $($arg:tt)+) => {
+        if $crate::log_level::enabled($crate::log_level::Level::Info) {
+            defmt::info!($($arg)+)
+        }
+    };
+    (Debug, $($arg:tt)+) => {
+        if $crate::log_level::enabled($crate::log_level::Level::Debug) {
+            defmt::debug!($($arg)+)
+        }
+    };
+}
+pub(crate) use log;
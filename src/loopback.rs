@@ -0,0 +1,129 @@
+//! Loopback of the reference waveform for the validation of the data path
+//!
+//! With the `loopback` feature the plain stream isn't acquired from the ADC, `LoopbackSource` sends
+//! the reference waveform uploaded by the client instead, repeated cyclically at the configured rate,
+//! so the host compares what it receives with what it uploaded and checks the packing, the sequence,
+//! the paging and the network path apart from the analog front end. The samples go through the same zero,
+//! gain and format as the converted ones, they're the codes the ADC would give.
+//!
+//! The reference is uploaded by `REFERENCE` in chunks (the control packet is bounded by `CMD_BUF_SIZE`),
+//! the chunk at offset 0 starts the new reference of `total` samples, zero filled until the following chunks
+//! arrive. The reference longer than `CAPACITY` or the chunk beyond `total` is rejected.
+//! The datagram is released when its samples would have been acquired at the rate, the late one starts
+//! the pacing over. Until the upload the samples are 0.
+//!
+//! The other modes (triggered, swept, injected, timestamped) keep converting the ADC.
+use core::cell::RefCell;
+
+use defmt::Format;
+use embassy_stm32::adc::Adc;
+use embassy_stm32::peripherals::ADC1;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::inputs::Inputs;
+use crate::source::SampleSource;
+use crate::timestamp;
+
+/// the longest reference, samples
+pub const CAPACITY: usize = 1024;
+
+struct Reference {
+    samples: [u16; CAPACITY],
+    len: usize,
+}
+
+static REFERENCE: Mutex<CriticalSectionRawMutex, RefCell<Reference>> =
+    Mutex::new(RefCell::new(Reference { samples: [0; CAPACITY], len: 0 }));
+
+/// The rejected upload
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum UploadError {
+    /// the reference doesn't fit `CAPACITY`
+    TooLong,
+    /// the chunk is beyond the reference or continues no upload
+    OutOfRange,
+}
+
+/// stores the `chunk` of the reference of `total` samples at `offset`,
+/// the chunk at offset 0 starts the new reference
+pub fn upload(total: u16, offset: u16, chunk: &[u16]) -> Result<(), UploadError> {
+    let (total, offset) = (total as usize, offset as usize);
+    if total > CAPACITY {
+        return Err(UploadError::TooLong);
+    }
+    REFERENCE.lock(|reference| {
+        let mut reference = reference.borrow_mut();
+        if offset == 0 {
+            reference.samples[..total].fill(0);
+            reference.len = total;
+        }
+        if reference.len != total || offset + chunk.len() > total {
+            return Err(UploadError::OutOfRange);
+        }
+        reference.samples[offset..offset + chunk.len()].copy_from_slice(chunk);
+        Ok(())
+    })
+}
+
+/// Reference waveform sent instead of the conversions
+pub struct LoopbackSource {
+    /// the sample period in nanoseconds
+    periodNs: u64,
+    /// the position in the reference of the next sample
+    position: usize,
+    /// the time the next datagram starts at, None - at once
+    next: Option<Instant>,
+}
+
+impl LoopbackSource {
+    /// `rateHz` - the samples per second
+    pub fn new(rateHz: u32) -> Self {
+        Self { periodNs: 1_000_000_000 / rateHz.max(1) as u64, position: 0, next: None }
+    }
+}
+
+impl SampleSource for LoopbackSource {
+    async fn fill(
+        &mut self,
+        _inputs: &mut Inputs,
+        _adc: &mut Adc<'_, ADC1>,
+        _channel: u8,
+        samples: &mut [u16],
+        blockSamples: usize,
+        offsets: &mut [u32],
+    ) -> Option<u32> {
+        let now = Instant::now();
+        let start = match self.next {
+            Some(next) if next >= now => next,
+            _ => now,
+        };
+        REFERENCE.lock(|reference| {
+            let reference = reference.borrow();
+            match reference.len {
+                0 => samples.fill(0),
+                len => {
+                    for sample in samples.iter_mut() {
+                        self.position %= len;
+                        *sample = reference.samples[self.position];
+                        self.position += 1;
+                    }
+                }
+            }
+        });
+        let blocks = (samples.len() + blockSamples - 1) / blockSamples;
+        for (n, offset) in offsets[..blocks].iter_mut().enumerate() {
+            *offset = ((n * blockSamples) as u64 * self.periodNs / 1000) as u32;
+        }
+        let end = start + Duration::from_micros(samples.len() as u64 * self.periodNs / 1000);
+        let stamp = timestamp::now();
+        Timer::at(end).await;
+        self.next = Some(end);
+        Some(stamp)
+    }
+    fn stop(&mut self) {
+        self.position = 0;
+        self.next = None;
+    }
+}
@@ -1,27 +1,118 @@
 #![no_std]
 #![no_main]
 #![feature(type_alias_impl_trait)]
+#![feature(async_fn_in_trait)]
+#![allow(incomplete_features)]
 #![allow(non_snake_case)]
 
+#[cfg(feature = "adc-dma")]
+mod adc_dma;
+mod arp_guard;
+mod backup;
+mod beacon;
+mod churn;
+mod client;
+mod clocks;
+mod control;
+mod control_port;
+#[cfg(feature = "data-ready")]
+mod data_ready;
+mod crc;
+mod deadman;
+#[cfg(feature = "ext-mux")]
+mod ext_mux;
+mod ext_trigger;
+mod health;
+mod inputs;
+mod lifecycle;
+mod link_hold;
+mod log_level;
+#[cfg(feature = "loopback")]
+mod loopback;
+#[cfg(feature = "mdns")]
+mod mdns;
+mod pacer;
+mod packet;
+mod phy;
+mod processor;
+mod rate;
+mod ring_log;
+mod selfcheck;
+mod session;
+mod settings;
+mod sink;
+mod source;
+mod stack;
+mod stats;
+mod telemetry;
+mod timestamp;
+mod tlv;
+mod trigger;
+mod wall_clock;
 
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use defmt::*;
 use heapless::Vec;
 use embassy_executor::{Spawner};
 use embassy_net::udp::UdpSocket;
-use embassy_net::{Ipv4Address, Ipv4Cidr, Stack, StackResources, udp::PacketMetadata};
-use embassy_time::{Duration, Timer, Delay, Instant};
-use embassy_stm32::adc::{Adc, SampleTime};
-use embassy_stm32::eth::generic_smi::GenericSMI;
+use embassy_net::{IpEndpoint, Ipv4Address, Ipv4Cidr, Stack, StackResources, udp::PacketMetadata};
+use embassy_time::{with_timeout, Duration, Timer, Delay, Instant};
+use futures::future::{select, Either};
+use embassy_stm32::adc::{Adc, Resolution, SampleTime};
 use embassy_stm32::eth::{Ethernet, PacketQueue};
-use embassy_stm32::peripherals::ETH;
+use embassy_stm32::flash::Flash;
+#[cfg(any(feature = "data-ready", feature = "ext-mux"))]
+use embassy_stm32::gpio::Pin as _;
+use embassy_stm32::peripherals::{ADC1, ETH, IWDG, RNG, RTC};
 use embassy_stm32::rng::Rng;
+use embassy_stm32::rtc::{Rtc, RtcConfig};
 use embassy_stm32::time::mhz;
+use embassy_stm32::wdg::IndependentWatchdog;
 use embassy_stm32::{interrupt, Config};
 use rand_core::RngCore;
 use static_cell::StaticCell;
 use {defmt_rtt as _, panic_probe as _};
 
+#[cfg(all(feature = "adc-dma", not(feature = "loopback")))]
+use crate::adc_dma::AdcDma;
+use crate::arp_guard::{ArpGuard, DuplicateIp};
+use crate::control::{Command, Handshake, HandshakeAck, Injected, Mode, Negotiated, NegotiatedParams};
+#[cfg(feature = "data-ready")]
+use crate::data_ready::DataReady;
+#[cfg(feature = "ext-mux")]
+use crate::ext_mux::ExtMux;
+use crate::ext_trigger::ExtTrigger;
+use crate::inputs::{Alignment, ChannelList, Inputs, Sweep};
+use crate::link_hold::LinkHold;
+use crate::log_level::{log, Level};
+use crate::packet::{
+    Contents, Layout, Metadata, PacketHeader, SampleFormat, StreamFormat, SubHeader, Summary, BASE_STAMP_SIZE,
+    CHANNEL_MILLIVOLTS, ETH_MTU, FLAG_ENVELOPE, FLAG_INJECTED, FLAG_LATE_START, FLAG_NO_SAMPLES, FLAG_RESUMED,
+    FLAG_SUBBLOCKS, FLAG_SUMMARY, FLAG_TIMESTAMPS, HEADER_SIZE, MAX_BLOCKS, MAX_DATAGRAM, MIN_INJECTED_EVERY, MIN_MTU,
+    MUX_NONE, SAMPLE_INDEX_SIZE, TIMESTAMP_STRIDE, UNITY_GAIN,
+};
+use crate::phy::ForcedPhy;
+use crate::pacer::Pacer;
+use crate::processor::{BlockProcessor, NoProcessor};
+use crate::rate::RateMeter;
+use crate::session::{
+    self, isValidClient, requestUnits, takeUnits, Break, Session, SessionState, StreamEndReason, Suspended,
+};
+use crate::settings::Settings;
+use crate::sink::{Sink, UdpSink};
+#[cfg(feature = "loopback")]
+use crate::loopback::LoopbackSource;
+#[cfg(not(any(feature = "adc-dma", feature = "loopback")))]
+use crate::source::BlockingSource;
+#[cfg(all(feature = "adc-dma", not(feature = "loopback")))]
+use crate::source::DmaSource;
+use crate::source::SampleSource;
+#[cfg(all(feature = "dma-stamps", not(feature = "loopback")))]
+use crate::timestamp::StampCapture;
+use crate::trigger::{self, PreTrigger, Trigger};
+
 
 // T, uc	QSIZE
 // 976.563	1 024
@@ -36,14 +127,218 @@ use {defmt_rtt as _, panic_probe as _};
 // 1.907	524 288
 
 const UDP_PORT: u16 = 15180;
+/// used until the address is set by the SET_IP command, all zeros ip - DHCP
+const DEFAULT_IP: Settings = Settings {
+    ip: [192, 168, 120, 173],
+    prefix: 24,
+    gateway: [192, 168, 120, 1],
+};
+/// guards the commands changing the board configuration
+const ADMIN_TOKEN: u32 = 0x5354_4D32;
+const MAC_ADDR: [u8; 6] = [0x00, 0x00, 0xDE, 0xAD, 0xBE, 0xEF];
+/// after the reset the session interrupted by it is started again to the same client with the default settings
+const AUTOSTART_LAST_CLIENT: bool = false;
+/// in the continuous modes the first datagram is acquired before the handshake acknowledgment is sent,
+/// so the data follows the acknowledgment immediately
+const PREFILL_FIRST_DATAGRAM: bool = true;
+/// the stop (`CAN`) sends the blocks of the datagram already acquired (the triggered blocks captured
+/// while waiting for the next trigger) and then the end marker instead of discarding them, see `sendEndMarker`
+const FLUSH_ON_STOP: bool = false;
+/// the raw samples (unsigned format, not tared, unity gain) are packed by swapping the buffer in place
+/// with `REV16` instead of encoding them one by one, see `packet::packRaw`
+const FAST_RAW_PACK: bool = true;
+/// the sample encodings the board offers to the client listing its own (the `7` handshake record),
+/// e.g. without `CAP_WORD_LE` the samples stay big endian, see `packet::negotiate`
+const SAMPLE_ENCODINGS: u8 = packet::CAP_WORD_BE | packet::CAP_WORD_LE;
+/// the end marker waits for the room in the TX buffer this long at most, the client may be gone
+const FLUSH_TIMEOUT: Duration = Duration::from_millis(100);
+/// the handshake arriving sooner after the end of the previous session is deferred until it passes,
+/// the TX buffer drains the datagrams of the previous session meanwhile, 0 - none
+const SESSION_COOLDOWN: Duration = Duration::from_millis(20);
+/// the acknowledgment of the `CONFIRM_ACK` handshake is resent this many times waiting for the confirmation,
+/// the wait is doubled after each attempt starting from `ACK_RETRY_PERIOD` (~2.5 s in total)
+const ACK_RETRIES: u32 = 6;
+const ACK_RETRY_PERIOD: Duration = Duration::from_millis(20);
+/// force 100M full duplex if the switch negotiates the other mode
+const ETH_FORCE_100_FULL: bool = false;
+/// SYSCLK, the Ethernet and ADC clocks derived from it are checked at startup, see `clocks`,
+/// 216 - the 27 MHz ADC clock and the exact 27 MHz timestamps
+const SYS_CLOCK_MHZ: u32 = 216;
+// at 200 MHz the 100 MHz timer clock isn't a multiple of the 27 MHz stamps (refused by `timestamp::init`),
+// the profile is left to the `main_int.rs` experiment
+const _: () = assert!(SYS_CLOCK_MHZ == 216);
 
-
-const SYN: u8 = 22;
-const EOT: u8 = 4;
 // const ADC_READ_DELAY: Duration = Duration::from_micros(61);
+const ADC_RESOLUTION: Resolution = Resolution::TwelveBit;
+/// the position of the result in the data register, `Left` - the 12 bit full scale is the 16 bit one,
+/// see `inputs::Alignment`
+const ADC_ALIGNMENT: Alignment = Alignment::Right;
+/// conversions of the regular sequence summed into one sample, 1 - no oversampling,
+/// divides the sample rate and multiplies the full scale (thresholds and offsets are in the summed counts)
+const ADC_SEQUENCE: u8 = 1;
+const _: () = assert!(ADC_SEQUENCE >= 1 && ADC_SEQUENCE <= inputs::MAX_SEQUENCE);
+// the sum of the left aligned conversions overflows
+const _: () = assert!(ADC_SEQUENCE == 1 || matches!(ADC_ALIGNMENT, Alignment::Right));
+/// samples dropped after the channel switch, the ADC power up and at the session start
+/// while the sample-and-hold and the reference settle, see `inputs`
+const ADC_WARMUP: u8 = 2;
+/// the hardware source starting the regular conversions in addition to the software start, None - software only,
+/// the timer is set up by the board specific code, see `ext_trigger`
+const ADC_EXT_TRIGGER: Option<ExtTrigger> = None;
+const _: () = assert!(match ADC_EXT_TRIGGER {
+    Some(trigger) => trigger.isAvailable(),
+    None => true,
+});
+/// each block starts at the edge of `ADC_EXT_TRIGGER` instead of at once, see `Inputs::readAligned`,
+/// the rest of the block is paced by the acquisition loop as before
+const ALIGN_BLOCKS: bool = false;
+const _: () = assert!(!ALIGN_BLOCKS || ADC_EXT_TRIGGER.is_some());
+/// the DMA samples the single conversions, see `source`
+#[cfg(feature = "adc-dma")]
+const _: () = assert!(ADC_SEQUENCE == 1);
+/// the sample time of the ADC channels, the dual interleaved mode needs the short one, see `adc_dma`
+#[cfg(not(feature = "adc-dual"))]
+const ADC_SAMPLE_TIME: SampleTime = SampleTime::Cycles144;
+#[cfg(all(feature = "adc-dma", not(feature = "adc-dual")))]
+const ADC_SAMPLE_CYCLES: u32 = 144;
+#[cfg(feature = "adc-dual")]
+const ADC_SAMPLE_TIME: SampleTime = SampleTime::Cycles3;
+#[cfg(feature = "adc-dual")]
+const ADC_SAMPLE_CYCLES: u32 = 3;
+/// the output impedance of the sources on the inputs, the sample time is checked against it at startup,
+/// see `inputs::minSampleTimeForImpedance`, None - not checked
+const ADC_SOURCE_OHMS: Option<u32> = None;
+/// the too short sample time for `ADC_SOURCE_OHMS` stops the startup instead of the warning
+const ADC_SOURCE_STRICT: bool = false;
+/// ADC clock cycles per conversion: the sample time and 12 bits
+#[cfg(feature = "adc-dma")]
+const ADC_CONVERSION_CYCLES: u32 = ADC_SAMPLE_CYCLES + 12;
+/// ADC2 starts its conversion this many ADC clocks after ADC1 in the dual interleaved mode (`adc-dual`),
+/// the samples are spaced 7 and 8 clocks apart, ~3.6 MS/s at 27 MHz
+#[cfg(feature = "adc-dual")]
+const ADC_DUAL_DELAY: u32 = 7;
+#[cfg(feature = "adc-dual")]
+const _: () = assert!(ADC_DUAL_DELAY >= adc_dma::MIN_DUAL_DELAY && ADC_DUAL_DELAY <= adc_dma::MAX_DUAL_DELAY);
+// the ADCs never sample the shared pin at once, ADC2 starts before ADC1 restarts
+#[cfg(feature = "adc-dual")]
+const _: () = assert!(ADC_SAMPLE_CYCLES < ADC_DUAL_DELAY && ADC_DUAL_DELAY < ADC_CONVERSION_CYCLES);
 const ADC_BUF_SIZE: usize = 512;
-const UDP_BUF_SIZE: usize = 1024;
+/// the threshold crossings ignored after the trigger of the triggered mode, microseconds
+const TRIGGER_HOLDOFF_US: u32 = 0;
+/// the path MTU to the clients, the datagrams are fitted into its UDP payload where possible,
+/// the larger ones are fragmented by IP (warned at init and at the handshake)
+const MTU: usize = ETH_MTU;
+const _: () = assert!(MTU >= MIN_MTU && MTU <= ETH_MTU);
+/// the layout fits the datagram into it, see `Layout::new`, the larger block is paged
+const UDP_BUF_SIZE: usize = MAX_DATAGRAM;
+/// the TX buffer of the data socket, the datagram larger than it is never sent (`send_to` fails),
+/// so the layout pages the blocks not fitting it, see `txCapacity`
+const UDP_TX_BUF_SIZE: usize = UDP_BUF_SIZE;
+const _: () = assert!(UDP_TX_BUF_SIZE >= packet::maxDatagram(MIN_MTU));
+/// the sample rate the stream is expected to achieve (samples per second, the gaps between the datagrams included),
+/// the measured one deviating beyond `SAMPLE_RATE_TOLERANCE_PPM` is flagged in STATS, see `rate`,
+/// None - only measured
+const SAMPLE_RATE_EXPECTED: Option<u32> = None;
+const SAMPLE_RATE_TOLERANCE_PPM: u32 = 10_000;
+/// the sample rate the loopback reference is sent at (`loopback`), see `loopback`
+#[cfg(feature = "loopback")]
+const LOOPBACK_RATE_HZ: u32 = 10_000;
+/// the polarity of the data-ready pulse (`data-ready`, the pin is PD0), see `data_ready`
+#[cfg(feature = "data-ready")]
+const DATA_READY_ACTIVE_HIGH: bool = true;
+/// the external analog mux (`ext-mux`, the select lines PE2..PE5, the low bit first) feeding the channel,
+/// stepped over its positions after each sample, waiting the settle time after the switch, see `ext_mux`
+#[cfg(feature = "ext-mux")]
+const MUX_CHANNEL: u8 = inputs::DEFAULT_CHANNEL;
+#[cfg(feature = "ext-mux")]
+const MUX_CHANNELS: u8 = 8;
+#[cfg(feature = "ext-mux")]
+const MUX_SETTLE_US: u32 = 2;
+#[cfg(feature = "ext-mux")]
+const _: () = assert!(MUX_CHANNELS >= 2 && MUX_CHANNELS <= 1 << ext_mux::SELECT_LINES);
+// the continuous conversions leave no room for the switch
+#[cfg(all(feature = "ext-mux", any(feature = "adc-dma", feature = "loopback")))]
+compile_error!("the external mux is stepped by the blocking conversions, it excludes `adc-dma` and `loopback`");
+// the stamps are captured at the ADC1 trigger, the interleaved ADC2 conversions have none
+#[cfg(all(feature = "dma-stamps", feature = "adc-dual"))]
+compile_error!("`dma-stamps` paces the single ADC, it excludes `adc-dual`");
+// TIM4 paces the stamped conversions, it isn't the external trigger as well
+#[cfg(feature = "dma-stamps")]
+const _: () = assert!(ADC_EXT_TRIGGER.is_none());
+/// the ADC reference (VREF+, tied to VDDA on the Nucleo), the full scale of the millivolts format
+const ADC_VREF_MV: u32 = 3300;
+/// the ADC waiting for the handshake is powered down after this long since the session end (or the boot),
+/// None - it's kept powered, so the session starts without the power-up (stabilization) delay,
+/// at the cost of the ADC supply current while idle
+const ADC_IDLE_TIMEOUT: Option<Duration> = None;
+/// if blocks per datagram isn't requested, smaller blocks are accumulated up to this size before sending,
+/// adding the acquisition time of the accumulated blocks to the latency
+/// (in the triggered mode - waiting for several triggers)
+const MIN_BLOCK_BYTES: usize = ADC_BUF_SIZE * 2;
+/// the sends pending longer without any completed is considered as the network stack stall, see `deadman`
+const NET_STALL_TIMEOUT_MS: u32 = 5_000;
+/// long enough to cover the settings sector erase (up to 4 s)
+const WATCHDOG_TIMEOUT_US: u32 = 8_000_000;
+/// the blocking acquisition of the plain stream masks the interrupts for at most this long at once (microseconds),
+/// the samples of the block aren't preempted at the cost of the network responsiveness, see `source`,
+/// None - the interrupts are never masked
+const LOW_JITTER_MASKED_US: Option<u32> = None;
+/// the longest masked window, microseconds: a thousandth of the watchdog timeout (8 ms),
+/// the stack misses at most a few frames and the watchdog is never at risk
+const LOW_JITTER_MAX_MASKED_US: u32 = WATCHDOG_TIMEOUT_US / 1000;
+const _: () = assert!(match LOW_JITTER_MASKED_US {
+    Some(us) => us > 0 && us <= LOW_JITTER_MAX_MASKED_US,
+    None => true,
+});
+const SUPERVISOR_PERIOD: Duration = Duration::from_secs(1);
+const CMD_BUF_SIZE: usize = 64;
+/// the queued control packets handled per poll while streaming, so the handshake queued
+/// behind the stray packets is taken without waiting for the next datagrams
+const CONTROL_BURST: usize = 8;
+/// the board answers mDNS queries for `<MDNS_HOST>.local` (the `mdns` feature)
+#[cfg(feature = "mdns")]
+const MDNS_HOST: &str = "stm32-adc";
+#[cfg(feature = "mdns")]
+const _: () = assert!(MDNS_HOST.len() <= mdns::MAX_HOST_LEN);
+/// the discovery beacon period, None - the beacon is disabled, see `beacon`
+const BEACON_PERIOD: Option<Duration> = Some(Duration::from_secs(2));
+/// the monitoring collector receiving the telemetry reports (ip, port), None - not sent, see `telemetry`
+const TELEMETRY_COLLECTOR: Option<([u8; 4], u16)> = None;
+/// the period of the telemetry reports
+const TELEMETRY_PERIOD: Duration = Duration::from_secs(10);
+/// how often the control packets are checked while waiting for the delayed start
+const START_POLL_PERIOD: Duration = Duration::from_millis(10);
+/// the paused session is ended after, the client is taken as gone (there is no keepalive of its own)
+const PAUSE_TIMEOUT: Duration = Duration::from_secs(30);
+/// the link (or the DHCP address) lost while streaming is waited for this long, the datagrams are held meanwhile
+/// and sent when it's back, see `link_hold`, None - the session ends at once, both with `StreamEndReason::LinkLost`
+const LINK_FLAP_HOLD: Option<Duration> = Some(Duration::from_secs(5));
+/// the datagrams held over the link flap, the ones not fitting are dropped
+const LINK_HOLD_BUF_SIZE: usize = 32 * 1024;
+/// how often the link is checked while the source waits for the samples (the stalled trigger), see `LINK_FLAP_HOLD`
+const LINK_POLL_PERIOD: Duration = Duration::from_millis(100);
+/// how often the address is checked while waiting for it (the DHCP lease)
+const ADDR_POLL_PERIOD: Duration = Duration::from_secs(1);
+/// the gateway is probed at the startup for this long before the data port is bound, see `probeGateway`,
+/// None - not probed
+const GATEWAY_PROBE_TIMEOUT: Option<Duration> = None;
+/// the static IP claimed by the other host (the ARP probes at the startup or any ARP later) is logged,
+/// `Refuse` - the data socket isn't bound until the re-probe finds the address free, see `arp_guard`
+const DUPLICATE_IP: DuplicateIp = DuplicateIp::Warn;
+const ARP_PROBES: u8 = 3;
+const ARP_PROBE_PERIOD: Duration = Duration::from_millis(200);
+/// the period of the re-probe of the refused address
+const ARP_REPROBE_PERIOD: Duration = Duration::from_secs(10);
+/// how often the board waiting for a command does the housekeeping (address change, ADC power down),
+/// regardless of the received packets, see `idlePoll`
+const IDLE_POLL_PERIOD: Duration = Duration::from_millis(500);
+const RNG_ATTEMPTS: usize = 3;
+/// USART6 rate of the `uart-sink` build
+#[cfg(feature = "uart-sink")]
+const UART_BAUDRATE: u32 = 921_600;
 
+/// moves `$val` into its own static and returns `&'static mut` to it, keeping the big buffers
+/// out of the task future (and of the stack it may be built on before it's moved into the task)
 macro_rules! singleton {
     ($val:expr) => {{
         type T = impl Sized;
@@ -53,13 +348,212 @@ macro_rules! singleton {
     }};
 }
 
-type Device = Ethernet<'static, ETH, GenericSMI>;
+type Device = ArpGuard<Ethernet<'static, ETH, ForcedPhy>>;
 
 #[embassy_executor::task]
 async fn net_task(stack: &'static Stack<Device>) -> ! {
     stack.run().await
 }
 
+/// answers the mDNS queries for the board name with the current address
+#[cfg(feature = "mdns")]
+#[embassy_executor::task]
+async fn mdns_task(stack: &'static Stack<Device>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 512];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 128];
+    let mut buf = [0; 512];
+    while !stack.is_config_up() {
+        Timer::after(ADDR_POLL_PERIOD).await;
+    }
+    if let Err(err) = stack.join_multicast_group(mdns::GROUP) {
+        warn!("[mdns] multicast group join error: {:?}", err);
+    }
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    unwrap!(socket.bind(mdns::PORT));
+    info!("[mdns] answering for {}.local", MDNS_HOST);
+    loop {
+        let Ok((n, remoteAddr)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let (Some(id), Some(addr)) = (mdns::query(&buf[..n], MDNS_HOST), localAddress(stack)) else {
+            continue;
+        };
+        let (id, to) = match remoteAddr.port {
+            mdns::PORT => (0, IpEndpoint::new(mdns::GROUP.into(), mdns::PORT)),
+            _ => (id, remoteAddr),
+        };
+        let len = mdns::answer(&mut buf, id, MDNS_HOST, addr);
+        if let Err(err) = deadman::guard(socket.send_to(&buf[..len], to)).await {
+            warn!("[mdns] Udp socket write error: {:?}", err);
+        }
+    }
+}
+
+/// broadcasts the discovery beacon every `period` while no session is running
+#[embassy_executor::task]
+async fn beacon_task(stack: &'static Stack<Device>, period: Duration) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 16];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; 64];
+    let mut buf = [0; 64];
+    while !stack.is_config_up() {
+        Timer::after(ADDR_POLL_PERIOD).await;
+    }
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    unwrap!(socket.bind(beacon::PORT));
+    info!("[beacon] broadcasting every {} ms", period.as_millis());
+    loop {
+        Timer::after(period).await;
+        let Some(addr) = localAddress(stack) else {
+            continue;
+        };
+        if beacon::isPaused() {
+            continue;
+        }
+        let len = beacon::write(&mut buf, addr, &MAC_ADDR, UDP_PORT);
+        let to = IpEndpoint::new(beacon::BROADCAST.into(), beacon::PORT);
+        if let Err(err) = deadman::guard(socket.send_to(&buf[..len], to)).await {
+            warn!("[beacon] Udp socket write error: {:?}", err);
+        }
+    }
+}
+
+/// sends the telemetry report to the `collector` every `period`, see `telemetry`
+#[embassy_executor::task]
+async fn telemetry_task(stack: &'static Stack<Device>, collector: IpEndpoint, period: Duration) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 16];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; telemetry::SIZE];
+    let mut buf = [0; telemetry::SIZE];
+    while !stack.is_config_up() {
+        Timer::after(ADDR_POLL_PERIOD).await;
+    }
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    unwrap!(socket.bind(telemetry::PORT));
+    info!("[telemetry] reporting to {:?} every {} ms", collector, period.as_millis());
+    let mut seq: u32 = 0;
+    let mut reachable = true;
+    // the socket was reset, the report just sent may be stuck as well
+    let mut reset = false;
+    loop {
+        Timer::after(period).await;
+        let Some(addr) = localAddress(stack) else {
+            continue;
+        };
+        let running = control_port::running();
+        let len = telemetry::write(&mut buf, seq, addr, &MAC_ADDR, running, stack.is_link_up(), txCapacity());
+        seq = seq.wrapping_add(1);
+        // the previous report still waiting for ARP holds the buffer, the collector is gone
+        match with_timeout(period, deadman::guard(socket.send_to(&buf[..len], collector))).await {
+            Ok(Ok(())) => {
+                // the previous report has left
+                if !reachable && !reset {
+                    info!("[telemetry] collector {:?} is back", collector);
+                    reachable = true;
+                }
+                reset = false;
+            }
+            result => {
+                if reachable {
+                    match result {
+                        Ok(Err(err)) => warn!("[telemetry] Udp socket write error: {:?}", err),
+                        _ => warn!("[telemetry] collector {:?} unreachable, the reports are dropped", collector),
+                    }
+                    reachable = false;
+                }
+                // drops the stuck report
+                socket.close();
+                unwrap!(socket.bind(telemetry::PORT));
+                reset = true;
+            }
+        }
+    }
+}
+
+/// answers the control commands on `control_port::PORT` while the session streams, see `control_port`
+#[embassy_executor::task]
+async fn control_task(stack: &'static Stack<Device>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 256];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 256];
+    let mut buf = [0; CMD_BUF_SIZE];
+    while !stack.is_config_up() {
+        Timer::after(ADDR_POLL_PERIOD).await;
+    }
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    unwrap!(socket.bind(control_port::PORT));
+    info!("[control] listening on port {}", control_port::PORT);
+    loop {
+        let Ok((n, remoteAddr)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Some(packet) = control::stripMagic(&buf[..n]) else {
+            continue;
+        };
+        let running = control_port::running();
+        let fromClient = running.map_or(false, |running| running.remote.addr == remoteAddr.addr);
+        match Command::parse(packet) {
+            Some(Command::Stop) if fromClient => {
+                info!("[control] stop received from {:?}", remoteAddr);
+                control_port::requestStop();
+            }
+            Some(Command::LogLevel(level)) => {
+                setLogLevel(&socket, level, remoteAddr).await;
+            }
+            Some(Command::Stats) => {
+                let stream = running.map_or(StreamFormat::default(), |running| running.stream);
+                replyStats(&socket, &stream, remoteAddr).await;
+            }
+            Some(Command::LinkStatus) => {
+                replyLinkStatus(&socket, stack, remoteAddr).await;
+            }
+            Some(Command::Log(from)) => {
+                replyLog(&socket, from, remoteAddr).await;
+            }
+            Some(command @ (Command::Arm | Command::Disarm | Command::ForceTrigger)) => {
+                controlTrigger(&socket, &command, running.is_some() && !fromClient, remoteAddr).await;
+            }
+            Some(command @ (Command::Pause(_) | Command::Resume)) => {
+                controlPause(&socket, &command, running.is_some(), !fromClient, remoteAddr).await;
+            }
+            Some(Command::SetBlockSize(samples)) => {
+                setBlockSize(&socket, samples, running.is_some() && !fromClient, remoteAddr).await;
+            }
+            Some(Command::Health) => {
+                replyHealth(&socket, stack, remoteAddr).await;
+            }
+            command => {
+                log!(Info, "[control] {:?} from {:?} skipped, accepted on the data port", command, remoteAddr);
+            }
+        }
+    }
+}
+
+/// feeds the watchdog while the network path makes progress,
+/// on the stall stops feeding it, so the board is reset
+#[embassy_executor::task]
+async fn supervisor_task(mut wdg: IndependentWatchdog<'static, IWDG>) -> ! {
+    unsafe { wdg.unleash() };
+    loop {
+        match deadman::pendingMillis() {
+            Some(pending) if pending > NET_STALL_TIMEOUT_MS => {
+                error!("network stalled for {} ms, waiting for watchdog reset", pending);
+                ring_log::push(Level::Error, format_args!("network stalled {} ms", pending));
+                loop {
+                    Timer::after(SUPERVISOR_PERIOD).await;
+                }
+            }
+            _ => unsafe { wdg.pet() },
+        }
+        Timer::after(SUPERVISOR_PERIOD).await;
+    }
+}
+
 #[embassy_executor::task]
 async fn run() {
     loop {
@@ -83,29 +577,101 @@ async fn run_high() {
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) -> ! {
+    stack::paint();
     info!("[main] enter");
 
     let mut config = Config::default();
-    config.rcc.sys_ck = Some(mhz(216));
+    config.rcc.sys_ck = Some(mhz(SYS_CLOCK_MHZ));
 
     let dp = embassy_stm32::init(config);
+    backup::init();
+    health::init();
 
-    let mut adcPin = dp.PA3;
+    let mut inputs = Inputs::new(dp.PA3, dp.PC0, dp.PC3, ADC_SEQUENCE);
     let mut adc = Adc::new(dp.ADC1, &mut Delay);
-    adc.set_sample_time(SampleTime::Cycles144);
+    adc.set_sample_time(ADC_SAMPLE_TIME);
+    adc.set_resolution(ADC_RESOLUTION);
+    clocks::validate(SYS_CLOCK_MHZ * 1_000_000);
+    if let Some(ohms) = ADC_SOURCE_OHMS {
+        inputs::checkSampleTime(ADC_SAMPLE_TIME, ohms, ADC_SOURCE_STRICT);
+    }
+    inputs.setExtTrigger(ADC_EXT_TRIGGER);
+    inputs.setWarmup(ADC_WARMUP);
+    let resolutionBits = (ADC_RESOLUTION.to_max_count() + 1).trailing_zeros();
+    inputs.setAlignment(ADC_ALIGNMENT, resolutionBits);
+    let alignShift = ADC_ALIGNMENT.shift(resolutionBits);
+    // zero of the signed samples
+    let midScale = ((ADC_RESOLUTION.to_max_count() + 1) / 2 << alignShift) as u16 * ADC_SEQUENCE as u16;
+    health::measureTemperature(&mut inputs);
+    #[cfg(feature = "ext-mux")]
+    inputs.setMux(
+        MUX_CHANNEL,
+        ExtMux::new(
+            [dp.PE2.degrade(), dp.PE3.degrade(), dp.PE4.degrade(), dp.PE5.degrade()],
+            MUX_CHANNELS,
+            MUX_SETTLE_US * SYS_CLOCK_MHZ,
+        ),
+    );
+    // the full scale bits (the resolution, the alignment, the sequence sum) above the byte, see `FORMAT_BYTE`
+    let byteShift = (u32::BITS - (2 * midScale as u32 - 1).leading_zeros()).saturating_sub(8);
+    timestamp::init(dp.TIM5);
+    checkMtu();
+    #[cfg(feature = "loopback")]
+    let mut source = LoopbackSource::new(LOOPBACK_RATE_HZ);
+    #[cfg(all(feature = "adc-dma", not(feature = "loopback")))]
+    let mut source = DmaSource::new(
+        {
+            let dma = AdcDma::new(dp.DMA2_CH0, singleton!([0u16; 2 * ADC_BUF_SIZE]));
+            #[cfg(feature = "adc-dual")]
+            let dma = dma.withDual(dp.ADC2, ADC_DUAL_DELAY);
+            #[cfg(feature = "dma-stamps")]
+            let dma = {
+                let capture = StampCapture::new(dp.DMA1_CH2, singleton!([0u32; 2 * ADC_BUF_SIZE]));
+                dma.withStamps(dp.TIM4, capture, ADC_CONVERSION_CYCLES)
+            };
+            dma
+        },
+        ADC_CONVERSION_CYCLES,
+    );
+    #[cfg(not(any(feature = "adc-dma", feature = "loopback")))]
+    let mut source = match LOW_JITTER_MASKED_US {
+        Some(us) => BlockingSource::lowJitter(Duration::from_micros(us as u64)),
+        None => BlockingSource::new(),
+    };
+    // the user processing of the datagrams, see `processor`
+    let mut processor = NoProcessor;
+    #[cfg(feature = "data-ready")]
+    let mut dataReady = DataReady::new(dp.PD0.degrade(), DATA_READY_ACTIVE_HIGH);
 
     // let mut vrefint_channel = adc.enable_vrefint();
 
+    #[cfg(feature = "uart-sink")]
+    {
+        let mut uartConfig = embassy_stm32::usart::Config::default();
+        uartConfig.baudrate = UART_BAUDRATE;
+        let uart = embassy_stm32::usart::UartTx::new(dp.USART6, dp.PG14, dp.DMA2_CH6, uartConfig);
+        streamUart(&mut sink::UartSink::new(uart), &mut adc, &mut inputs, midScale).await;
+    }
+
     // Generate random seed.
+    let mut flash = Flash::new(dp.FLASH);
+    let settings = match Settings::load(&mut flash) {
+        Some(settings) => {
+            info!("settings loaded from flash");
+            settings
+        }
+        None => DEFAULT_IP,
+    };
+
     let mut rng = Rng::new(dp.RNG);
-    let mut seed = [0; 8];
-    rng.fill_bytes(&mut seed);
-    let seed = u64::from_le_bytes(seed);
+    let seed = randomSeed(&mut rng);
 
-    let eth_int = interrupt::take!(ETH);
-    let mac_addr = [0x00, 0x00, 0xDE, 0xAD, 0xBE, 0xEF];
+    let eth_int = match takeEthInterrupt() {
+        Ok(eth_int) => eth_int,
+        Err(err) => defmt::panic!("Ethernet init failed: {:?}", err),
+    };
 
-    let device = Ethernet::new(
+    let device = ArpGuard::new(Ethernet::new(
         singleton!(PacketQueue::<16, 16>::new()),
         dp.ETH,
         eth_int,
@@ -118,103 +684,1627 @@ async fn main(spawner: Spawner) -> ! {
         dp.PG13,
         dp.PB13,
         dp.PG11,
-        GenericSMI,
-        mac_addr,
+        ForcedPhy::new(ETH_FORCE_100_FULL),
+        MAC_ADDR,
         0,
-    );
+    ));
+    if !settings.isDhcp() {
+        arp_guard::watch(settings.ip);
+    }
 
-    // let config = embassy_net::Config::Dhcp(Default::default());
-    let localIp = Ipv4Address::new(192, 168, 120, 173);
-    let config = embassy_net::Config::Static(embassy_net::StaticConfig {
-       address: Ipv4Cidr::new(localIp, 24),
-       dns_servers: Vec::new(),
-       gateway: Some(Ipv4Address::new(192, 168, 120, 1)),
-    });
+    let config = match settings.isDhcp() {
+        true => embassy_net::Config::Dhcp(Default::default()),
+        false => embassy_net::Config::Static(embassy_net::StaticConfig {
+            address: Ipv4Cidr::new(Ipv4Address(settings.ip), settings.prefix),
+            dns_servers: Vec::new(),
+            gateway: match settings.gateway {
+                [0, 0, 0, 0] => None,
+                gateway => Some(Ipv4Address(gateway)),
+            },
+        }),
+    };
 
     // Init network stack
     let stack = &*singleton!(
-        Stack::new(device, config, singleton!(StackResources::<2>::new()), seed)
+        // the data socket, DHCP, mDNS, the beacon, the control port and the telemetry
+        Stack::new(device, config, singleton!(StackResources::<6>::new()), seed)
     );
 
     // Launch network task
     unwrap!(spawner.spawn(net_task(&stack)));
     info!("Network task initialized");
+    unwrap!(spawner.spawn(control_task(stack)));
+    unwrap!(spawner.spawn(supervisor_task(IndependentWatchdog::new(dp.IWDG, WATCHDOG_TIMEOUT_US))));
+    info!("Supervisor task initialized");
+    #[cfg(feature = "mdns")]
+    unwrap!(spawner.spawn(mdns_task(stack)));
+    if let Some(period) = BEACON_PERIOD {
+        unwrap!(spawner.spawn(beacon_task(stack, period)));
+    }
+    if let Some((ip, port)) = TELEMETRY_COLLECTOR {
+        let collector = IpEndpoint::new(Ipv4Address(ip).into(), port);
+        unwrap!(spawner.spawn(telemetry_task(stack, collector, TELEMETRY_PERIOD)));
+    }
 
     // Then we can use it!
     let mut rx_meta = [PacketMetadata::EMPTY; 16];
-    let mut rx_buffer = [0; UDP_BUF_SIZE];
+    // the buffers scaling with `UDP_BUF_SIZE` and `ADC_BUF_SIZE` are statics, see `singleton`
+    let rx_buffer = singleton!([0u8; UDP_BUF_SIZE]);
     let mut tx_meta = [PacketMetadata::EMPTY; 16];
-    let mut tx_buffer = [0; UDP_BUF_SIZE];
-    let mut udpBuf = [0; UDP_BUF_SIZE];    
-
-    // let now = NaiveDate::from_ymd_opt(2023, 5, 10)
-    //     .unwrap()
-    //     .and_hms_opt(10, 30, 15)
-    //     .unwrap();
-    // let mut rtc = Rtc::new(dp.RTC, RtcConfig::default());
-    // rtc.set_datetime(DateTime::from(now)).expect("datetime not set");
+    let tx_buffer = singleton!([0u8; UDP_TX_BUF_SIZE]);
+    let udpBuf = singleton!([0u8; UDP_BUF_SIZE]);
+    let mut cmdBuf = [0; CMD_BUF_SIZE];
+    let mut replyBuf = [0; CMD_BUF_SIZE];
+    let samples = singleton!([0u16; ADC_BUF_SIZE]);
+    let stamps = singleton!([0u32; ADC_BUF_SIZE / TIMESTAMP_STRIDE]);
+    let injectedSamples = singleton!([0u16; ADC_BUF_SIZE / MIN_INJECTED_EVERY]);
+    let preTrigger = singleton!(PreTrigger::<ADC_BUF_SIZE>::new());
+    let mut linkHold = LinkHold::new(singleton!([0u8; LINK_HOLD_BUF_SIZE]));
+    #[cfg(feature = "pack-bench")]
+    benchPack(&mut samples[..], &mut udpBuf[..]);
+
+    // set by the host, see `wall_clock`
+    let mut rtc = Rtc::new(dp.RTC, RtcConfig::default());
     // let mut before = Instant::now();
+    let mut suspended: Option<Suspended> = None;
+    let mut autostart = match AUTOSTART_LAST_CLIENT {
+        true => backup::load(),
+        false => None,
+    };
+    // the last session ended (or the boot)
+    let mut idleSince = Instant::now();
+    if let Some(timeout) = GATEWAY_PROBE_TIMEOUT {
+        while !stack.is_config_up() {
+            Timer::after(ADDR_POLL_PERIOD).await;
+        }
+        probeGateway(stack, timeout).await;
+    }
+    if !settings.isDhcp() {
+        match arp_guard::probe(ARP_PROBES, ARP_PROBE_PERIOD).await {
+            Some(_) => error!("the static IP {} conflicts, {:?}", settings.ip, DUPLICATE_IP),
+            None => info!("[arp] no other host uses {}", settings.ip),
+        }
+    }
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer[..], &mut tx_meta, &mut tx_buffer[..]);
+    // the address the socket is bound on
+    let mut bound = None;
+    let mut state = SessionState::Unbound;
     loop {
-        let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
-        
-        info!("UDP bind on {}:{}...", localIp, UDP_PORT);
-        match socket.bind(UDP_PORT) {
-            Ok(_) => {
-                info!("UDP server ready!");
-                loop {
-                    info!("waiting handshake message...");
-                    let (_n, remoteAddr) = socket.recv_from(&mut udpBuf).await.unwrap();
-                    // debug!("received message from {:?}: {:?}", remoteAddr, bufDouble);
-                    if handshakeReceived(&udpBuf) {
-                        info!("received handshake from {:?}", remoteAddr);
-                        loop {
-                            // let now = Instant::now().as_micros();
-                            for i in (0..UDP_BUF_SIZE).step_by(2) {
-                                let measured = adc.read(&mut adcPin);
-                                let bytes = measured.to_be_bytes();
-                                udpBuf[i] = bytes[0];
-                                udpBuf[i + 1] = bytes[1];
+        let phase = state.phase();
+        state = match state {
+            SessionState::Unbound => 'unbound: {
+                // the DHCP lease
+                while !stack.is_config_up() {
+                    Timer::after(ADDR_POLL_PERIOD).await;
+                }
+                if DUPLICATE_IP == DuplicateIp::Refuse && arp_guard::conflict().is_some() {
+                    let period = ARP_REPROBE_PERIOD.as_secs();
+                    error!("UDP bind refused, the address conflicts, probing again in {} s", period);
+                    Timer::after(ARP_REPROBE_PERIOD).await;
+                    arp_guard::probe(ARP_PROBES, ARP_PROBE_PERIOD).await;
+                    break 'unbound SessionState::bindFailed(&mut socket);
+                }
+                bound = localAddress(stack);
+                info!("UDP bind on {:?}:{}...", bound, UDP_PORT);
+                match socket.bind(UDP_PORT) {
+                    Ok(_) => {
+                        info!("UDP server ready!");
+                        let autostart = autostart.take();
+                        if let Some(remote) = autostart {
+                            info!("autostart to the last client {:?}", remote);
+                        }
+                        SessionState::bound(&mut socket, autostart)
+                    }
+                    Err(err) => {
+                        warn!("UDP bind error: {:?}", err);
+                        SessionState::bindFailed(&mut socket)
+                    }
+                }
+            }
+            SessionState::Idle { pending } => 'idle: {
+                let received = match pending {
+                    Some(pending) => Some(pending),
+                    None => recvCommand(&socket, stack, bound, &mut cmdBuf, &mut inputs, idleSince).await,
+                };
+                let Some((command, remoteAddr)) = received else {
+                    break 'idle SessionState::readdressed(&mut socket);
+                };
+                match command {
+                    Command::Handshake(_) if !isValidClient(remoteAddr) => {}
+                    Command::Handshake(handshake) => {
+                        info!("received handshake {:?} from {:?}", handshake, remoteAddr);
+                        let ready = idleSince + SESSION_COOLDOWN;
+                        if let Some(remaining) = ready.checked_duration_since(Instant::now()) {
+                            info!("handshake deferred {} us by the session cooldown", remaining.as_micros());
+                            Timer::at(ready).await;
+                        }
+                        break 'idle SessionState::streaming(&mut socket, handshake, remoteAddr);
+                    }
+                    Command::Stop => {
+                        log!(Info, "stop from {:?} skipped, not streaming", remoteAddr);
+                    }
+                    Command::Confirm(seq) => {
+                        // the late confirmation of the session given up
+                        info!("confirmation {} from {:?} skipped, not streaming", seq, remoteAddr);
+                    }
+                    Command::LogLevel(level) => {
+                        setLogLevel(&socket, level, remoteAddr).await;
+                    }
+                    Command::Stats => {
+                        replyStats(&socket, &StreamFormat::default(), remoteAddr).await;
+                    }
+                    Command::LinkStatus => {
+                        replyLinkStatus(&socket, stack, remoteAddr).await;
+                    }
+                    Command::GetConfig => {
+                        replyConfig(&socket, &inputs, &StreamFormat::default(), remoteAddr).await;
+                    }
+                    Command::Tare(channels) => {
+                        tare(&socket, &mut inputs, &mut adc, channels, remoteAddr).await;
+                    }
+                    Command::SelfCheck => {
+                        selfCheck(&socket, &mut inputs, remoteAddr).await;
+                    }
+                    Command::SetUnits(_) => {
+                        warn!("SET_UNITS from {:?} rejected: not streaming", remoteAddr);
+                        replyStatus(&socket, control::SET_UNITS, control::ERR_INVALID, remoteAddr).await;
+                    }
+                    Command::Log(from) => {
+                        replyLog(&socket, from, remoteAddr).await;
+                    }
+                    command @ (Command::Pause(_) | Command::Resume) => {
+                        controlPause(&socket, &command, false, false, remoteAddr).await;
+                    }
+                    Command::SetBlockSize(samples) => {
+                        // the limit is 0, rejected
+                        setBlockSize(&socket, samples, false, remoteAddr).await;
+                    }
+                    Command::Health => {
+                        // the ADC is free, the temperature is measured
+                        health::measureTemperature(&mut inputs);
+                        replyHealth(&socket, stack, remoteAddr).await;
+                    }
+                    command @ (Command::Arm | Command::Disarm | Command::ForceTrigger) => {
+                        // no triggered session
+                        controlTrigger(&socket, &command, false, remoteAddr).await;
+                    }
+                    #[cfg(feature = "adc-dump")]
+                    Command::DumpAdc => {
+                        replyAdcDump(&socket, remoteAddr).await;
+                    }
+                    #[cfg(feature = "loopback")]
+                    Command::Reference { total, offset, chunk } => {
+                        uploadReference(&socket, total, offset, &chunk, remoteAddr).await;
+                    }
+                    Command::SetTime(secs) => {
+                        setTime(&socket, &mut rtc, secs, remoteAddr).await;
+                    }
+                    command @ Command::SetIp { ip, prefix, gateway, .. } => {
+                        let settings = Settings { ip, prefix, gateway };
+                        let status = if let Some(status) = checkAdmin(&command, remoteAddr) {
+                            status
+                        } else if let Err(err) = settings.store(&mut flash) {
+                            error!("SET_IP settings store error: {:?}", err);
+                            ring_log::push(Level::Error, format_args!("settings store error"));
+                            control::ERR_STORE
+                        } else {
+                            control::OK
+                        };
+                        replyStatus(&socket, control::SET_IP, status, remoteAddr).await;
+                        if status == control::OK {
+                            warn!("IP set to {:?}/{} gw {:?}, restarting", ip, prefix, gateway);
+                            reset().await;
+                        }
+                    }
+                    command @ Command::Reboot { .. } => {
+                        if let Some(status) = checkAdmin(&command, remoteAddr) {
+                            replyStatus(&socket, control::REBOOT, status, remoteAddr).await;
+                        } else {
+                            replyStatus(&socket, control::REBOOT, control::OK, remoteAddr).await;
+                            warn!("REBOOT requested by {:?}, restarting", remoteAddr);
+                            reset().await;
+                        }
+                    }
+                }
+                SessionState::Idle { pending: None }
+            }
+            SessionState::Streaming { handshake, remote: remoteAddr, mut received } => 'streaming: {
+                let Handshake {
+                    mode,
+                    mut format,
+                    blockSamples,
+                    blocksPerDatagram,
+                    startDelayUs,
+                    confirmAck,
+                    metadataFirst,
+                    channelConfigs,
+                    mut injected,
+                    mut envelope,
+                    mut summary,
+                    rateLimit,
+                    maskBits,
+                    formats,
+                } = handshake;
+                // powered down by the idle timeout
+                if !inputs.isPowered() {
+                    let mut before = Instant::now();
+                    inputs.powerUp();
+                    logElapsed("ADC powered up in", &mut before);
+                }
+                inputs.resettle();
+                let mut startAt = startDelayUs.map(|us| received + Duration::from_micros(us as u64));
+                // kept until the session starts, the rejected handshake doesn't drop it
+                let resumed = match suspended.as_ref() {
+                    Some(suspended) if mode == Mode::Resume => suspended.resume(remoteAddr, Instant::now()),
+                    _ => None,
+                };
+                let (mut session, missed) = match resumed {
+                    Some((session, missed)) => {
+                        info!("session resumed at seq {}, {} blocks missed", session.seq(), missed);
+                        (session, missed)
+                    }
+                    None => (Session::new(remoteAddr), 0),
+                };
+                ring_log::push(Level::Info, format_args!("session with {} at seq {}", remoteAddr, session.seq()));
+                // the client predating the record decodes big endian only
+                let encoding = packet::negotiate(formats.unwrap_or(packet::CAP_WORD_BE), SAMPLE_ENCODINGS);
+                format.littleEndian = encoding == packet::CAP_WORD_LE;
+                let requestedFormat = format;
+                // the text line carries no mux positions
+                let muxChannels = if format.ascii { 0 } else { inputs.muxChannels() };
+                // the resolution keeps at least its top bit
+                let maskBits = maskBits.min(resolutionBits as u8 - 1);
+                // the sum of the sequence carries ceil(log2(ADC_SEQUENCE)) bits more, the mask grows by them
+                let sumBits = u8::BITS - (ADC_SEQUENCE - 1).leading_zeros();
+                let sampleMask = !((((1u32 << (maskBits as u32 + sumBits)) - 1) << alignShift) as u16);
+                let requestedInjected = injected;
+                let triggered = matches!(mode, Mode::Triggered { .. });
+                if triggered && format.timestamps {
+                    // the pretrigger samples are taken before the block, they have no stamps
+                    warn!("timestamps aren't available in the triggered mode");
+                    format.timestamps = false;
+                }
+                if triggered && injected.is_some() {
+                    warn!("injected channel isn't available in the triggered mode");
+                    injected = None;
+                }
+                if format.ascii {
+                    // the text line carries the plain samples only
+                    warn!("ASCII output, the other parts of the datagram are off");
+                    format = SampleFormat { signed: format.signed, ascii: true, ..SampleFormat::default() };
+                    (injected, envelope, summary) = (None, None, None);
+                }
+                let contents = Contents {
+                    timestamps: format.timestamps,
+                    baseStamp: format.baseStamp,
+                    sampleIndex: format.sampleIndex,
+                    ascii: format.ascii,
+                    byte: format.byte,
+                    injectedEvery: injected.map(|injected| injected.every as usize),
+                    envelope: envelope.map(|group| group as usize),
+                    summary,
+                    muxPositions: muxChannels > 0,
+                };
+                let mut layout = Layout::new(
+                    blockSamples as usize,
+                    blocksPerDatagram as usize,
+                    MIN_BLOCK_BYTES,
+                    ADC_BUF_SIZE,
+                    &contents,
+                    packet::maxDatagram(MTU),
+                    txCapacity(),
+                );
+                let size = contents.size(layout.blockSamples, layout.blocks);
+                if size > txCapacity() {
+                    // even the single sample block doesn't fit, `send_to` would fail on each datagram
+                    error!("datagram of {} bytes exceeds the TX buffer of {}, session rejected", size, txCapacity());
+                    ring_log::push(Level::Error, format_args!("datagram of {} bytes over TX buffer", size));
+                    replyStatus(&socket, control::ACK, control::ERR_INVALID, remoteAddr).await;
+                    break 'streaming SessionState::rejected(&mut socket);
+                }
+                // the session starts, the suspended one is replaced
+                suspended = None;
+                if size > packet::maxDatagram(MTU) {
+                    warn!("datagram of {} bytes exceeds the MTU {}, it's fragmented", size, MTU);
+                }
+                let mut blockSamples = layout.blockSamples;
+                let mut offsets = [0u32; MAX_BLOCKS];
+                // the mux position of the first sample of each block
+                let mut muxStarts = [MUX_NONE; MAX_BLOCKS];
+                let mut sweep = match mode {
+                    Mode::Sweep { dwell, channels } => Sweep::new(channels, dwell),
+                    _ => Sweep::new(ChannelList::single(inputs::DEFAULT_CHANNEL), 1),
+                };
+                for config in channelConfigs.iter() {
+                    sweep.setDivider(config.channel, config.divider);
+                }
+                if sweep.period() > 1 {
+                    info!("sweep schedule repeats every {} cycles", sweep.period());
+                }
+                let mut capture = match mode {
+                    Mode::Triggered { threshold, pretrigger } => {
+                        let pretrigger = pretrigger as usize;
+                        if pretrigger >= blockSamples {
+                            warn!("pretrigger {} limited to {}", pretrigger, blockSamples - 1);
+                        }
+                        let holdoff = Duration::from_micros(TRIGGER_HOLDOFF_US as u64);
+                        // the threshold is in the counts
+                        let threshold = ((threshold as u32) << alignShift).min(u16::MAX as u32) as u16;
+                        Some((Trigger::new(threshold, holdoff), pretrigger.min(blockSamples - 1)))
+                    }
+                    _ => None,
+                };
+                if let Some(Injected { channel, .. }) = injected {
+                    inputs.setInjected(&mut adc, channel);
+                }
+                let injectedEvery = |injected: Option<Injected>| injected.map_or(0, |injected| injected.every);
+                let params = NegotiatedParams {
+                    format: Negotiated::new(requestedFormat, format),
+                    blockSamples: Negotiated::new(handshake.blockSamples, layout.logicalSamples as u16),
+                    blocksPerDatagram: Negotiated::new(handshake.blocksPerDatagram as u16, layout.blocks as u16),
+                    pretrigger: Negotiated::new(
+                        match mode {
+                            Mode::Triggered { pretrigger, .. } => pretrigger,
+                            _ => 0,
+                        },
+                        capture.as_ref().map_or(0, |(_, pretrigger)| *pretrigger as u16),
+                    ),
+                    injectedEvery: Negotiated::new(injectedEvery(requestedInjected), injectedEvery(injected)),
+                };
+                params.log();
+                let ack = HandshakeAck {
+                    seq: session.seq(),
+                    missed,
+                    params,
+                    pageSamples: blockSamples as u16,
+                    leftAligned: ADC_ALIGNMENT == Alignment::Left,
+                    byteShift: byteShift as u8,
+                    muxChannels,
+                    maskBits,
+                    encoding,
+                    crc: confirmAck,
+                };
+                let ackLen = ack.write(&mut replyBuf);
+                // the triggered mode can't wait for the trigger, the delayed one - for the start,
+                // before acknowledging
+                // the confirmed one - for the confirmation, see `waitConfirm`
+                let mut prefill = PREFILL_FIRST_DATAGRAM && capture.is_none() && startAt.is_none() && !confirmAck;
+                let mut confirming = confirmAck;
+                let mut metadataPending = metadataFirst;
+                if !prefill && !confirming {
+                    if let Err(err) = deadman::guard(socket.send_to(&replyBuf[..ackLen], remoteAddr)).await {
+                        warn!("Udp socket write error: {:?}", err);
+                    }
+                    logElapsed("handshake acknowledged in", &mut received);
+                }
+                let mut streamFlags = 0;
+                if layout.hasSubheaders() {
+                    streamFlags |= FLAG_SUBBLOCKS;
+                }
+                if format.timestamps {
+                    streamFlags |= FLAG_TIMESTAMPS;
+                }
+                if injected.is_some() {
+                    streamFlags |= FLAG_INJECTED;
+                }
+                if envelope.is_some() {
+                    streamFlags |= FLAG_ENVELOPE;
+                }
+                match summary {
+                    Some(Summary::WithSamples) => streamFlags |= FLAG_SUMMARY,
+                    Some(Summary::Only) => streamFlags |= FLAG_SUMMARY | FLAG_NO_SAMPLES,
+                    None => {}
+                }
+                session.stream = StreamFormat {
+                    format,
+                    flags: streamFlags,
+                    channels: sweep.channelCount() as u8,
+                };
+                // the plain stream of the single channel is acquired by the `source`,
+                // the timestamped one if the source stamps it
+                let plain = capture.is_none()
+                    && injected.is_none()
+                    && (!format.timestamps || source.isStamped())
+                    && sweep.channelCount() == 1
+                    && layout.pages() == 1
+                    && !ALIGN_BLOCKS;
+                let mut rateMeter = RateMeter::new(SAMPLE_RATE_EXPECTED, SAMPLE_RATE_TOLERANCE_PPM);
+                let maxDatagram = packet::maxDatagram(MTU).min(txCapacity());
+                session::setBlockLimit(layout.maxResized(ADC_BUF_SIZE, &contents, maxDatagram) as u16);
+                // the pretrigger applied at the start, limited by the changed block
+                let startPretrigger = capture.as_ref().map_or(0, |(_, pretrigger)| *pretrigger);
+                // left from the previous session
+                takeUnits();
+                session::resume();
+                // the session is paused since, see `PAUSE`
+                let mut pausedSince: Option<Instant> = None;
+                // the link is down since, the datagrams are held, see `LINK_FLAP_HOLD`
+                let mut linkDownSince: Option<Instant> = None;
+                preTrigger.clear();
+                if capture.is_some() {
+                    trigger::begin();
+                }
+                backup::store(session.remote);
+                beacon::pause();
+                control_port::begin(session.remote, session.stream);
+                let mut sink = UdpSink::new(&socket, session.remote);
+                let mut pacer = rateLimit.map(|rate| Pacer::new(rate, txCapacity()));
+                if pacer.is_some() {
+                    info!("data rate limited to {} bytes/s", pacer::limit());
+                }
+                // page of the logical block carried by the next datagram
+                let mut page = 0;
+                let end = 'session: loop {
+                    if confirming {
+                        confirming = false;
+                        let ack = &replyBuf[..ackLen];
+                        let end =
+                            waitConfirm(&socket, stack, &mut inputs, &mut adc, &mut rtc, &mut cmdBuf, &session, ack)
+                                .await;
+                        if let Some(end) = end {
+                            break 'session end;
+                        }
+                        logElapsed("handshake confirmed in", &mut received);
+                    }
+                    if let Some(at) = startAt.take() {
+                        if Instant::now() > at {
+                            warn!("start time passed, starting at once");
+                            session.flag(FLAG_LATE_START);
+                        }
+                        // the last slice ends at the start time exactly
+                        while Instant::now() < at {
+                            Timer::at(at.min(Instant::now() + START_POLL_PERIOD)).await;
+                            let end =
+                                pollControl(&socket, stack, &mut inputs, &mut adc, &mut rtc, &mut cmdBuf, &session)
+                                    .await;
+                            if let Some(end) = end {
+                                break 'session end;
+                            }
+                        }
+                    }
+                    match session::paused() {
+                        Some(acquiring) => {
+                            let since = *pausedSince.get_or_insert_with(Instant::now);
+                            if since.elapsed() > PAUSE_TIMEOUT {
+                                let timeout = PAUSE_TIMEOUT.as_secs();
+                                warn!("session paused for over {} s, the client is taken as gone", timeout);
+                                ring_log::push(Level::Warn, format_args!("pause timeout of {}", session.remote));
+                                break 'session Break::Closed(StreamEndReason::PauseTimeout);
+                            }
+                            if !acquiring {
+                                source.stop();
+                                Timer::after(START_POLL_PERIOD).await;
+                                if linkLost(stack, &mut linkDownSince, session.seq()) {
+                                    break 'session Break::Closed(StreamEndReason::LinkLost);
+                                }
+                                if !linkDown(stack) && addressChanged(stack, bound) {
+                                    break 'session Break::Rebind;
+                                }
+                                let end =
+                                    pollControl(&socket, stack, &mut inputs, &mut adc, &mut rtc, &mut cmdBuf, &session)
+                                        .await;
+                                if let Some(end) = end {
+                                    break 'session end;
+                                }
+                                continue;
+                            }
+                        }
+                        None => {
+                            if let Some(since) = pausedSince.take() {
+                                let pause = since.elapsed().as_millis();
+                                info!("session resumed after {} ms pause at seq {}", pause, session.seq());
+                                session.flag(FLAG_RESUMED);
+                            }
+                        }
+                    }
+                    // the block size is changed between the logical blocks only
+                    if let (0, Some(samples)) = (page, session::takeBlockSize()) {
+                        layout = layout.resized(samples as usize);
+                        blockSamples = layout.blockSamples;
+                        if let Some((_, pretrigger)) = capture.as_mut() {
+                            *pretrigger = startPretrigger.min(blockSamples - 1);
+                        }
+                        metadataPending = metadataFirst;
+                        info!("block size changed to {} samples at seq {}", blockSamples, session.seq());
+                    }
+                    // let now = Instant::now().as_micros();
+                    let channel = sweep.channel();
+                    let started = Instant::now();
+                    let mut baseStamp = 0;
+                    let mut datagramSamples = layout.pageSamples(page);
+                    // the session ends after the datagram cut short by the stop, see `FLUSH_ON_STOP`
+                    let mut flushed = None;
+                    let lastPage = page + 1 == layout.pages();
+                    if plain {
+                        let datagram = &mut samples[..datagramSamples];
+                        let muxStart = inputs.muxPosition(channel);
+                        // the source waiting for the trigger sends nothing, the link is watched meanwhile
+                        let filled = source.fill(&mut inputs, &mut adc, channel, datagram, blockSamples, &mut offsets);
+                        let watched = watchLink(stack, &mut linkDownSince, session.seq());
+                        match select(pin!(filled), pin!(watched)).await {
+                            Either::Left((Some(stamp), _)) => baseStamp = stamp,
+                            Either::Left((None, _)) => continue,
+                            Either::Right(_) => break 'session Break::Closed(StreamEndReason::LinkLost),
+                        }
+                        if format.timestamps {
+                            source.stamps(&mut stamps[..]);
+                        }
+                        // the blocks are converted back to back
+                        for (n, start) in muxStarts.iter_mut().enumerate() {
+                            *start = muxAdvance(muxStart, muxChannels, (n * blockSamples) as isize);
+                        }
+                    } else {
+                        let blocks = samples[..datagramSamples].chunks_mut(blockSamples);
+                        'blocks: for (n, (block, offset)) in blocks.zip(offsets.iter_mut()).enumerate() {
+                            let mut first = 0;
+                            if let (0, Some((detector, pretrigger))) = (page, capture.as_mut()) {
+                                // armed, the acquisition runs into the ring until the trigger fires,
+                                // the following pages of the block continue the capture
+                                detector.rearm();
+                                'armed: loop {
+                                    for _ in 0..ADC_BUF_SIZE {
+                                        let measured = inputs.read(&mut adc, channel);
+                                        let crossed = detector.check(measured);
+                                        if preTrigger.collected() >= *pretrigger && trigger::fire(crossed) {
+                                            detector.fired(Instant::now());
+                                            first = preTrigger.snapshot(&mut block[..*pretrigger]);
+                                            block[first] = measured;
+                                            first += 1;
+                                            break 'armed;
+                                        }
+                                        preTrigger.push(measured);
+                                    }
+                                    // nothing is sent while armed, let the network stack run
+                                    Timer::after(Duration::from_ticks(1)).await;
+                                    let end = pollControl(
+                                        &socket,
+                                        stack,
+                                        &mut inputs,
+                                        &mut adc,
+                                        &mut rtc,
+                                        &mut cmdBuf,
+                                        &session,
+                                    )
+                                    .await;
+                                    match end {
+                                        Some(Break::Stop) if FLUSH_ON_STOP && n > 0 => {
+                                            datagramSamples = n * blockSamples;
+                                            flushed = Some(Break::Stop);
+                                            break 'blocks;
+                                        }
+                                        Some(end) => break 'session end,
+                                        None => {}
+                                    }
+                                    if linkLost(stack, &mut linkDownSince, session.seq()) {
+                                        break 'session Break::Closed(StreamEndReason::LinkLost);
+                                    }
+                                }
+                                preTrigger.clear();
+                            }
+                            // the pretrigger samples and the trigger one are already converted
+                            muxStarts[n] = muxAdvance(inputs.muxPosition(channel), muxChannels, -(first as isize));
+                            // the triggered block has started at the threshold crossing
+                            let aligned = ALIGN_BLOCKS && first == 0;
+                            *offset = Instant::now().duration_since(started).as_micros() as u32;
+                            if n == 0 {
+                                baseStamp = timestamp::now();
+                            }
+                            for (i, sample) in block.iter_mut().enumerate().skip(first) {
+                                *sample = match aligned && i == 0 {
+                                    true => {
+                                        let measured = inputs.readAligned(&mut adc, channel);
+                                        // the block starts at the edge
+                                        *offset = Instant::now().duration_since(started).as_micros() as u32;
+                                        if n == 0 {
+                                            baseStamp = timestamp::now();
+                                        }
+                                        measured
+                                    }
+                                    false => inputs.read(&mut adc, channel),
+                                };
+                                let index = n * blockSamples + i;
+                                if format.timestamps && index % TIMESTAMP_STRIDE == 0 {
+                                    stamps[index / TIMESTAMP_STRIDE] = timestamp::now();
+                                }
+                                if let Some(Injected { every, .. }) = injected {
+                                    if index % every as usize == 0 {
+                                        injectedSamples[index / every as usize] = inputs.readInjected();
+                                    }
+                                }
                                 // Timer::after(ADC_READ_DELAY).await;
                                 // info!("measured: {}", measured);
                             }
-                            // let elapsed = Instant::now().as_micros() - now;
-                            // info!("ADC done in: {:?} us ({:?} us)", elapsed, elapsed / ADC_BUF_SIZE as u64);
-                            if socket.is_open() {
-                                match socket.send_to(&udpBuf, remoteAddr).await {
-                                    Ok(_) => {}
-                                    Err(err) => {
-                                        info!("Udp socket write error: {:?}", err);
+                            if capture.is_some() && n + 1 < layout.blocks {
+                                trigger::armNext();
+                            }
+                        }
+                    }
+                    // let elapsed = Instant::now().as_micros() - now;
+                    // info!("ADC done in: {:?} us ({:?} us)", elapsed, elapsed / ADC_BUF_SIZE as u64);
+                    if capture.is_none() {
+                        rateMeter.push(baseStamp, datagramSamples);
+                    }
+                    // the units switch by `SET_UNITS` applies to the whole datagram, no block mixes them
+                    if let Some(millivolts) = takeUnits() {
+                        format.millivolts = millivolts;
+                        session.stream.format = format;
+                        let units = if millivolts { "mV" } else { "counts" };
+                        info!("units switched to {} at seq {}", units, session.seq());
+                    }
+                    let mut header = PacketHeader { channel, ..session.header() };
+                    processor.process(&mut samples[..datagramSamples], &mut header);
+                    if maskBits > 0 {
+                        samples[..datagramSamples].iter_mut().for_each(|sample| *sample &= sampleMask);
+                    }
+                    #[cfg(feature = "data-ready")]
+                    dataReady.pulse();
+                    if format.millivolts {
+                        header.channel |= CHANNEL_MILLIVOLTS;
+                    }
+                    let zero = format.zero(midScale, inputs.offset(channel));
+                    let gain = channelConfigs
+                        .iter()
+                        .find(|config| config.channel == channel)
+                        .map_or(UNITY_GAIN, |config| config.gain);
+                    let gain = match format.millivolts {
+                        // the full scale counts are `ADC_VREF_MV`
+                        true => (gain as u32 * ADC_VREF_MV / (2 * midScale as u32)) as u16,
+                        false => gain,
+                    };
+                    let len = match format.ascii {
+                        // the text line for the bring-up, see `packet::packAscii`
+                        true => {
+                            let datagram = &samples[..datagramSamples];
+                            packet::packAscii(header.seq, header.channel, datagram, format, zero, gain, &mut udpBuf[..])
+                        }
+                        false => {
+                            let mut len = header.write(&mut udpBuf[..]);
+                            if format.baseStamp {
+                                udpBuf[len..len + BASE_STAMP_SIZE].copy_from_slice(&baseStamp.to_be_bytes());
+                                len += BASE_STAMP_SIZE;
+                            }
+                            let plain = !format.signed && !format.byte;
+                            let raw = FAST_RAW_PACK && plain && zero == 0 && gain == UNITY_GAIN;
+                            let bytes = format.byte.then_some(byteShift);
+                            let blocks = samples[..datagramSamples].chunks_mut(blockSamples);
+                            for (n, (block, offset)) in blocks.zip(offsets.iter()).enumerate() {
+                                if layout.hasSubheaders() {
+                                    let subHeader = SubHeader { samples: block.len() as u16, offsetUs: *offset };
+                                    len += subHeader.write(&mut udpBuf[len..]);
+                                }
+                                if format.sampleIndex {
+                                    let index = session.sampleIndex() + (n * blockSamples) as u64;
+                                    udpBuf[len..len + SAMPLE_INDEX_SIZE].copy_from_slice(&index.to_be_bytes());
+                                    len += SAMPLE_INDEX_SIZE;
+                                }
+                                if summary.is_some() {
+                                    len += packet::packSummary(block, zero, gain, &mut udpBuf[len..]);
+                                }
+                                len += match envelope {
+                                    _ if summary == Some(Summary::Only) => 0,
+                                    Some(group) => {
+                                        let group = group as usize;
+                                        let buf = &mut udpBuf[len..];
+                                        packet::packEnvelope(block, group, format, zero, gain, bytes, buf)
+                                    }
+                                    // the block isn't used after packing, it's swapped in place
+                                    None if raw => packet::packRaw(block, format.littleEndian, &mut udpBuf[len..]),
+                                    None if format.byte => {
+                                        packet::packBytes(block, format, zero, gain, byteShift, &mut udpBuf[len..])
                                     }
+                                    None => packet::pack(block, format, zero, gain, &mut udpBuf[len..]),
                                 };
-                            } else {
-                                info!("socket is not open");
-                                break;
-                            }            
-                            // Timer::after(Duration::from_millis(1000)).await;
+                            }
+                            if format.timestamps {
+                                let count = packet::stampCount(datagramSamples);
+                                len += packet::packStamps(&stamps[..count], &mut udpBuf[len..]);
+                            }
+                            if let Some(Injected { channel, every }) = injected {
+                                let count = (datagramSamples + every as usize - 1) / every as usize;
+                                len += packet::packInjected(channel, &injectedSamples[..count], &mut udpBuf[len..]);
+                            }
+                            if contents.muxPositions {
+                                let (starts, buf) = (&muxStarts[..layout.blocks], &mut udpBuf[len..]);
+                                let count = datagramSamples;
+                                len += packet::packMuxPositions(starts, blockSamples, count, muxChannels, buf);
+                            }
+                            len
+                        }
+                    };
+                    if prefill {
+                        prefill = false;
+                        if let Err(err) = deadman::guard(socket.send_to(&replyBuf[..ackLen], remoteAddr)).await {
+                            // the client doesn't know about the session, pre-filled data is dropped
+                            warn!("Udp socket write error: {:?}, session not started", err);
+                            break Break::Closed(StreamEndReason::AckFailed);
+                        }
+                        logElapsed("handshake acknowledged (pre-filled) in", &mut received);
+                    }
+                    if capture.is_some() && lastPage {
+                        trigger::transferring();
+                    }
+                    if metadataPending && socket.is_open() {
+                        metadataPending = false;
+                        let metadata = Metadata {
+                            stream: session.stream,
+                            seq: session.seq(),
+                            missed,
+                            blockSamples: layout.logicalSamples as u16,
+                            blocks: layout.blocks as u16,
+                            pageSamples: blockSamples as u16,
+                            sampleIndex: session.sampleIndex(),
+                        };
+                        let mut buf = [0; packet::METADATA_SIZE];
+                        metadata.write(&mut buf);
+                        if let Err(err) = deadman::guard(socket.send_to(&buf, session.remote)).await {
+                            warn!("Udp socket write error: {:?}, metadata not sent", err);
+                        }
+                    }
+                    if linkLost(stack, &mut linkDownSince, session.seq()) {
+                        break Break::Closed(StreamEndReason::LinkLost);
+                    }
+                    let down = linkDown(stack);
+                    let back = if down { None } else { linkDownSince.take() };
+                    if let Some(since) = back {
+                        // back with the other address the held datagrams have nowhere to go
+                        if addressChanged(stack, bound) {
+                            break Break::Rebind;
+                        }
+                        let held = linkHold.count();
+                        info!("link back after {} ms, {} held datagrams sent", since.elapsed().as_millis(), held);
+                        ring_log::push(Level::Info, format_args!("link back, {} held datagrams", held));
+                        for datagram in linkHold.datagrams() {
+                            sendDatagram(&mut sink, &mut pacer, datagram).await;
+                        }
+                        linkHold.clear();
+                    }
+                    if socket.is_open() {
+                        // paused with the acquisition running, the datagram is dropped, its seq is skipped
+                        if pausedSince.is_none() {
+                            if !down {
+                                sendDatagram(&mut sink, &mut pacer, &udpBuf[..len]).await;
+                            } else if !linkHold.push(&udpBuf[..len]) {
+                                stats::inc(&stats::LINK_DROPS);
+                            }
+                        }
+                        if capture.is_some() && lastPage {
+                            trigger::armNext();
                         }
                     } else {
-                        info!("received wrong handshake from({:?}): {:?}", remoteAddr, udpBuf);
+                        info!("socket is not open");
+                        break Break::Closed(StreamEndReason::SocketClosed);
+                    }
+                    session.next(datagramSamples);
+                    if let Some(end) = flushed {
+                        info!("{} acquired blocks flushed", datagramSamples / blockSamples);
+                        break end;
+                    }
+                    // the channel is switched between the logical blocks only
+                    if lastPage {
+                        page = 0;
+                        sweep.next();
+                    } else {
+                        page += 1;
+                    }
+                    if !down && addressChanged(stack, bound) {
+                        break Break::Rebind;
+                    }
+                    let end = pollControl(&socket, stack, &mut inputs, &mut adc, &mut rtc, &mut cmdBuf, &session).await;
+                    if let Some(end) = end {
+                        break end;
+                    }
+                    // Timer::after(Duration::from_millis(1000)).await;
+                };
+                trigger::end();
+                // the link didn't come back (or with the other address)
+                stats::add(&stats::LINK_DROPS, linkHold.clear());
+                session::setBlockLimit(0);
+                source.stop();
+                beacon::resume();
+                control_port::end();
+                if let Break::Stop = end {
+                    backup::clear();
+                    if FLUSH_ON_STOP {
+                        sendEndMarker(&socket, session.seq(), session.remote).await;
                     }
                 }
+                let reason = end.reason();
+                info!("session with {:?} ended at seq {}: {:?}", session.remote, session.seq(), reason);
+                ring_log::push(Level::Info, format_args!("session ended at seq {}: {:?}", session.seq(), reason));
+                if socket.is_open() {
+                    sendEnded(&socket, reason, session.seq(), session.remote).await;
+                }
+                suspended = Some(session.suspend());
+                idleSince = Instant::now();
+                if let Break::Rebind = end {
+                    notifyAddressChanged(&socket, bound, localAddress(stack), session.remote).await;
+                }
+                SessionState::ended(&mut socket, end)
+            }
+        };
+        if state.phase() != phase {
+            debug!("[session] {:?} -> {:?}", phase, state.phase());
+        }
+    }
+}
+/// sends the `ack` of the `CONFIRM_ACK` handshake until the session client confirms it, handles the other
+/// control packets meanwhile, the handshake of the session client is its retransmission (our ack was lost),
+/// it's answered with the same ack, returns Some if the session has to be ended (not confirmed - `Closed`)
+async fn waitConfirm(
+    socket: &UdpSocket<'_>,
+    stack: &Stack<Device>,
+    inputs: &mut Inputs,
+    adc: &mut Adc<'_, ADC1>,
+    rtc: &mut Rtc<'_, RTC>,
+    buf: &mut [u8],
+    session: &Session,
+    ack: &[u8],
+) -> Option<Break> {
+    let mut period = ACK_RETRY_PERIOD;
+    for attempt in 0..=ACK_RETRIES {
+        if attempt > 0 {
+            info!("ack to {:?} not confirmed, resent ({})", session.remote, attempt);
+        }
+        if let Err(err) = deadman::guard(socket.send_to(ack, session.remote)).await {
+            warn!("Udp socket write error: {:?}", err);
+        }
+        let deadline = Instant::now() + period;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            let Ok(received) = with_timeout(remaining, socket.recv_from(buf)).await else {
+                break;
+            };
+            let Ok((n, remoteAddr)) = received else {
+                continue;
+            };
+            let fromClient = remoteAddr == session.remote;
+            match control::stripMagic(&buf[..n]).and_then(Command::parse) {
+                Some(Command::Confirm(seq)) if fromClient && seq == session.seq() => return None,
+                Some(Command::Handshake(_)) if fromClient => {
+                    info!("duplicate handshake from {:?}, ack resent", remoteAddr);
+                    if let Err(err) = deadman::guard(socket.send_to(ack, session.remote)).await {
+                        warn!("Udp socket write error: {:?}", err);
+                    }
+                }
+                _ => {
+                    let end = handleControl(socket, stack, inputs, adc, rtc, &buf[..n], remoteAddr, session).await;
+                    if end.is_some() {
+                        return end;
+                    }
+                }
+            }
+        }
+        period = period * 2;
+    }
+    warn!("ack to {:?} not confirmed, session not started", session.remote);
+    Some(Break::Closed(StreamEndReason::NotConfirmed))
+}
+/// logs the cycles of packing the full `samples` buffer by the per sample encoding and by `packRaw`,
+/// the results are compared, and of the one datagram copy of those the zero-copy path would save
+/// (the DMA half copy-out, the socket TX buffer, the Ethernet TX buffer), run at init with the `pack-bench` feature
+#[cfg(feature = "pack-bench")]
+fn benchPack(samples: &mut [u16], buf: &mut [u8]) {
+    use cortex_m::peripheral::DWT;
+    let mut cp = unsafe { cortex_m::Peripherals::steal() };
+    cp.DCB.enable_trace();
+    cp.DWT.enable_cycle_counter();
+    for (i, sample) in samples.iter_mut().enumerate() {
+        *sample = (i * 7) as u16 & 0x0FFF;
+    }
+    let mut expected = [0u8; ADC_BUF_SIZE * 2];
+    let start = DWT::cycle_count();
+    let len = packet::pack(samples, Default::default(), 0, UNITY_GAIN, &mut expected);
+    let encoded = DWT::cycle_count().wrapping_sub(start);
+    let start = DWT::cycle_count();
+    packet::packRaw(samples, false, buf);
+    let swapped = DWT::cycle_count().wrapping_sub(start);
+    if buf[..len] != expected[..len] {
+        error!("[bench] packRaw differs from pack");
+    }
+    info!("[bench] {} samples packed: per sample {} cycles, rev16 {} cycles", samples.len(), encoded, swapped);
+    let start = DWT::cycle_count();
+    expected[..len].copy_from_slice(&buf[..len]);
+    let copied = DWT::cycle_count().wrapping_sub(start);
+    info!("[bench] {} bytes copied in {} cycles, the copy path makes 3 such copies", len, copied);
+}
+/// sends the end marker of the stopped session: the `CAN` reply with the sequence of the packet
+/// which would follow the last one sent, gives up after `FLUSH_TIMEOUT` if the TX buffer doesn't drain
+async fn sendEndMarker(socket: &UdpSocket<'_>, seq: u32, remoteAddr: IpEndpoint) {
+    let mut reply = [0; 6];
+    let len = control::reply(&mut reply, control::CAN, &seq.to_be_bytes());
+    match with_timeout(FLUSH_TIMEOUT, deadman::guard(socket.send_to(&reply[..len], remoteAddr))).await {
+        Ok(Ok(())) => info!("end marker sent to {:?} at seq {}", remoteAddr, seq),
+        Ok(Err(err)) => warn!("Udp socket write error: {:?}, end marker not sent", err),
+        Err(_) => warn!("end marker to {:?} timed out", remoteAddr),
+    }
+}
+/// sends the data `datagram` paced by the `pacer` under the deadman, the error is logged, the datagram is dropped
+async fn sendDatagram(sink: &mut impl Sink, pacer: &mut Option<Pacer>, datagram: &[u8]) {
+    if let Some(pacer) = pacer.as_mut() {
+        pacer.take(datagram.len()).await;
+    }
+    let sent = deadman::guard(sink.send(datagram)).await;
+    match sent {
+        Ok(_) => health::sent(datagram.len()),
+        Err(err) => {
+            log!(Info, "Udp socket write error: {:?}", err);
+        }
+    };
+}
+/// sends the `ENDED` reply with the `reason` and the sequence following the last packet sent,
+/// gives up after `FLUSH_TIMEOUT` if the TX buffer doesn't drain
+async fn sendEnded(socket: &UdpSocket<'_>, reason: StreamEndReason, seq: u32, remoteAddr: IpEndpoint) {
+    let mut payload = [0; 5];
+    payload[0] = reason as u8;
+    payload[1..].copy_from_slice(&seq.to_be_bytes());
+    let mut reply = [0; 7];
+    let len = control::reply(&mut reply, control::ENDED, &payload);
+    match with_timeout(FLUSH_TIMEOUT, deadman::guard(socket.send_to(&reply[..len], remoteAddr))).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => warn!("Udp socket write error: {:?}, ENDED not sent", err),
+        Err(_) => warn!("ENDED to {:?} timed out", remoteAddr),
+    }
+}
+/// the housekeeping of the board waiting for a command: powers the ADC down after the idle timeout,
+/// returns false if the local address differs from the `bound` one and the socket has to be bound again
+fn idlePoll(stack: &Stack<Device>, bound: Option<Ipv4Address>, inputs: &mut Inputs, idleSince: Instant) -> bool {
+    if addressChanged(stack, bound) {
+        return false;
+    }
+    if let Some(timeout) = ADC_IDLE_TIMEOUT {
+        if idleSince.elapsed() > timeout {
+            inputs.powerDown();
+        }
+    }
+    true
+}
+/// the mux `position` moved by `samples` (back if negative) over its `channels`, `MUX_NONE` is kept
+fn muxAdvance(position: u8, channels: u8, samples: isize) -> u8 {
+    match position {
+        MUX_NONE => MUX_NONE,
+        _ => (position as isize + samples).rem_euclid(channels.max(1) as isize) as u8,
+    }
+}
+/// the largest datagram sent, both the datagram buffer and the socket TX buffer hold it
+const fn txCapacity() -> usize {
+    match UDP_TX_BUF_SIZE < UDP_BUF_SIZE {
+        true => UDP_TX_BUF_SIZE,
+        false => UDP_BUF_SIZE,
+    }
+}
+/// warns if the datagram of the full sample buffer doesn't fit the UDP payload of the `MTU`
+/// or the socket TX buffer
+fn checkMtu() {
+    if UDP_TX_BUF_SIZE < packet::maxDatagram(MTU) {
+        warn!(
+            "TX buffer of {} bytes is below the MTU {} payload, the larger blocks are paged",
+            UDP_TX_BUF_SIZE, MTU
+        );
+    }
+    let size = Contents::default().size(ADC_BUF_SIZE, 1);
+    let maxDatagram = packet::maxDatagram(MTU);
+    if size > maxDatagram {
+        let maxSamples = (maxDatagram - HEADER_SIZE) / 2;
+        warn!(
+            "MTU {}: blocks above {} samples are IP fragmented ({} bytes at {})",
+            MTU, maxSamples, size, ADC_BUF_SIZE
+        );
+    } else {
+        info!("MTU {}, UDP payload up to {} bytes", MTU, maxDatagram);
+    }
+}
+/// waits for the control command doing the idle housekeeping every `IDLE_POLL_PERIOD`,
+/// returns None if the local address differs from the `bound` one
+async fn recvCommand(
+    socket: &UdpSocket<'_>,
+    stack: &Stack<Device>,
+    bound: Option<Ipv4Address>,
+    buf: &mut [u8],
+    inputs: &mut Inputs,
+    idleSince: Instant,
+) -> Option<(Command, IpEndpoint)> {
+    let mut nextPoll = Instant::now() + IDLE_POLL_PERIOD;
+    loop {
+        log!(Info, "waiting handshake message...");
+        let (n, remoteAddr) = loop {
+            // on time even if the packets keep coming
+            if Instant::now() >= nextPoll {
+                nextPoll = Instant::now() + IDLE_POLL_PERIOD;
+                if !idlePoll(stack, bound, inputs, idleSince) {
+                    return None;
+                }
+            }
+            let remaining = nextPoll.checked_duration_since(Instant::now()).unwrap_or(Duration::from_ticks(0));
+            if let Ok(received) = with_timeout(remaining, socket.recv_from(buf)).await {
+                break received.unwrap();
+            }
+        };
+        let Some(packet) = control::stripMagic(&buf[..n]) else {
+            log!(Debug, "dropped packet without magic from {:?}", remoteAddr);
+            continue;
+        };
+        match Command::parse(packet) {
+            Some(Command::Handshake(_)) if !churn::admit(remoteAddr.addr, Instant::now()) => {}
+            Some(command) => return Some((command, remoteAddr)),
+            None => {
+                log!(Info, "received wrong handshake from({:?}): {:?}", remoteAddr, &buf[..n]);
+            }
+        }
+    }
+}
+/// handles the control packets received while streaming if any (up to `CONTROL_BURST`), doesn't wait for them,
+/// returns Some if the session has to be ended
+async fn pollControl(
+    socket: &UdpSocket<'_>,
+    stack: &Stack<Device>,
+    inputs: &mut Inputs,
+    adc: &mut Adc<'_, ADC1>,
+    rtc: &mut Rtc<'_, RTC>,
+    buf: &mut [u8],
+    session: &Session,
+) -> Option<Break> {
+    if control_port::takeStop() {
+        info!("stop received on the control port");
+        return Some(Break::Stop);
+    }
+    for _ in 0..CONTROL_BURST {
+        let (n, remoteAddr) = tryRecv(socket, buf).await?;
+        let end = handleControl(socket, stack, inputs, adc, rtc, &buf[..n], remoteAddr, session).await;
+        if end.is_some() {
+            return end;
+        }
+    }
+    None
+}
+/// handles the control packet `received` from `remoteAddr` while streaming,
+/// returns Some if the session has to be ended
+async fn handleControl(
+    socket: &UdpSocket<'_>,
+    stack: &Stack<Device>,
+    inputs: &mut Inputs,
+    adc: &mut Adc<'_, ADC1>,
+    rtc: &mut Rtc<'_, RTC>,
+    received: &[u8],
+    remoteAddr: IpEndpoint,
+    session: &Session,
+) -> Option<Break> {
+    let Some(packet) = control::stripMagic(received) else {
+        log!(Debug, "dropped packet without magic from {:?}", remoteAddr);
+        return None;
+    };
+    match Command::parse(packet) {
+        Some(Command::Stop) if remoteAddr.addr == session.remote.addr => {
+            info!("stop received from {:?}", remoteAddr);
+            Some(Break::Stop)
+        }
+        Some(Command::LogLevel(level)) => {
+            setLogLevel(socket, level, remoteAddr).await;
+            None
+        }
+        Some(Command::Stats) => {
+            replyStats(socket, &session.stream, remoteAddr).await;
+            None
+        }
+        Some(Command::LinkStatus) => {
+            replyLinkStatus(socket, stack, remoteAddr).await;
+            None
+        }
+        Some(Command::Log(from)) => {
+            replyLog(socket, from, remoteAddr).await;
+            None
+        }
+        Some(Command::GetConfig) => {
+            replyConfig(socket, inputs, &session.stream, remoteAddr).await;
+            None
+        }
+        Some(Command::Tare(channels)) => {
+            // the stream pauses for the tare, the offsets apply from the next datagram
+            tare(socket, inputs, adc, channels, remoteAddr).await;
+            None
+        }
+        Some(Command::SelfCheck) => {
+            // the stream pauses for the burst, the next datagram reconfigures the channel
+            selfCheck(socket, inputs, remoteAddr).await;
+            None
+        }
+        #[cfg(feature = "adc-dump")]
+        Some(Command::DumpAdc) => {
+            replyAdcDump(socket, remoteAddr).await;
+            None
+        }
+        #[cfg(feature = "loopback")]
+        Some(Command::Reference { total, offset, chunk }) => {
+            uploadReference(socket, total, offset, &chunk, remoteAddr).await;
+            None
+        }
+        Some(Command::SetTime(secs)) => {
+            setTime(socket, rtc, secs, remoteAddr).await;
+            None
+        }
+        Some(Command::SetUnits(millivolts)) => {
+            let status = match remoteAddr.addr == session.remote.addr {
+                true => {
+                    requestUnits(millivolts);
+                    control::OK
+                }
+                false => control::ERR_BUSY,
+            };
+            replyStatus(socket, control::SET_UNITS, status, remoteAddr).await;
+            None
+        }
+        Some(command @ (Command::Arm | Command::Disarm | Command::ForceTrigger)) => {
+            let busy = remoteAddr.addr != session.remote.addr;
+            controlTrigger(socket, &command, busy, remoteAddr).await;
+            None
+        }
+        Some(command @ (Command::Pause(_) | Command::Resume)) => {
+            controlPause(socket, &command, true, remoteAddr.addr != session.remote.addr, remoteAddr).await;
+            None
+        }
+        Some(Command::SetBlockSize(samples)) => {
+            setBlockSize(socket, samples, remoteAddr.addr != session.remote.addr, remoteAddr).await;
+            None
+        }
+        Some(Command::Health) => {
+            // the last measured temperature, the stream isn't paused
+            replyHealth(socket, stack, remoteAddr).await;
+            None
+        }
+        Some(command @ (Command::SetIp { .. } | Command::Reboot { .. })) => {
+            let (opcode, name) = match command {
+                Command::SetIp { .. } => (control::SET_IP, "SET_IP"),
+                _ => (control::REBOOT, "REBOOT"),
+            };
+            let status = match remoteAddr.addr == session.remote.addr {
+                true => checkAdmin(&command, remoteAddr),
+                false => {
+                    warn!("{} from {:?} rejected: streaming to {:?}", name, remoteAddr, session.remote);
+                    Some(control::ERR_BUSY)
+                }
+            };
+            if let Some(status) = status {
+                // the stream goes on
+                replyStatus(socket, opcode, status, remoteAddr).await;
+                return None;
             }
-            Err(err) => {
-                warn!("UDP bind error: {:?}", err);
+            // verified, applied (and replied) once the session has ended
+            Some(Break::Pending(command, remoteAddr))
+        }
+        Some(Command::Handshake(_)) if !churn::admit(remoteAddr.addr, Instant::now()) => None,
+        Some(Command::Handshake(handshake)) => {
+            match remoteAddr.addr == session.remote.addr {
+                true => info!("handshake from the session client {:?}, restarting the session", remoteAddr),
+                false => info!("handshake from {:?}, the session of {:?} is taken over", remoteAddr, session.remote),
             }
+            Some(Break::Pending(Command::Handshake(handshake), remoteAddr))
+        }
+        _ => {
+            log!(Debug, "skipped message from {:?}: {:?}", remoteAddr, received);
+            None
+        }
+    }
+}
+/// applies the ARM / DISARM / FORCE_TRIGGER `command` of `remoteAddr` unless `busy`
+/// (the session of the other client), replies with the status
+async fn controlTrigger(socket: &UdpSocket<'_>, command: &Command, busy: bool, remoteAddr: IpEndpoint) {
+    let (opcode, apply): (u8, fn() -> bool) = match command {
+        Command::Arm => (control::ARM, trigger::arm),
+        Command::Disarm => (control::DISARM, trigger::disarm),
+        _ => (control::FORCE_TRIGGER, trigger::force),
+    };
+    let status = match busy {
+        true => control::ERR_BUSY,
+        false if apply() => control::OK,
+        false => control::ERR_INVALID,
+    };
+    info!("{:?} from {:?}: status {}, capture {:?}", command, remoteAddr, status, trigger::state());
+    replyStatus(socket, opcode, status, remoteAddr).await;
+}
+/// applies the PAUSE / RESUME `command` of `remoteAddr` to the session if `running`
+/// unless `busy` (the session of the other client), replies with the status
+async fn controlPause(socket: &UdpSocket<'_>, command: &Command, running: bool, busy: bool, remoteAddr: IpEndpoint) {
+    let (opcode, status) = match (command, running, busy) {
+        (Command::Pause(_), false, _) => (control::PAUSE, control::ERR_INVALID),
+        (_, false, _) => (control::RESUME, control::ERR_INVALID),
+        (Command::Pause(_), true, true) => (control::PAUSE, control::ERR_BUSY),
+        (_, true, true) => (control::RESUME, control::ERR_BUSY),
+        (Command::Pause(acquiring), true, false) => {
+            session::pause(*acquiring);
+            (control::PAUSE, control::OK)
+        }
+        (_, true, false) => {
+            session::resume();
+            (control::RESUME, control::OK)
+        }
+    };
+    info!("{:?} from {:?}: status {}", command, remoteAddr, status);
+    replyStatus(socket, opcode, status, remoteAddr).await;
+}
+/// requests the blocks of `samples` for the running session unless `busy` (the session of the other client),
+/// replies with the status, the accepted samples and the largest block
+async fn setBlockSize(socket: &UdpSocket<'_>, samples: u16, busy: bool, remoteAddr: IpEndpoint) {
+    let max = session::blockLimit();
+    let status = match busy {
+        true => control::ERR_BUSY,
+        false if session::requestBlockSize(samples) => control::OK,
+        false => {
+            warn!("SET_BLOCK_SIZE {} from {:?} rejected, valid 1..={}", samples, remoteAddr, max);
+            control::ERR_INVALID
+        }
+    };
+    let accepted = if status == control::OK { samples } else { 0 };
+    let mut payload = [0; 5];
+    payload[0] = status;
+    payload[1..3].copy_from_slice(&accepted.to_be_bytes());
+    payload[3..5].copy_from_slice(&max.to_be_bytes());
+    let mut reply = [0; 7];
+    let len = control::reply(&mut reply, control::SET_BLOCK_SIZE, &payload);
+    if let Err(err) = deadman::guard(socket.send_to(&reply[..len], remoteAddr)).await {
+        warn!("Udp socket write error: {:?}", err);
+    }
+}
+/// sets the RTC to the Unix time `secs`, replies with the status, the time read back and the sample stamp
+async fn setTime(socket: &UdpSocket<'_>, rtc: &mut Rtc<'_, RTC>, secs: u32, remoteAddr: IpEndpoint) {
+    let (status, time) = match wall_clock::set(rtc, secs) {
+        Ok(time) => {
+            info!("RTC set to {} by {:?}", time, remoteAddr);
+            (control::OK, time)
+        }
+        Err(wall_clock::Error::Invalid) => {
+            warn!("SET_TIME from {:?} rejected: {} is out of the RTC range", remoteAddr, secs);
+            (control::ERR_INVALID, wall_clock::now(rtc).unwrap_or(0))
+        }
+        Err(wall_clock::Error::Rtc) => {
+            error!("SET_TIME RTC error");
+            (control::ERR_STORE, 0)
+        }
+    };
+    let mut payload = [0; 9];
+    payload[0] = status;
+    payload[1..5].copy_from_slice(&time.to_be_bytes());
+    payload[5..9].copy_from_slice(&timestamp::now().to_be_bytes());
+    let mut reply = [0; CMD_BUF_SIZE];
+    let len = control::reply(&mut reply, control::SET_TIME, &payload);
+    if let Err(err) = deadman::guard(socket.send_to(&reply[..len], remoteAddr)).await {
+        warn!("Udp socket write error: {:?}", err);
+    }
+}
+/// sends the reply with the command `status` to `remoteAddr`
+/// checks the token of the SET_IP / REBOOT `command` and the address of SET_IP,
+/// returns the status it's rejected with, None - accepted
+fn checkAdmin(command: &Command, remoteAddr: IpEndpoint) -> Option<u8> {
+    let (name, token) = match command {
+        Command::SetIp { token, .. } => ("SET_IP", *token),
+        Command::Reboot { token } => ("REBOOT", *token),
+        _ => return None,
+    };
+    if token != ADMIN_TOKEN {
+        warn!("{} from {:?} rejected: wrong token", name, remoteAddr);
+        return Some(control::ERR_TOKEN);
+    }
+    if let Command::SetIp { ip, prefix, gateway, .. } = *command {
+        if !(Settings { ip, prefix, gateway }).isValid() {
+            warn!("SET_IP from {:?} rejected: invalid address {:?}/{}", remoteAddr, ip, prefix);
+            return Some(control::ERR_INVALID);
+        }
+    }
+    None
+}
+async fn replyStatus(socket: &UdpSocket<'_>, opcode: u8, status: u8, remoteAddr: IpEndpoint) {
+    let mut reply = [0; 3];
+    let len = control::reply(&mut reply, opcode, &[status]);
+    if let Err(err) = deadman::guard(socket.send_to(&reply[..len], remoteAddr)).await {
+        warn!("Udp socket write error: {:?}", err);
+    }
+}
+/// resets the board after the reply has gone out
+async fn reset() -> ! {
+    Timer::after(Duration::from_millis(100)).await;
+    cortex_m::peripheral::SCB::sys_reset();
+}
+/// applies the runtime log level received from `remoteAddr`, replies with the current one
+async fn setLogLevel(socket: &UdpSocket<'_>, level: Level, remoteAddr: IpEndpoint) {
+    warn!("log level set to {:?} by {:?}", level, remoteAddr);
+    log_level::set(level);
+    let reply = [control::SYN, control::LOG_LEVEL, log_level::get() as u8];
+    if let Err(err) = deadman::guard(socket.send_to(&reply, remoteAddr)).await {
+        warn!("Udp socket write error: {:?}", err);
+    }
+}
+/// sends the ring log lines from `from` to `remoteAddr` one per reply, ends with the reply of the next line number
+async fn replyLog(socket: &UdpSocket<'_>, from: u32, remoteAddr: IpEndpoint) {
+    let mut line = [0; ring_log::RECORD_SIZE];
+    let mut reply = [0; ring_log::RECORD_SIZE + 2];
+    let mut seq = from;
+    // the lines pushed meanwhile aren't chased beyond the ring
+    for _ in 0..ring_log::CAPACITY {
+        let Some((len, next)) = ring_log::read(seq, &mut line) else {
+            break;
         };
+        let len = control::reply(&mut reply, control::LOG, &line[..len]);
+        if let Err(err) = deadman::guard(socket.send_to(&reply[..len], remoteAddr)).await {
+            warn!("Udp socket write error: {:?}", err);
+        }
+        seq = next;
+    }
+    let len = control::reply(&mut reply, control::LOG, &ring_log::next().to_be_bytes());
+    if let Err(err) = deadman::guard(socket.send_to(&reply[..len], remoteAddr)).await {
+        warn!("Udp socket write error: {:?}", err);
     }
 }
-//
-// fn logElapsed(message: &str, before: &mut Instant) {
-//     let now = Instant::now();
-//     let elapsed = now.as_micros() - before.as_micros();
-//     *before = now;
-//     info!("{}: {:?}", message, elapsed);
-// }
-/// return true if handshake received
-fn handshakeReceived(buf: & [u8; UDP_BUF_SIZE]) -> bool {
-    buf[0] == SYN && buf[1] == EOT
+/// sends the STATS reply prefixed with the `stream` format to `remoteAddr`
+async fn replyStats(socket: &UdpSocket<'_>, stream: &StreamFormat, remoteAddr: IpEndpoint) {
+    let mut payload = [0; CMD_BUF_SIZE - 2];
+    let len = stream.write(&mut payload);
+    let len = len + stats::write(&mut payload[len..]);
+    let mut reply = [0; CMD_BUF_SIZE];
+    let len = control::reply(&mut reply, control::STATS, &payload[..len]);
+    if let Err(err) = deadman::guard(socket.send_to(&reply[..len], remoteAddr)).await {
+        warn!("Udp socket write error: {:?}", err);
+    }
+}
+/// sends the HEALTH reply to `remoteAddr`
+async fn replyHealth(socket: &UdpSocket<'_>, stack: &Stack<Device>, remoteAddr: IpEndpoint) {
+    let mut payload = [0; health::SIZE];
+    let len = health::write(&mut payload, stack.is_link_up(), txCapacity());
+    let mut reply = [0; CMD_BUF_SIZE];
+    let len = control::reply(&mut reply, control::HEALTH, &payload[..len]);
+    if let Err(err) = deadman::guard(socket.send_to(&reply[..len], remoteAddr)).await {
+        warn!("Udp socket write error: {:?}", err);
+    }
+}
+/// sends the GET_CONFIG reply prefixed with the `stream` format to `remoteAddr`
+async fn replyConfig(socket: &UdpSocket<'_>, inputs: &Inputs, stream: &StreamFormat, remoteAddr: IpEndpoint) {
+    let mut payload = [0; CMD_BUF_SIZE - 2];
+    let len = stream.write(&mut payload);
+    payload[len] = (ADC_RESOLUTION.to_max_count() + 1).trailing_zeros() as u8;
+    payload[len + 1] = ADC_SEQUENCE;
+    let len = len + 2 + inputs.writeOffsets(&inputs::CHANNELS, &mut payload[len + 2..]);
+    let mut reply = [0; CMD_BUF_SIZE];
+    let len = control::reply(&mut reply, control::GET_CONFIG, &payload[..len]);
+    if let Err(err) = deadman::guard(socket.send_to(&reply[..len], remoteAddr)).await {
+        warn!("Udp socket write error: {:?}", err);
+    }
+}
+/// sends the DUMP_ADC reply to `remoteAddr`
+#[cfg(feature = "adc-dump")]
+async fn replyAdcDump(socket: &UdpSocket<'_>, remoteAddr: IpEndpoint) {
+    let mut payload = [0; CMD_BUF_SIZE - 2];
+    let len = inputs::dumpRegisters(&mut payload);
+    let mut reply = [0; CMD_BUF_SIZE];
+    let len = control::reply(&mut reply, control::DUMP_ADC, &payload[..len]);
+    if let Err(err) = deadman::guard(socket.send_to(&reply[..len], remoteAddr)).await {
+        warn!("Udp socket write error: {:?}", err);
+    }
+}
+/// stores the loopback reference `chunk` uploaded by `remoteAddr`, replies with the status
+#[cfg(feature = "loopback")]
+async fn uploadReference(
+    socket: &UdpSocket<'_>,
+    total: u16,
+    offset: u16,
+    chunk: &control::ReferenceChunk,
+    remoteAddr: IpEndpoint,
+) {
+    let status = match loopback::upload(total, offset, chunk.samples()) {
+        Ok(()) => control::OK,
+        Err(err) => {
+            warn!("REFERENCE from {:?} of {} at {} rejected: {:?}", remoteAddr, total, offset, err);
+            control::ERR_INVALID
+        }
+    };
+    replyStatus(socket, control::REFERENCE, status, remoteAddr).await;
 }
+/// measures the tare offsets of the `channels` requested by `remoteAddr`, replies with them
+async fn tare(
+    socket: &UdpSocket<'_>,
+    inputs: &mut Inputs,
+    adc: &mut Adc<'_, ADC1>,
+    channels: ChannelList,
+    remoteAddr: IpEndpoint,
+) {
+    for channel in channels.as_slice() {
+        let offset = inputs.tare(adc, *channel);
+        info!("channel {} tared at {} by {:?}", channel, offset, remoteAddr);
+    }
+    let mut payload = [0; CMD_BUF_SIZE - 2];
+    let len = inputs.writeOffsets(channels.as_slice(), &mut payload);
+    let mut reply = [0; CMD_BUF_SIZE];
+    let len = control::reply(&mut reply, control::TARE, &payload[..len]);
+    if let Err(err) = deadman::guard(socket.send_to(&reply[..len], remoteAddr)).await {
+        warn!("Udp socket write error: {:?}", err);
+    }
+}
+/// runs the self-check requested by `remoteAddr`, replies with its report
+async fn selfCheck(socket: &UdpSocket<'_>, inputs: &mut Inputs, remoteAddr: IpEndpoint) {
+    info!("self-check requested by {:?}", remoteAddr);
+    let report = selfcheck::runSelfcheck(inputs);
+    let mut payload = [0; selfcheck::REPORT_SIZE];
+    let len = report.write(&mut payload);
+    let mut reply = [0; CMD_BUF_SIZE];
+    let len = control::reply(&mut reply, control::SELFCHECK, &payload[..len]);
+    if let Err(err) = deadman::guard(socket.send_to(&reply[..len], remoteAddr)).await {
+        warn!("Udp socket write error: {:?}", err);
+    }
+}
+/// logs if the configured gateway is reachable, waits for it up to `timeout`, skipped without the gateway.
+/// The gateway is resolved by ARP through `arp_guard` (the request resent every `ARP_PROBE_PERIOD`),
+/// the reply proves it's on the link whatever it filters, the timeout means the wrong or unplugged gateway
+async fn probeGateway(stack: &Stack<Device>, timeout: Duration) {
+    let Some(config) = stack.config() else {
+        return;
+    };
+    let Some(gateway) = config.gateway else {
+        info!("no gateway, probe skipped");
+        return;
+    };
+    let started = Instant::now();
+    let sender = config.address.address().0;
+    match arp_guard::resolve(gateway.0, sender, timeout, ARP_PROBE_PERIOD).await {
+        Some(mac) => {
+            let ms = started.elapsed().as_millis();
+            info!("gateway {:?} reachable at {:02x}, answered in {} ms", gateway, mac, ms);
+        }
+        None => warn!("gateway {:?} unreachable: no ARP reply within {} ms", gateway, timeout.as_millis()),
+    }
+}
+/// the current IPv4 address of the board, None while DHCP has no lease
+fn localAddress(stack: &Stack<Device>) -> Option<Ipv4Address> {
+    stack.config().map(|config| config.address.address())
+}
+/// returns true if the link or the address is down
+fn linkDown(stack: &Stack<Device>) -> bool {
+    !(stack.is_link_up() && stack.is_config_up())
+}
+/// returns true if the link is down past `LINK_FLAP_HOLD` (at once without the hold), the session ends then,
+/// `since` - when it went down, set at the first one seen at the `seq`, cleared by the caller when it's back
+fn linkLost(stack: &Stack<Device>, since: &mut Option<Instant>, seq: u32) -> bool {
+    if !linkDown(stack) {
+        return false;
+    }
+    let since = *since.get_or_insert_with(|| {
+        warn!("link lost at seq {}, the datagrams are held", seq);
+        ring_log::push(Level::Warn, format_args!("link lost at seq {}", seq));
+        Instant::now()
+    });
+    match LINK_FLAP_HOLD {
+        Some(hold) if since.elapsed() <= hold => false,
+        _ => {
+            warn!("link down for {} ms, the session is ended", since.elapsed().as_millis());
+            true
+        }
+    }
+}
+/// completes when `linkLost`, checked every `LINK_POLL_PERIOD`, so the session waiting for the samples ends too
+async fn watchLink(stack: &Stack<Device>, since: &mut Option<Instant>, seq: u32) {
+    loop {
+        Timer::after(LINK_POLL_PERIOD).await;
+        if linkLost(stack, since, seq) {
+            return;
+        }
+    }
+}
+/// returns true if the current address differs from the `bound` one
+fn addressChanged(stack: &Stack<Device>, bound: Option<Ipv4Address>) -> bool {
+    let current = localAddress(stack);
+    if current != bound {
+        warn!("local address changed: {:?} -> {:?}", bound, current);
+        return true;
+    }
+    false
+}
+/// tells the session client at `remoteAddr` to handshake again with the `new` address
+async fn notifyAddressChanged(
+    socket: &UdpSocket<'_>,
+    old: Option<Ipv4Address>,
+    new: Option<Ipv4Address>,
+    remoteAddr: IpEndpoint,
+) {
+    let mut payload = [0; 8];
+    payload[0..4].copy_from_slice(old.map_or(&[0; 4], |addr| addr.as_bytes()));
+    payload[4..8].copy_from_slice(new.map_or(&[0; 4], |addr| addr.as_bytes()));
+    let mut reply = [0; CMD_BUF_SIZE];
+    let len = control::reply(&mut reply, control::ADDR_CHANGED, &payload);
+    if let Err(err) = deadman::guard(socket.send_to(&reply[..len], remoteAddr)).await {
+        warn!("Udp socket write error: {:?}", err);
+    }
+}
+/// sends the LINK_STATUS reply to `remoteAddr`
+async fn replyLinkStatus(socket: &UdpSocket<'_>, stack: &Stack<Device>, remoteAddr: IpEndpoint) {
+    let mut payload = [0; 8];
+    payload[0] = stack.is_link_up() as u8;
+    payload[1] = phy::linkMode() as u8;
+    payload[2..8].copy_from_slice(&MAC_ADDR);
+    let mut reply = [0; CMD_BUF_SIZE];
+    let len = control::reply(&mut reply, control::LINK_STATUS, &payload);
+    if let Err(err) = deadman::guard(socket.send_to(&reply[..len], remoteAddr)).await {
+        warn!("Udp socket write error: {:?}", err);
+    }
+}
+/// streams the default channel to the UART as the plain session without the network control,
+/// the packets are the same as sent over UDP
+#[cfg(feature = "uart-sink")]
+async fn streamUart(
+    sink: &mut sink::UartSink<'_>,
+    adc: &mut Adc<'_, ADC1>,
+    inputs: &mut Inputs,
+    midScale: u16,
+) -> ! {
+    info!("streaming over UART at {} baud", UART_BAUDRATE);
+    let mut samples = [0u16; ADC_BUF_SIZE];
+    let mut buf = [0u8; HEADER_SIZE + ADC_BUF_SIZE * 2];
+    let mut seq: u32 = 0;
+    loop {
+        for sample in samples.iter_mut() {
+            *sample = inputs.read(adc, inputs::DEFAULT_CHANNEL);
+        }
+        let header = PacketHeader {
+            seq,
+            channel: inputs::DEFAULT_CHANNEL,
+            ..Default::default()
+        };
+        let mut len = header.write(&mut buf);
+        let format = packet::SampleFormat::default();
+        let zero = format.zero(midScale, inputs.offset(inputs::DEFAULT_CHANNEL));
+        len += packet::pack(&samples, format, zero, packet::UNITY_GAIN, &mut buf[len..]);
+        if let Err(err) = sink.send(&buf[..len]).await {
+            log!(Info, "Uart write error: {:?}", err);
+        }
+        seq = seq.wrapping_add(1);
+    }
+}
+
+/// reads the network stack seed from the RNG, retrying if the RNG fails or produces the trivial value,
+/// if all attempts failed the weak seed is used and reported in STATS
+fn randomSeed(rng: &mut Rng<'_, RNG>) -> u64 {
+    for attempt in 1..=RNG_ATTEMPTS {
+        let mut seed = [0; 8];
+        match rng.try_fill_bytes(&mut seed) {
+            Ok(_) if isNonTrivial(&seed) => return u64::from_le_bytes(seed),
+            Ok(_) => warn!("RNG produced trivial seed {:?}, attempt {}", seed, attempt),
+            Err(_) => warn!("RNG error, attempt {}", attempt),
+        }
+        rng.reset();
+    }
+    error!("RNG failed to produce the seed, using the weak one");
+    ring_log::push(Level::Error, format_args!("RNG failed, weak seed"));
+    stats::setFlag(stats::FLAG_RNG_WEAK);
+    Instant::now().as_ticks() ^ 0x5DEE_CE66_D1CE_F00D
+}
+/// return false if the seed is a constant byte or repeated 32-bit word, typical for the failed RNG
+fn isNonTrivial(seed: &[u8; 8]) -> bool {
+    seed.iter().any(|b| *b != seed[0]) && seed[..4] != seed[4..]
+}
+/// The interrupt is taken by the earlier init
+#[derive(Debug, Format)]
+struct InterruptTaken(&'static str);
 
+/// takes the ETH interrupt, the Err instead of the `take!` panic if it's already taken (the init runs twice)
+fn takeEthInterrupt() -> Result<interrupt::ETH, InterruptTaken> {
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+    if TAKEN.swap(true, Ordering::Relaxed) {
+        error!("ETH interrupt is already taken");
+        return Err(InterruptTaken("ETH"));
+    }
+    Ok(interrupt::take!(ETH))
+}
+/// returns the packet if it is already received, doesn't wait for it
+async fn tryRecv(socket: &UdpSocket<'_>, buf: &mut [u8]) -> Option<(usize, IpEndpoint)> {
+    match with_timeout(Duration::from_ticks(0), socket.recv_from(buf)).await {
+        Ok(Ok(received)) => Some(received),
+        _ => None,
+    }
+}
+//
+/// logs the time passed since `before` with the `message` at the info level, moves `before` to now,
+/// returns the elapsed time, zero if `before` is ahead of now
+/// (the 64-bit tick counter doesn't wrap within the board lifetime)
+fn logElapsed(message: &str, before: &mut Instant) -> Duration {
+    let now = Instant::now();
+    let elapsed = Duration::from_ticks(now.as_ticks().saturating_sub(before.as_ticks()));
+    *before = now;
+    log!(Info, "{}: {} us", message, elapsed.as_micros());
+    elapsed
+}
 // icrementing index up to QSIZE, then return it to 0
 // fn incrementLoop(index: usize) -> usize {
 //     (index + 1) % QSIZE
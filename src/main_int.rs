@@ -46,6 +46,8 @@ const ADC_READ_DELAY: Duration = Duration::from_micros(61);
 const QSIZE: usize = 512;
 const QSIZE_DOUBLE: usize = QSIZE * 2;
 
+/// moves `$val` into its own static and returns `&'static mut` to it, keeping the big buffers
+/// out of the task future (and of the stack it may be built on before it's moved into the task)
 macro_rules! singleton {
     ($val:expr) => {{
         type T = impl Sized;
@@ -76,6 +78,7 @@ async fn main(spawner: Spawner) -> ! {
     info!("[main] enter");
 
     let mut config = Config::default();
+    // the early experiment, the firmware runs at 216 MHz, see `clocks` for the difference
     config.rcc.sys_ck = Some(mhz(200));
 
     let dp = embassy_stm32::init(config);
@@ -132,10 +135,11 @@ async fn main(spawner: Spawner) -> ! {
 
     // Then we can use it!
     let mut rx_meta = [PacketMetadata::EMPTY; 16];
-    let mut rx_buffer = [0; QSIZE_DOUBLE];
+    // the big buffers are statics, see `singleton`
+    let rx_buffer = singleton!([0u8; QSIZE_DOUBLE]);
     let mut tx_meta = [PacketMetadata::EMPTY; 16];
-    let mut tx_buffer = [0; QSIZE_DOUBLE];
-    let mut bufDouble = [0; QSIZE_DOUBLE];    
+    let tx_buffer = singleton!([0u8; QSIZE_DOUBLE]);
+    let bufDouble = singleton!([0u8; QSIZE_DOUBLE]);
 
     // let now = NaiveDate::from_ymd_opt(2023, 5, 10)
     //     .unwrap()
@@ -145,7 +149,7 @@ async fn main(spawner: Spawner) -> ! {
     // rtc.set_datetime(DateTime::from(now)).expect("datetime not set");
     let mut before = Instant::now();
     loop {
-        let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+        let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer[..], &mut tx_meta, &mut tx_buffer[..]);
         
         info!("UDP bind on {}:{}...", localIp, udpPort);
         let r = socket.bind(udpPort);
@@ -157,7 +161,7 @@ async fn main(spawner: Spawner) -> ! {
         info!("UDP server ready!");
         loop {
             info!("waiting handshake message...");
-            let (_n, remoteAddr) = socket.recv_from(&mut bufDouble).await.unwrap();
+            let (_n, remoteAddr) = socket.recv_from(&mut bufDouble[..]).await.unwrap();
             info!("received message from {:?}: {:?}", remoteAddr, bufDouble);
             if handshakeReceived(&bufDouble) {
                 info!("received handshake from {:?}", remoteAddr);
@@ -174,7 +178,7 @@ async fn main(spawner: Spawner) -> ! {
                     // logElapsed("ADC convertion done", &mut before);
                     if socket.is_open() {
                         // logElapsed("ADC transfering start", &mut before);
-                        let r = socket.send_to(&bufDouble, remoteAddr).await;
+                        let r = socket.send_to(&bufDouble[..], remoteAddr).await;
                         if let Err(e) = r {
                             info!("write error: {:?}", e);
                             break;
@@ -61,7 +61,7 @@
 
 use core::cell::RefCell;
 use core::mem;
-use core::sync::atomic::{AtomicUsize, Ordering, AtomicBool};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use embassy_net::udp::UdpSocket;
 use heapless::Vec;
 
@@ -92,12 +92,19 @@ const ADC_BUFFER_SIZE: usize = 1024;
 const UDP_BUFFER_SIZE: usize = ADC_BUFFER_SIZE * 2;
 const ADC_BUFFER_SIZE_ADD: usize = ADC_BUFFER_SIZE + 1;
 
-static ADC_DONE: AtomicBool = AtomicBool::new(false);
-static ACT_BUFFER: AtomicUsize = AtomicUsize::new(1);
-// static BUFFER1: Mutex<RefCell<Option<[u16; ADC_BUFFER_SIZE]>>> = Mutex::new(RefCell::new(None));
-// static BUFFER2: Mutex<RefCell<Option<[u16; ADC_BUFFER_SIZE]>>> = Mutex::new(RefCell::new(None));
-static mut BUFFER1: Queue<u16, ADC_BUFFER_SIZE_ADD> = Queue::new();
-static mut BUFFER2: Queue<u16, ADC_BUFFER_SIZE_ADD> = Queue::new();
+/// sample buffers in the ring, 2 - ping-pong, more buffers absorb longer network stalls,
+/// each one costs (ADC_BUFFER_SIZE + 1) * 2 bytes of RAM (~2 KiB)
+const BUFFER_COUNT: usize = 2;
+const _: () = assert!(BUFFER_COUNT >= 2);
+const EMPTY_BUFFER: Queue<u16, ADC_BUFFER_SIZE_ADD> = Queue::new();
+/// the ring of the sample buffers, the ADC fills them in turn, the network drains them in the same order
+static mut BUFFERS: [Queue<u16, ADC_BUFFER_SIZE_ADD>; BUFFER_COUNT] = [EMPTY_BUFFER; BUFFER_COUNT];
+/// buffers filled by the ADC since the start, the next one to fill is `FILLED % BUFFER_COUNT`,
+/// written by the ADC task only
+static FILLED: AtomicUsize = AtomicUsize::new(0);
+/// buffers drained by the network since the start, the oldest filled one is `DRAINED % BUFFER_COUNT`,
+/// written by the network loop only
+static DRAINED: AtomicUsize = AtomicUsize::new(0);
 
 
 macro_rules! singleton {
@@ -129,39 +136,24 @@ async fn run_high(
     let mut now = Instant::now().as_micros();
     let mut t = 0;
     let adcDelay = Duration::from_ticks(1); //ADC_CYCLE / (ADC_BUFFER_SIZE as u64);
-    let mut act = 1;
     loop {
         now = Instant::now().as_micros();
-        let buffer = match act {
-            1 => unsafe {
-                &mut BUFFER1
-            },
-            _ => unsafe {
-                &mut BUFFER2
-            },
-        };
-        if buffer.is_empty() {
+        let filled = FILLED.load(Ordering::Relaxed);
+        // the buffer is free once the network has drained it, the counters only grow (wrapping)
+        if filled.wrapping_sub(DRAINED.load(Ordering::Acquire)) < BUFFER_COUNT {
+            let buffer = unsafe { &mut BUFFERS[filled % BUFFER_COUNT] };
             for _ in 0..ADC_BUFFER_SIZE {
-                adc.read(&mut pin);
-                // buffer.enqueue(
-                // ).unwrap();
+                let sample = adc.read(&mut pin);
+                // can't overflow, the buffer is empty and holds ADC_BUFFER_SIZE samples
+                buffer.enqueue(sample).ok();
                 cortex_m::asm::wfe();
                 Timer::after(adcDelay).await;
                 // info!("ADC conversion index: {} done", i);
             }
-            act = match act {
-                1 => {
-                    ACT_BUFFER.store(2, Ordering::Relaxed);
-                    2
-                },
-                _ => {
-                    ACT_BUFFER.store(1, Ordering::Relaxed);
-                    1
-                }
-            };
-            ADC_DONE.store(true, Ordering::Relaxed);    
+            // publishes the buffer to the network
+            FILLED.store(filled.wrapping_add(1), Ordering::Release);
         } else {
-            info!("ADC buffer {} is not ready, skeep cycle", act);
+            info!("ADC all {} buffers are not drained, skeep cycle", BUFFER_COUNT);
             Timer::after(Duration::from_micros(90 * 3 * (ADC_BUFFER_SIZE as u64))).await;
         }
         let elapsed = Instant::now().as_micros() - now;
@@ -242,8 +234,6 @@ async fn main(mainSpawner: Spawner) -> ! {
 
     // cortex_m::interrupt::free(|cs| {
     //     // enable_interrupt(&mut button);
-    //     BUFFER1.borrow(cs).borrow_mut().replace([0; ADC_BUFFER_SIZE]);
-    //     BUFFER2.borrow(cs).borrow_mut().replace([0; ADC_BUFFER_SIZE]);
     //     // NVIC::unmask(pac::Interrupt::EXTI15_10);
     // });
 
@@ -343,18 +333,12 @@ async fn main(mainSpawner: Spawner) -> ! {
                 info!("received handshake from {:?}", remoteAddr);
                 let mut j: usize = 0;
                 loop {
-                    while !ADC_DONE.load(Ordering::Relaxed) {
+                    let drained = DRAINED.load(Ordering::Relaxed);
+                    while FILLED.load(Ordering::Acquire) == drained {
                         Timer::after(Duration::from_micros(10)).await;
                     }
-                    ADC_DONE.store(false, Ordering::Relaxed);
-                    let buffer = match ACT_BUFFER.load(Ordering::Relaxed) {
-                        1 => unsafe {
-                            &mut BUFFER1
-                        },
-                        _ => unsafe {
-                            &mut BUFFER2
-                        },
-                    };
+                    // the oldest filled buffer
+                    let buffer = unsafe { &mut BUFFERS[drained % BUFFER_COUNT] };
                     let mut bytes: [u8; 2];
                     for i in 0..(ADC_BUFFER_SIZE) {
                         bytes = buffer.dequeue().unwrap().to_be_bytes();
@@ -362,19 +346,8 @@ async fn main(mainSpawner: Spawner) -> ! {
                         bufDouble[j] = bytes[0];
                         bufDouble[j + 1] = bytes[1];
                     }
-                    // cortex_m::interrupt::free(|cs| {
-                    //     let b1: &mut [u16; ADC_BUFFER_SIZE];
-                    //     let b2: &mut [u16; ADC_BUFFER_SIZE];
-                    //     let mut br1 = BUFFER1.borrow(cs).borrow_mut();
-                    //     b1 = br1.as_mut().unwrap();
-                    //     let mut br2 = BUFFER2.borrow(cs).borrow_mut();
-                    //     b2 = br2.as_mut().unwrap();
-                        
-                    //     let buffer = match ACT_BUFFER.load(Ordering::Relaxed) {
-                    //         1 => b2,
-                    //         _ => b1,
-                    //     };                     
-                    // });
+                    // returns the buffer to the ADC
+                    DRAINED.store(drained.wrapping_add(1), Ordering::Release);
 
                     if socket.is_open() {
                         // logElapsed("ADC transfering start", &mut before);
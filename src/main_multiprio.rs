@@ -33,6 +33,13 @@
 //!     [med] done in 993 ms
 //! ```
 //!
+//! The interrupt executors borrow the vectors of the peripherals the board doesn't use, they are selected
+//! by `executorVector!` (the handler and its `ExecutorVector` config) and validated when the executor starts.
+//! Safe to borrow on the STM32F767 unless the board uses the peripheral:
+//! `UART4`, `UART5`, `UART7`, `UART8`, `SPI4`, `SPI5`, `SPI6`, `SAI1`, `SAI2`, `CAN3_*`, `DFSDM1_FLT*`.
+//! Not to be borrowed: `ETH` and the pins' `EXTI*`, the timer of the time driver (`time-driver-any`),
+//! `USART6` (the `uart-sink` build of `main.rs`) and any vector enabled by the driver - `start` rejects it.
+//!
 //! For comparison, try changing the code so all 3 tasks get spawned on the low priority executor.
 //! You will get an output like the following. Note that no computation is ever interrupted.
 //!
@@ -69,7 +76,7 @@ use cortex_m::interrupt::Mutex;
 use cortex_m::peripheral::NVIC;
 // use cortex_m_rt::entry;
 use defmt::*;
-use embassy_executor::{Executor, InterruptExecutor, Spawner};
+use embassy_executor::{Executor, InterruptExecutor, SendSpawner, SpawnError, Spawner};
 use embassy_net::{Stack, Ipv4Address, Ipv4Cidr, StackResources, udp::PacketMetadata};
 use embassy_stm32::adc::{Adc, SampleTime};
 use embassy_stm32::eth::{Ethernet, PacketQueue};
@@ -89,6 +96,8 @@ const EOT: u8 = 4;
 const UDP_PORT: u16 = 15180;
 const ADC_BUFFER_SIZE: usize = 1024;
 const UDP_BUFFER_SIZE: usize = ADC_BUFFER_SIZE * 2;
+/// implemented NVIC priority bits of the STM32F7, priority levels 0 (the most urgent) ..= 15
+const NVIC_PRIO_BITS: u8 = 4;
 
 static ADC_DONE: AtomicBool = AtomicBool::new(false);
 static ACT_BUFFER: AtomicUsize = AtomicUsize::new(1);
@@ -152,22 +161,68 @@ async fn run_low() {
     }
 }
 
-static EXECUTOR_HIGH: InterruptExecutor = InterruptExecutor::new();
-static EXECUTOR_MED: InterruptExecutor = InterruptExecutor::new();
-static EXECUTOR_LOW: StaticCell<Executor> = StaticCell::new();
+/// logs the failed spawn of the `task` instead of panicking, returns true if the task is spawned,
+/// the only spawn error is the exhausted task pool - more instances than `#[task(pool_size)]` allows
+fn spawned(task: &str, result: Result<(), SpawnError>) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(err) => {
+            error!("[main] task {} not spawned: {:?}, its pool is exhausted, check #[task(pool_size)]", task, err);
+            false
+        }
+    }
+}
+
+/// Otherwise unused interrupt vector backing the interrupt executor
+struct ExecutorVector {
+    name: &'static str,
+    interrupt: Interrupt,
+    /// NVIC priority level, lower is more urgent, 0..(1 << NVIC_PRIO_BITS)
+    priority: u8,
+}
 
-#[interrupt]
-unsafe fn UART4() {
-    // debug!("[interrupt] EXECUTOR_HIGH");
-    EXECUTOR_HIGH.on_interrupt()
+impl ExecutorVector {
+    /// sets the priority and starts the `executor` on the vector,
+    /// None if the priority is out of range or the vector is already enabled (used by a driver)
+    fn start(&self, executor: &'static InterruptExecutor, nvic: &mut NVIC) -> Option<SendSpawner> {
+        if self.priority >= 1 << NVIC_PRIO_BITS {
+            error!("[main] {} priority {} out of 0..{}", self.name, self.priority, 1 << NVIC_PRIO_BITS);
+            return None;
+        }
+        if NVIC::is_enabled(self.interrupt) {
+            error!("[main] {} is in use, borrow another vector for the executor", self.name);
+            return None;
+        }
+        unsafe { nvic.set_priority(self.interrupt, self.priority << (8 - NVIC_PRIO_BITS)) };
+        Some(executor.start(self.interrupt))
+    }
 }
 
-#[interrupt]
-unsafe fn UART5() {
-    // debug!("[interrupt] EXECUTOR_MED");
-    EXECUTOR_MED.on_interrupt()
+/// defines the `$vector` handler running the `$executor` and its `$config` with the `$priority`
+macro_rules! executorVector {
+    ($vector:ident, $priority:expr, $executor:ident, $config:ident) => {
+        #[interrupt]
+        unsafe fn $vector() {
+            $executor.on_interrupt()
+        }
+        const $config: ExecutorVector = ExecutorVector {
+            name: stringify!($vector),
+            interrupt: Interrupt::$vector,
+            priority: $priority,
+        };
+    };
 }
 
+static EXECUTOR_HIGH: InterruptExecutor = InterruptExecutor::new();
+static EXECUTOR_MED: InterruptExecutor = InterruptExecutor::new();
+static EXECUTOR_LOW: StaticCell<Executor> = StaticCell::new();
+
+executorVector!(UART4, 6, EXECUTOR_HIGH, HIGH_VECTOR);
+executorVector!(UART5, 7, EXECUTOR_MED, MED_VECTOR);
+const _: () = assert!(HIGH_VECTOR.interrupt as u16 != MED_VECTOR.interrupt as u16);
+// the high executor preempts the medium one
+const _: () = assert!(HIGH_VECTOR.priority < MED_VECTOR.priority);
+
 #[embassy_executor::main]
 async fn main(mainSpawner: Spawner) -> ! {
 // #[entry]
@@ -239,39 +294,35 @@ async fn main(mainSpawner: Spawner) -> ! {
     );
 
     // Launch network task
-    unwrap!(mainSpawner.spawn(net_task(&stack)));
-    info!("Network task initialized");
+    if spawned("net_task", mainSpawner.spawn(net_task(&stack))) {
+        info!("Network task initialized");
+    } else {
+        // degraded: no network, the executors below still run
+        error!("[main] running without the network");
+    }
 
 
     // let _p = embassy_stm32::init(Default::default());
     let mut nvic: NVIC = unsafe { mem::transmute(()) };
 
-    // High-priority executor: UART4, priority level 6
-    unsafe { nvic.set_priority(Interrupt::UART4, 6 << 4) };
-    let spawner = EXECUTOR_HIGH.start(Interrupt::UART4);
-    spawner.spawn(
-        run_high()
-    ).unwrap();
-    // unwrap!(spawner.spawn(
-    //     run_high()
-    // ));
-    info!("High-priority task initialized");
-
-    // Medium-priority executor: UART5, priority level 7
-    unsafe { nvic.set_priority(Interrupt::UART5, 7 << 4) };
-    let spawner = EXECUTOR_MED.start(Interrupt::UART5);
-    spawner.spawn(
-        run_med()
-    ).unwrap();
-    // unwrap!(spawner.spawn(
-    //     run_med()
-    // ));
-    info!("Medium-priority task initialized");
+    // High-priority executor, see `HIGH_VECTOR`
+    if let Some(spawner) = HIGH_VECTOR.start(&EXECUTOR_HIGH, &mut nvic) {
+        if spawned("run_high", spawner.spawn(run_high())) {
+            info!("High-priority task initialized on {}", HIGH_VECTOR.name);
+        }
+    }
+
+    // Medium-priority executor, see `MED_VECTOR`
+    if let Some(spawner) = MED_VECTOR.start(&EXECUTOR_MED, &mut nvic) {
+        if spawned("run_med", spawner.spawn(run_med())) {
+            info!("Medium-priority task initialized on {}", MED_VECTOR.name);
+        }
+    }
 
     // Low priority executor: runs in thread mode, using WFE/SEV
     // let executor = EXECUTOR_LOW.init(Executor::new());
     // executor.run(|spawner| {
-    //     unwrap!(spawner.spawn(run_low()));
+    //     spawned("run_low", spawner.spawn(run_low()));
     // });
 
     info!("[main] loop enter");
@@ -0,0 +1,85 @@
+//! Minimal mDNS responder answering the A record queries for `<host>.local`
+//!
+//! Only the questions are looked at, the answer is the single A record of the board address.
+//! Queries from port 5353 are answered to the multicast group,
+//! legacy unicast queries (other source port) - to the sender with the query id.
+//! The names with compression pointers in the questions are never matched.
+use embassy_net::Ipv4Address;
+
+pub const PORT: u16 = 5353;
+pub const GROUP: Ipv4Address = Ipv4Address([224, 0, 0, 251]);
+/// the longest DNS label
+pub const MAX_HOST_LEN: usize = 63;
+
+const HEADER_SIZE: usize = 12;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// the unicast response bit of the question class, the cache flush bit of the answer class
+const CLASS_TOP_BIT: u16 = 0x8000;
+const TTL_SECS: u32 = 120;
+const LOCAL: &[u8] = b"\x05local\x00";
+
+/// returns the id of the `packet` if it's a query for the A record of `<host>.local`
+pub fn query(packet: &[u8], host: &str) -> Option<u16> {
+    let header = packet.get(..HEADER_SIZE)?;
+    if u16::from_be_bytes([header[2], header[3]]) & FLAG_RESPONSE != 0 {
+        return None;
+    }
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let mut pos = HEADER_SIZE;
+    for _ in 0..questions {
+        let end = skipName(packet, pos)?;
+        let question = packet.get(end..end + 4)?;
+        let qtype = u16::from_be_bytes([question[0], question[1]]);
+        let qclass = u16::from_be_bytes([question[2], question[3]]) & !CLASS_TOP_BIT;
+        if matches!(qtype, TYPE_A | TYPE_ANY) && qclass == CLASS_IN && isHostName(&packet[pos..end], host) {
+            return Some(u16::from_be_bytes([header[0], header[1]]));
+        }
+        pos = end + 4;
+    }
+    None
+}
+
+/// writes the response with the A record `<host>.local` -> `addr` into `buf`, returns its length
+pub fn answer(buf: &mut [u8], id: u16, host: &str, addr: Ipv4Address) -> usize {
+    buf[0..2].copy_from_slice(&id.to_be_bytes());
+    buf[2..4].copy_from_slice(&(FLAG_RESPONSE | FLAG_AUTHORITATIVE).to_be_bytes());
+    // no questions, one answer
+    buf[4..12].copy_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0]);
+    let mut pos = HEADER_SIZE;
+    buf[pos] = host.len() as u8;
+    buf[pos + 1..pos + 1 + host.len()].copy_from_slice(host.as_bytes());
+    pos += 1 + host.len();
+    buf[pos..pos + LOCAL.len()].copy_from_slice(LOCAL);
+    pos += LOCAL.len();
+    buf[pos..pos + 2].copy_from_slice(&TYPE_A.to_be_bytes());
+    buf[pos + 2..pos + 4].copy_from_slice(&(CLASS_IN | CLASS_TOP_BIT).to_be_bytes());
+    buf[pos + 4..pos + 8].copy_from_slice(&TTL_SECS.to_be_bytes());
+    buf[pos + 8..pos + 10].copy_from_slice(&4u16.to_be_bytes());
+    buf[pos + 10..pos + 14].copy_from_slice(addr.as_bytes());
+    pos + 14
+}
+
+/// returns the offset following the name starting at `pos`
+fn skipName(packet: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        match *packet.get(pos)? as usize {
+            0 => return Some(pos + 1),
+            // compression pointer ends the name
+            len if len & 0xC0 == 0xC0 => return Some(pos + 2),
+            len => pos += 1 + len,
+        }
+    }
+}
+
+/// return true if the encoded `name` is `<host>.local`, case insensitive
+fn isHostName(name: &[u8], host: &str) -> bool {
+    let len = host.len();
+    name.len() == 1 + len + LOCAL.len()
+        && name[0] as usize == len
+        && name[1..1 + len].eq_ignore_ascii_case(host.as_bytes())
+        && name[1 + len..].eq_ignore_ascii_case(LOCAL)
+}
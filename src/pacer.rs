@@ -0,0 +1,66 @@
+//! Token bucket capping the data bytes sent per second
+//!
+//! The session requesting the rate limit (the handshake TLV record `5`) sends each datagram only when the bucket
+//! holds its bytes: the bucket fills at the limit per second up to `capacity` (the largest datagram, so one always
+//! passes, the idle stream can't save more than one datagram of burst) and the datagram takes its bytes out.
+//! The tokens are kept in bytes times microseconds, the integer refill loses no fraction of the byte.
+//! The wait delays the acquisition of the next datagram (the blocking one) or overruns the DMA halves,
+//! the stream is slowed down to the limit rather than the datagrams dropped.
+//! The limit in force is reported in STATS, 0 - unlimited (no session or no limit requested).
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_time::{Duration, Instant, Timer};
+
+/// the lowest limit applied, bytes per second, the lower requested one is raised to it
+pub const MIN_RATE: u32 = 8_000;
+
+/// the limit of the running session, bytes per second, 0 - unlimited
+static LIMIT: AtomicU32 = AtomicU32::new(0);
+
+/// the limit of the running session, bytes per second, 0 - unlimited
+pub fn limit() -> u32 {
+    LIMIT.load(Ordering::Relaxed)
+}
+
+/// The token bucket of the session, reported in STATS until dropped
+pub struct Pacer {
+    /// bytes per second
+    rate: u32,
+    /// the tokens, bytes * 1e6
+    tokens: u64,
+    capacity: u64,
+    updated: Instant,
+}
+
+impl Pacer {
+    /// the bucket of `rate` bytes per second holding up to `capacity` bytes, starts full
+    pub fn new(rate: u32, capacity: usize) -> Self {
+        let rate = rate.max(MIN_RATE);
+        LIMIT.store(rate, Ordering::Relaxed);
+        let capacity = capacity as u64 * 1_000_000;
+        Self { rate, tokens: capacity, capacity, updated: Instant::now() }
+    }
+    /// waits until the bucket holds `bytes`, takes them
+    pub async fn take(&mut self, bytes: usize) {
+        let needed = (bytes as u64 * 1_000_000).min(self.capacity);
+        self.refill();
+        if self.tokens < needed {
+            let waitUs = (needed - self.tokens + self.rate as u64 - 1) / self.rate as u64;
+            Timer::after(Duration::from_micros(waitUs)).await;
+            self.refill();
+        }
+        self.tokens = self.tokens.saturating_sub(needed);
+    }
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsedUs = now.duration_since(self.updated).as_micros();
+        self.updated = now;
+        self.tokens = (self.tokens + elapsedUs * self.rate as u64).min(self.capacity);
+    }
+}
+
+impl Drop for Pacer {
+    fn drop(&mut self) {
+        LIMIT.store(0, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,968 @@
+//! Data packet streamed to the client
+//!
+//! ```not_rust
+//! | STX | flags: u8 | seq: u32 | channel: u8 | samples: u16 ... |
+//! ```
+//! With `FLAG_SUBBLOCKS` the datagram carries several acquisition blocks, each prefixed with the sub-header:
+//! ```not_rust
+//! | STX | flags: u8 | seq: u32 | channel: u8 | samples: u16 | offset: u32 | samples: u16 ... | samples: u16 | offset: u32 | ...
+//! ```
+//! where `offset` - microseconds from the start of the first block to the start (trigger) of this one.
+//!
+//! With `FORMAT_BASE_STAMP` (acknowledged in the format, the packets don't flag it) the datagram is the super-block:
+//! one sequence and one timer stamp for all its blocks, the stamp follows the header:
+//! ```not_rust
+//! | STX | flags: u8 | seq: u32 | channel: u8 | stamp: u32 | samples: u16 | offset: u32 | samples: u16 ... |
+//! ```
+//! `stamp` - the timer (see `timestamp`) when the first block started (in the triggered mode - at its trigger),
+//! the block `k` started `offset[k] - offset[0]` microseconds after it. The blocks per datagram is the sub-block count.
+//!
+//! With `FORMAT_SAMPLE_INDEX` (acknowledged in the format as well) each block (after its sub-header if any)
+//! starts with the session index of its first sample:
+//! ```not_rust
+//! | index: u64 | samples: u16 ... |
+//! ```
+//! The index counts the acquired samples from the session start (all the channels of the sweep,
+//! one oversampled sum is one sample, the envelope and the summary still index the acquired samples),
+//! it runs on across the resume skipping the samples of the missed datagrams,
+//! so the index gap between the datagrams received is the exact count of the samples lost.
+//!
+//! With `FLAG_ENVELOPE` each block carries the `| min | max |` pair per group of the samples
+//! (the sub-header still counts the acquired samples), the stamps and the injected samples index
+//! the acquired samples as well.
+//!
+//! With `FLAG_SUMMARY` each block (after its sub-header) starts with the summary of the acquired samples,
+//! scaled by the channel gain and clipped:
+//! ```not_rust
+//! | rms: u16 | peak-to-peak: u16 | samples: u16 ... |
+//! ```
+//! where the RMS is taken relative to the zero of the format, with `FLAG_NO_SAMPLES` the samples
+//! (the envelope) are left out and the block is the summary only.
+//!
+//! With `FLAG_TIMESTAMPS` the datagram ends with the timer stamps of every `TIMESTAMP_STRIDE`-th sample
+//! of the datagram (0, stride, 2 * stride..., counted across the blocks), see `timestamp`:
+//! ```not_rust
+//! | ...samples | stamp: u32 | stamp: u32 | ...
+//! ```
+//! With `FLAG_INJECTED` the injected channel samples (raw counts) taken every `every` samples
+//! of the datagram follow (after the stamps if any):
+//! ```not_rust
+//! | ... | channel: u8 | sample: u16 | sample: u16 | ...
+//! ```
+//! Built with the external mux (the `ext-mux` feature, the acknowledgment reports its positions) the datagram ends
+//! with the mux position of each acquired sample, `MUX_NONE` for the samples of the other channels, see `ext_mux`:
+//! ```not_rust
+//! | ... | position: u8 | position: u8 | ...
+//! ```
+//!
+//! The block larger than the sample buffer is paged: acquired and sent a buffer (page) per datagram as it fills,
+//! so the RAM doesn't hold the whole block, the last page may be shorter. The page of the datagram is
+//! `(seq - first seq) % pages`, the first sequence and the page size are reported in the handshake acknowledgment.
+//! The pages follow each other without the trigger wait, a few microseconds of packing and queuing apart.
+//!
+//! With the `METADATA_FIRST` handshake the session starts with the metadata packet before the first data packet,
+//! for the decoders framing the stream by its header packet (otherwise the metadata is the acknowledgment only):
+//! ```not_rust
+//! | SOH | stream format: see `StreamFormat` | seq: u32 | missed: u32 | block: u16 | blocks: u16 | page: u16 |
+//! sample index: u64 |
+//! ```
+//! `seq` and `sample index` - of the first data packet, the metadata packet takes no sequence number,
+//! the rest as in the acknowledgment. It isn't retransmitted, the acknowledgment is the reliable copy.
+//!
+//! With `FORMAT_ASCII` (built with the `debug-ascii` feature, cleared in the acknowledged format otherwise)
+//! the datagram is the text line of the comma separated decimals, for the bring-up by `nc -u` without the decoder:
+//! ```not_rust
+//! seq,channel,sample,sample...\n
+//! ```
+//! the samples are encoded by the format (signed or not, zero, gain) and printed, the blocks follow each other
+//! without the sub-headers, the other parts of the datagram aren't available. The decimals are variable width,
+//! the layout reserves the widest ones (`ASCII_HEADER_SIZE`, `ASCII_SAMPLE_SIZE`), so the line holds
+//! 3..=7 times less samples than the binary datagram and the full block is paged.
+//!
+//! Blocks per datagram, in order of precedence:
+//! - the paged block is sent one page per datagram
+//! - the block size is limited by the sample buffer and by the datagram buffer (whole samples with all the parts
+//!   of the datagram), the larger block is paged
+//! - the requested count is used as is, if not requested - as many as needed to reach the minimum datagram size
+//! - the count is limited by `MAX_BLOCKS`, the sample buffer and the UDP payload of the MTU
+//! (with all the parts of the datagram, see `Contents`), so the datagram of several blocks is never fragmented,
+//! the single block larger than the payload is (see `maxDatagram`)
+//!
+//! All multibyte values are big endian, written byte by byte, so the samples start at any offset
+//! (the header is 7 bytes, the odd parts are the channel and the injected channel) with no alignment padding.
+//! The 16 bit samples (and the envelope pairs) only may be little endian instead: the handshake lists
+//! the sample encodings the client decodes (`CAP_*`), the board picks the first of `CAP_PRIORITY` both support,
+//! big endian if none, see `negotiate`. Little endian is the MCU's own order, the raw samples are copied as they are.
+//! Only the whole samples are written into the datagram buffer, the bytes left at its end (less than the sample,
+//! the envelope pair) are unused and never sent.
+//! Samples are unsigned ADC counts or, with `FORMAT_SIGNED`,
+//! signed `i16` centered by subtracting the mid-scale of the ADC resolution.
+//! If the channel is tared, its offset is subtracted instead in both formats.
+//! The channel gain of the handshake scales the samples relative to that zero.
+//!
+//! With `FORMAT_BYTE` (acknowledged in the format, the packets don't flag it) each sample (the envelope value)
+//! is one byte, halving the datagram: the sample as encoded above shifted right by the byte shift
+//! of the acknowledgment (the full scale bits above 8) and clipped to `u8` (`i8` if signed), see `packBytes`.
+//! The summary and the injected samples stay `u16`.
+//!
+//! The module depends on `core` only, so its tests run on the host apart from the firmware:
+//! `rustc --edition 2021 --test src/packet.rs -o /tmp/packet && /tmp/packet`
+
+pub const STX: u8 = 2;
+/// the start of the metadata packet
+pub const SOH: u8 = 1;
+/// size of the metadata packet
+pub const METADATA_SIZE: usize = 1 + STREAM_FORMAT_SIZE + 22;
+/// the first packet of the resumed session or after the `PAUSE`, sequence continues the previous one
+pub const FLAG_RESUMED: u8 = 0b0000_0001;
+/// the datagram carries several blocks with the sub-headers
+pub const FLAG_SUBBLOCKS: u8 = 0b0000_0010;
+/// the datagram ends with the sample timestamps
+pub const FLAG_TIMESTAMPS: u8 = 0b0000_0100;
+/// the delayed start was already passed when the session was set up, the first packet started late
+pub const FLAG_LATE_START: u8 = 0b0000_1000;
+/// the datagram ends with the injected channel samples
+pub const FLAG_INJECTED: u8 = 0b0001_0000;
+/// the blocks carry the min / max envelope instead of the samples
+pub const FLAG_ENVELOPE: u8 = 0b0010_0000;
+/// the blocks start with the RMS / peak-to-peak summary
+pub const FLAG_SUMMARY: u8 = 0b0100_0000;
+/// the blocks carry the summary only
+pub const FLAG_NO_SAMPLES: u8 = 0b1000_0000;
+/// the mux position of the sample taken from the channel without the mux
+pub const MUX_NONE: u8 = 0xFF;
+/// size of the header in front of the samples
+pub const HEADER_SIZE: usize = 7;
+/// size of the sub-header in front of each block
+pub const SUBHEADER_SIZE: usize = 6;
+/// maximum blocks in one datagram
+pub const MAX_BLOCKS: usize = 32;
+/// size of the summary in front of the block samples
+pub const SUMMARY_SIZE: usize = 4;
+/// one timestamp per this many samples
+pub const TIMESTAMP_STRIDE: usize = 16;
+/// the densest injected conversion, one per this many regular samples
+pub const MIN_INJECTED_EVERY: usize = 16;
+/// the Ethernet MTU
+pub const ETH_MTU: usize = 1500;
+/// the smallest MTU every IPv4 host accepts
+pub const MIN_MTU: usize = 576;
+/// IPv4 and UDP headers
+const IP_UDP_HEADERS: usize = 28;
+/// UDP payload fitting the Ethernet MTU of 1500 without IP fragmentation
+pub const MAX_DATAGRAM: usize = maxDatagram(ETH_MTU);
+
+/// the UDP payload fitting the `mtu` without IP fragmentation
+pub const fn maxDatagram(mtu: usize) -> usize {
+    mtu - IP_UDP_HEADERS
+}
+
+/// the channel gain of 1.0, the gain is fixed point with `GAIN_SHIFT` fraction bits
+pub const UNITY_GAIN: u16 = 1 << GAIN_SHIFT;
+const GAIN_SHIFT: u32 = 12;
+
+/// sample format flag, samples are `i16` centered around zero
+pub const FORMAT_SIGNED: u8 = 0b0000_0001;
+/// sample format flag, the datagram carries the sample timestamps
+pub const FORMAT_TIMESTAMPS: u8 = 0b0000_0010;
+/// sample format flag, the datagram carries one timer stamp of its first block after the header
+pub const FORMAT_BASE_STAMP: u8 = 0b0000_0100;
+/// size of the base stamp following the header
+pub const BASE_STAMP_SIZE: usize = 4;
+/// sample format flag, each block starts with the session index of its first sample
+pub const FORMAT_SAMPLE_INDEX: u8 = 0b0000_1000;
+/// size of the sample index in front of each block
+pub const SAMPLE_INDEX_SIZE: usize = 8;
+/// sample format flag, the datagram is the text line, built with the `debug-ascii` feature only
+pub const FORMAT_ASCII: u8 = 0b0001_0000;
+/// sample format flag, the samples are millivolts instead of the counts, switched mid-session by `SET_UNITS`
+pub const FORMAT_MILLIVOLTS: u8 = 0b0010_0000;
+/// sample format flag, the samples are the top 8 bits, one byte each
+pub const FORMAT_BYTE: u8 = 0b0100_0000;
+/// sample encoding capability, `u16` big endian, the fallback every client decodes
+pub const CAP_WORD_BE: u8 = 1 << 0;
+/// sample encoding capability, `u16` little endian
+pub const CAP_WORD_LE: u8 = 1 << 1;
+/// sample encoding capability, 12 bit samples packed two in three bytes, reserved, not sent by this firmware
+pub const CAP_PACKED12: u8 = 1 << 2;
+/// the sample encodings this firmware sends
+pub const CAP_SUPPORTED: u8 = CAP_WORD_BE | CAP_WORD_LE;
+/// the sample encodings from the most preferred: the smaller datagram, then the cheaper packing
+pub const CAP_PRIORITY: [u8; 3] = [CAP_PACKED12, CAP_WORD_LE, CAP_WORD_BE];
+/// raised in the header channel byte of the datagram of millivolts (the header flags are all taken),
+/// the channel is in the low bits
+pub const CHANNEL_MILLIVOLTS: u8 = 0x80;
+/// the widest ASCII line header `4294967295,255,`
+pub const ASCII_HEADER_SIZE: usize = 15;
+/// the widest ASCII sample `-32768,` (the last one ends with the newline instead)
+pub const ASCII_SAMPLE_SIZE: usize = 7;
+
+/// version of the `StreamFormat` prefix, its fields are never changed, the new ones are appended
+pub const STREAM_FORMAT_VERSION: u8 = 1;
+/// size of the `StreamFormat` prefix
+pub const STREAM_FORMAT_SIZE: usize = 6;
+/// `StreamFormat` endianness of the samples, the other multibyte values are always big endian
+const BIG_ENDIAN: u8 = 0;
+const LITTLE_ENDIAN: u8 = 1;
+/// `StreamFormat` bytes per sample
+const SAMPLE_BYTES: u8 = 2;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PacketHeader {
+    pub flags: u8,
+    pub seq: u32,
+    /// ADC channel the samples are taken from
+    pub channel: u8,
+}
+
+impl PacketHeader {
+    /// writes the header into the beginning of `buf`, returns the header size
+    pub fn write(&self, buf: &mut [u8]) -> usize {
+        buf[0] = STX;
+        buf[1] = self.flags;
+        buf[2..6].copy_from_slice(&self.seq.to_be_bytes());
+        buf[6] = self.channel;
+        HEADER_SIZE
+    }
+}
+
+/// Sub-header of the block in the datagram carrying several blocks
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SubHeader {
+    pub samples: u16,
+    pub offsetUs: u32,
+}
+
+impl SubHeader {
+    /// writes the sub-header into the beginning of `buf`, returns the sub-header size
+    pub fn write(&self, buf: &mut [u8]) -> usize {
+        buf[0..2].copy_from_slice(&self.samples.to_be_bytes());
+        buf[2..6].copy_from_slice(&self.offsetUs.to_be_bytes());
+        SUBHEADER_SIZE
+    }
+}
+
+/// The metadata packet preceding the first data packet of the session
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Metadata {
+    pub stream: StreamFormat,
+    /// sequence number of the first data packet
+    pub seq: u32,
+    /// blocks skipped since the resumed session was ended
+    pub missed: u32,
+    /// samples per logical block
+    pub blockSamples: u16,
+    pub blocks: u16,
+    /// samples per datagram of the paged block
+    pub pageSamples: u16,
+    /// the sample index of the first data packet, see `FORMAT_SAMPLE_INDEX`
+    pub sampleIndex: u64,
+}
+
+impl Metadata {
+    /// writes the packet into the beginning of `buf`, returns its size
+    pub fn write(&self, buf: &mut [u8]) -> usize {
+        buf[0] = SOH;
+        let len = 1 + self.stream.write(&mut buf[1..]);
+        buf[len..len + 4].copy_from_slice(&self.seq.to_be_bytes());
+        buf[len + 4..len + 8].copy_from_slice(&self.missed.to_be_bytes());
+        buf[len + 8..len + 10].copy_from_slice(&self.blockSamples.to_be_bytes());
+        buf[len + 10..len + 12].copy_from_slice(&self.blocks.to_be_bytes());
+        buf[len + 12..len + 14].copy_from_slice(&self.pageSamples.to_be_bytes());
+        buf[len + 14..len + 22].copy_from_slice(&self.sampleIndex.to_be_bytes());
+        METADATA_SIZE
+    }
+}
+
+/// Per block summary requested by the handshake
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(test), derive(defmt::Format))]
+pub enum Summary {
+    /// the summary followed by the samples
+    WithSamples,
+    /// the summary instead of the samples
+    Only,
+}
+
+/// Parts of the datagram besides the header and the blocks of samples
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(not(test), derive(defmt::Format))]
+pub struct Contents {
+    pub timestamps: bool,
+    pub baseStamp: bool,
+    pub sampleIndex: bool,
+    /// the text line, the other parts are off, see `FORMAT_ASCII`
+    pub ascii: bool,
+    /// one byte per sample, see `FORMAT_BYTE`
+    pub byte: bool,
+    /// one injected sample per this many samples
+    pub injectedEvery: Option<usize>,
+    /// samples per min / max pair
+    pub envelope: Option<usize>,
+    pub summary: Option<Summary>,
+    /// the datagram ends with the mux positions of the samples
+    pub muxPositions: bool,
+}
+
+impl Contents {
+    /// size of the datagram carrying `blocks` of `blockSamples` each
+    pub fn size(&self, blockSamples: usize, blocks: usize) -> usize {
+        if self.ascii {
+            return ASCII_HEADER_SIZE + blockSamples * blocks * ASCII_SAMPLE_SIZE;
+        }
+        let subHeader = if blocks > 1 { SUBHEADER_SIZE } else { 0 };
+        let summary = if self.summary.is_some() { SUMMARY_SIZE } else { 0 };
+        let index = if self.sampleIndex { SAMPLE_INDEX_SIZE } else { 0 };
+        let data = match (self.summary, self.envelope) {
+            (Some(Summary::Only), _) => 0,
+            (_, Some(group)) => (blockSamples + group - 1) / group * 2 * self.sampleSize(),
+            (_, None) => blockSamples * self.sampleSize(),
+        };
+        let samples = blockSamples * blocks;
+        let stamps = if self.timestamps { stampCount(samples) * 4 } else { 0 };
+        let baseStamp = if self.baseStamp { BASE_STAMP_SIZE } else { 0 };
+        let injected = match self.injectedEvery {
+            Some(every) => 1 + (samples + every - 1) / every * 2,
+            None => 0,
+        };
+        let positions = if self.muxPositions { samples } else { 0 };
+        HEADER_SIZE + baseStamp + blocks * (subHeader + index + summary + data) + stamps + injected + positions
+    }
+    /// bytes per sample in the blocks
+    pub fn sampleSize(&self) -> usize {
+        match self.byte {
+            true => 1,
+            false => 2,
+        }
+    }
+}
+
+/// Wire format of the running session, the fixed prefix of the STATS and GET_CONFIG replies,
+/// so the observer joining later decodes the datagrams without seeing the handshake
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(not(test), derive(defmt::Format))]
+pub struct StreamFormat {
+    pub format: SampleFormat,
+    /// the packet flags raised in every datagram of the session (sub-blocks, timestamps, envelope...)
+    pub flags: u8,
+    /// channels streamed in turn, 0 - no session
+    pub channels: u8,
+}
+
+impl StreamFormat {
+    /// writes the prefix into the beginning of `buf`, returns its size:
+    /// | version: u8 | endian: u8 | sample bytes: u8 | format: u8 | flags: u8 | channels: u8 |
+    pub fn write(&self, buf: &mut [u8]) -> usize {
+        buf[0] = STREAM_FORMAT_VERSION;
+        buf[1] = match self.format.littleEndian {
+            true => LITTLE_ENDIAN,
+            false => BIG_ENDIAN,
+        };
+        buf[2] = match self.format.byte {
+            true => 1,
+            false => SAMPLE_BYTES,
+        };
+        buf[3] = self.format.flags();
+        buf[4] = self.flags;
+        buf[5] = self.channels;
+        STREAM_FORMAT_SIZE
+    }
+}
+
+/// Blocks layout of the datagram
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(test), derive(defmt::Format))]
+pub struct Layout {
+    /// samples per block in the datagram, the page size if the logical block is paged
+    pub blockSamples: usize,
+    pub blocks: usize,
+    /// samples of the logical block, more than `blockSamples` if it's acquired and sent in pages
+    pub logicalSamples: usize,
+}
+
+impl Layout {
+    /// `blockSamples` - requested samples per block, 0 - `maxSamples`,
+    /// more than `maxSamples` - the block is paged, one page of `maxSamples` per datagram,
+    /// `requested` - requested blocks per datagram, 0 - as many as needed to reach `minBytes`,
+    /// `maxSamples` - size of the sample buffer, `contents` - the other parts of the datagram,
+    /// `maxDatagram` - the UDP payload of the MTU, `capacity` - the datagram buffer
+    pub fn new(
+        blockSamples: usize,
+        requested: usize,
+        minBytes: usize,
+        maxSamples: usize,
+        contents: &Contents,
+        maxDatagram: usize,
+        capacity: usize,
+    ) -> Self {
+        let logicalSamples = match blockSamples {
+            0 => maxSamples,
+            _ => blockSamples,
+        };
+        let mut blockSamples = logicalSamples.min(maxSamples);
+        // the single block may exceed the MTU, never the buffer, the rest of it is paged
+        while blockSamples > 1 && contents.size(blockSamples, 1) > capacity {
+            blockSamples -= 1;
+        }
+        let blockBytes = blockSamples * contents.sampleSize();
+        let blocks = match requested {
+            _ if logicalSamples > blockSamples => 1,
+            0 => (minBytes + blockBytes - 1) / blockBytes,
+            _ => requested,
+        };
+        let mut blocks = blocks.min(MAX_BLOCKS).min(maxSamples / blockSamples).max(1);
+        while blocks > 1 && contents.size(blockSamples, blocks) > maxDatagram {
+            blocks -= 1;
+        }
+        Self {
+            blockSamples,
+            blocks,
+            logicalSamples,
+        }
+    }
+    /// total samples in the datagram
+    pub fn samples(&self) -> usize {
+        self.blockSamples * self.blocks
+    }
+    /// datagrams carrying one logical block, 1 - not paged
+    pub fn pages(&self) -> usize {
+        (self.logicalSamples + self.blockSamples - 1) / self.blockSamples
+    }
+    /// samples in the datagram carrying the `page` of the logical block, the last page may be shorter
+    pub fn pageSamples(&self, page: usize) -> usize {
+        match self.pages() {
+            1 => self.samples(),
+            _ => self.blockSamples.min(self.logicalSamples - page * self.blockSamples),
+        }
+    }
+    /// the layout of the same blocks per datagram with the unpaged blocks of `samples`, see `maxResized`
+    pub fn resized(&self, samples: usize) -> Self {
+        Self { blockSamples: samples, blocks: self.blocks, logicalSamples: samples }
+    }
+    /// the largest block `resized` takes: the blocks fit the buffer of `maxSamples`
+    /// and the datagram with the `contents` fits `maxDatagram`
+    pub fn maxResized(&self, maxSamples: usize, contents: &Contents, maxDatagram: usize) -> usize {
+        let mut samples = maxSamples / self.blocks;
+        while samples > 1 && contents.size(samples, self.blocks) > maxDatagram {
+            samples -= 1;
+        }
+        samples
+    }
+    /// return true if the blocks are prefixed with sub-headers
+    pub fn hasSubheaders(&self) -> bool {
+        self.blocks > 1
+    }
+}
+
+/// Sample wire format selected by the handshake
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(not(test), derive(defmt::Format))]
+pub struct SampleFormat {
+    pub signed: bool,
+    pub timestamps: bool,
+    pub baseStamp: bool,
+    pub sampleIndex: bool,
+    pub ascii: bool,
+    pub millivolts: bool,
+    pub byte: bool,
+    /// the 16 bit samples are little endian, negotiated by the capabilities, not in the flags, see `negotiate`
+    pub littleEndian: bool,
+}
+
+impl SampleFormat {
+    pub fn fromFlags(flags: u8) -> Self {
+        Self {
+            signed: flags & FORMAT_SIGNED != 0,
+            timestamps: flags & FORMAT_TIMESTAMPS != 0,
+            baseStamp: flags & FORMAT_BASE_STAMP != 0,
+            sampleIndex: flags & FORMAT_SAMPLE_INDEX != 0,
+            ascii: cfg!(feature = "debug-ascii") && flags & FORMAT_ASCII != 0,
+            millivolts: flags & FORMAT_MILLIVOLTS != 0,
+            byte: flags & FORMAT_BYTE != 0,
+            littleEndian: false,
+        }
+    }
+    pub fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.signed {
+            flags |= FORMAT_SIGNED;
+        }
+        if self.timestamps {
+            flags |= FORMAT_TIMESTAMPS;
+        }
+        if self.baseStamp {
+            flags |= FORMAT_BASE_STAMP;
+        }
+        if self.sampleIndex {
+            flags |= FORMAT_SAMPLE_INDEX;
+        }
+        if self.ascii {
+            flags |= FORMAT_ASCII;
+        }
+        if self.millivolts {
+            flags |= FORMAT_MILLIVOLTS;
+        }
+        if self.byte {
+            flags |= FORMAT_BYTE;
+        }
+        flags
+    }
+    /// the count reported as zero: the tare `offset` if any, otherwise
+    /// the mid-scale for the signed samples and 0 for the unsigned ones
+    pub fn zero(&self, midScale: u16, offset: Option<u16>) -> u16 {
+        match (offset, self.signed) {
+            (Some(offset), _) => offset,
+            (None, true) => midScale,
+            (None, false) => 0,
+        }
+    }
+}
+
+/// the sample encoding of `CAP_PRIORITY` first supported by the `client` and the `board`, `CAP_WORD_BE` if none
+pub fn negotiate(client: u8, board: u8) -> u8 {
+    let mutual = client & board & CAP_SUPPORTED;
+    CAP_PRIORITY.into_iter().find(|cap| mutual & cap != 0).unwrap_or(CAP_WORD_BE)
+}
+
+/// writes the `samples` in the `format` into `buf`, returns the number of written bytes,
+/// `zero` - the count subtracted from the samples, see `SampleFormat::zero`,
+/// `gain` - the multiplier applied after, the results out of the format range are clipped,
+/// the samples not fitting `buf` as a whole are left out (never the case with the `Layout` fitted datagram)
+pub fn pack(samples: &[u16], format: SampleFormat, zero: u16, gain: u16, buf: &mut [u8]) -> usize {
+    let samples = &samples[..samples.len().min(buf.len() / 2)];
+    for (i, sample) in samples.iter().enumerate() {
+        buf[i * 2..i * 2 + 2].copy_from_slice(&encodeWord(*sample, format, zero, gain));
+    }
+    samples.len() * 2
+}
+
+/// writes the `samples` one byte each into `buf` as `pack` would, shifted right by `shift`
+/// (the full scale bits above 8) and clipped to `u8` (`i8` if signed), returns the number of written bytes,
+/// see `FORMAT_BYTE`
+pub fn packBytes(samples: &[u16], format: SampleFormat, zero: u16, gain: u16, shift: u32, buf: &mut [u8]) -> usize {
+    let samples = &samples[..samples.len().min(buf.len())];
+    for (byte, sample) in buf.iter_mut().zip(samples.iter()) {
+        *byte = encodeByte(*sample, format, zero, gain, shift);
+    }
+    samples.len()
+}
+
+/// writes the raw `samples` (unsigned, zero 0, unity gain - as `pack` would) into `buf`,
+/// returns the number of written bytes. The big endian samples are swapped in place a word (two samples)
+/// per `REV16` and copied out at once instead of encoding them one by one, they are left big endian,
+/// the `littleEndian` ones are copied as they are
+pub fn packRaw(samples: &mut [u16], littleEndian: bool, buf: &mut [u8]) -> usize {
+    let count = samples.len().min(buf.len() / 2);
+    let samples = &mut samples[..count];
+    if !littleEndian {
+        let (head, words, tail) = unsafe { samples.align_to_mut::<u32>() };
+        for word in words.iter_mut() {
+            *word = rev16(*word);
+        }
+        for sample in head.iter_mut().chain(tail.iter_mut()) {
+            *sample = sample.swap_bytes();
+        }
+    }
+    let len = samples.len() * 2;
+    let bytes = unsafe { core::slice::from_raw_parts(samples.as_ptr() as *const u8, len) };
+    buf[..len].copy_from_slice(bytes);
+    len
+}
+
+/// swaps the bytes of both halves of the `word`, the single `REV16` on the Cortex-M
+#[inline(always)]
+fn rev16(word: u32) -> u32 {
+    #[cfg(target_arch = "arm")]
+    unsafe {
+        let swapped;
+        core::arch::asm!("rev16 {0}, {1}", out(reg) swapped, in(reg) word, options(pure, nomem, nostack));
+        swapped
+    }
+    #[cfg(not(target_arch = "arm"))]
+    {
+        ((word & 0x00FF_00FF) << 8) | ((word >> 8) & 0x00FF_00FF)
+    }
+}
+
+/// writes the `| min | max |` pair of each `group` of the `samples` into `buf` as `pack` does
+/// (as `packBytes` does with the `byteShift`), the last group may be shorter, returns the number of written bytes,
+/// the pairs not fitting `buf` are left out
+pub fn packEnvelope(
+    samples: &[u16],
+    group: usize,
+    format: SampleFormat,
+    zero: u16,
+    gain: u16,
+    byteShift: Option<u32>,
+    buf: &mut [u8],
+) -> usize {
+    let pairSize = if byteShift.is_some() { 2 } else { 4 };
+    let mut len = 0;
+    for chunk in samples.chunks(group).take(buf.len() / pairSize) {
+        let (min, max) = chunk.iter().fold((u16::MAX, 0), |(min, max), sample| (min.min(*sample), max.max(*sample)));
+        // the scaling is monotonic, the encoded pair keeps the order
+        match byteShift {
+            Some(shift) => {
+                buf[len] = encodeByte(min, format, zero, gain, shift);
+                buf[len + 1] = encodeByte(max, format, zero, gain, shift);
+            }
+            None => {
+                buf[len..len + 2].copy_from_slice(&encodeWord(min, format, zero, gain));
+                buf[len + 2..len + 4].copy_from_slice(&encodeWord(max, format, zero, gain));
+            }
+        }
+        len += pairSize;
+    }
+    len
+}
+
+/// writes the `| rms | peak-to-peak |` summary of the `samples` into `buf`, returns the number of written bytes,
+/// the RMS is taken relative to the `zero`, both values are scaled by the `gain` and clipped to `u16`
+pub fn packSummary(samples: &[u16], zero: u16, gain: u16, buf: &mut [u8]) -> usize {
+    let (min, max, squares) = samples.iter().fold((u16::MAX, 0, 0u64), |(min, max, squares), sample| {
+        let value = *sample as i64 - zero as i64;
+        (min.min(*sample), max.max(*sample), squares + (value * value) as u64)
+    });
+    let rms = isqrt(squares / samples.len().max(1) as u64);
+    let peakToPeak = max.saturating_sub(min) as u64;
+    buf[0..2].copy_from_slice(&scale(rms, gain).to_be_bytes());
+    buf[2..4].copy_from_slice(&scale(peakToPeak, gain).to_be_bytes());
+    SUMMARY_SIZE
+}
+
+/// the non-negative `value` multiplied by the `gain`, clipped to `u16`
+fn scale(value: u64, gain: u16) -> u16 {
+    ((value * gain as u64) >> GAIN_SHIFT).min(u16::MAX as u64) as u16
+}
+
+/// the integer square root, rounded down
+fn isqrt(value: u64) -> u64 {
+    let mut rest = value;
+    let mut root = 0;
+    let mut bit = 1u64 << 62;
+    while bit > rest {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rest >= root + bit {
+            rest -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+/// the `sample` in the `format`, see `pack`
+fn encode(sample: u16, format: SampleFormat, zero: u16, gain: u16) -> [u8; 2] {
+    let value = sample as i64 - zero as i64;
+    let value = match gain {
+        UNITY_GAIN => value,
+        // the summed or left aligned counts (up to ~65520) times any u16 gain overflow i32
+        _ => (value * gain as i64) >> GAIN_SHIFT,
+    };
+    match format.signed {
+        true => (value.clamp(i16::MIN as i64, i16::MAX as i64) as i16).to_be_bytes(),
+        false => (value.clamp(0, u16::MAX as i64) as u16).to_be_bytes(),
+    }
+}
+
+/// the `sample` in the `format` of two bytes in its byte order
+fn encodeWord(sample: u16, format: SampleFormat, zero: u16, gain: u16) -> [u8; 2] {
+    let mut encoded = encode(sample, format, zero, gain);
+    if format.littleEndian {
+        encoded.reverse();
+    }
+    encoded
+}
+
+/// the `sample` in the `format` of one byte, see `packBytes`
+fn encodeByte(sample: u16, format: SampleFormat, zero: u16, gain: u16, shift: u32) -> u8 {
+    let encoded = encode(sample, format, zero, gain);
+    match format.signed {
+        true => (i16::from_be_bytes(encoded) >> shift).clamp(i8::MIN as i16, i8::MAX as i16) as i8 as u8,
+        false => (u16::from_be_bytes(encoded) >> shift).min(u8::MAX as u16) as u8,
+    }
+}
+
+/// writes the text line `seq,channel,sample...\n` of the `samples` encoded as `pack` does into `buf`,
+/// returns its length, the samples not fitting `buf` at their widest are left out, see `FORMAT_ASCII`
+pub fn packAscii(
+    seq: u32,
+    channel: u8,
+    samples: &[u16],
+    format: SampleFormat,
+    zero: u16,
+    gain: u16,
+    buf: &mut [u8],
+) -> usize {
+    let mut len = writeDecimal(seq as i64, buf);
+    buf[len] = b',';
+    len += 1;
+    len += writeDecimal(channel as i64, &mut buf[len..]);
+    let fitting = buf.len().saturating_sub(ASCII_HEADER_SIZE) / ASCII_SAMPLE_SIZE;
+    for sample in samples.iter().take(fitting) {
+        buf[len] = b',';
+        len += 1;
+        let encoded = encode(*sample, format, zero, gain);
+        let value = match format.signed {
+            true => i16::from_be_bytes(encoded) as i64,
+            false => u16::from_be_bytes(encoded) as i64,
+        };
+        len += writeDecimal(value, &mut buf[len..]);
+    }
+    buf[len] = b'\n';
+    len + 1
+}
+
+/// writes the `value` in decimal into `buf`, returns the number of written digits (with the sign)
+fn writeDecimal(value: i64, buf: &mut [u8]) -> usize {
+    let mut digits = [0u8; 20];
+    let mut rest = value.unsigned_abs();
+    let mut count = 0;
+    loop {
+        digits[count] = b'0' + (rest % 10) as u8;
+        count += 1;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    let sign = (value < 0) as usize;
+    if value < 0 {
+        buf[0] = b'-';
+    }
+    for (i, digit) in digits[..count].iter().rev().enumerate() {
+        buf[sign + i] = *digit;
+    }
+    sign + count
+}
+
+/// number of the timestamps taken for the `samples` of the datagram
+pub fn stampCount(samples: usize) -> usize {
+    (samples + TIMESTAMP_STRIDE - 1) / TIMESTAMP_STRIDE
+}
+
+/// writes the `stamps` into `buf`, returns the number of written bytes
+pub fn packStamps(stamps: &[u32], buf: &mut [u8]) -> usize {
+    for (i, stamp) in stamps.iter().enumerate() {
+        buf[i * 4..i * 4 + 4].copy_from_slice(&stamp.to_be_bytes());
+    }
+    stamps.len() * 4
+}
+
+/// writes the mux positions of the `samples` in the blocks of `blockSamples` into `buf`, returns the number
+/// of written bytes, `starts` - the position of the first sample of each block (`MUX_NONE` - not muxed),
+/// the consecutive samples cycle over the `channels` positions
+pub fn packMuxPositions(starts: &[u8], blockSamples: usize, samples: usize, channels: u8, buf: &mut [u8]) -> usize {
+    for (i, position) in buf[..samples].iter_mut().enumerate() {
+        *position = match starts[i / blockSamples] {
+            MUX_NONE => MUX_NONE,
+            start => ((start as usize + i % blockSamples) % channels.max(1) as usize) as u8,
+        };
+    }
+    samples
+}
+
+/// writes the injected `channel` and its `samples` into `buf`, returns the number of written bytes
+pub fn packInjected(channel: u8, samples: &[u16], buf: &mut [u8]) -> usize {
+    buf[0] = channel;
+    for (i, sample) in samples.iter().enumerate() {
+        buf[1 + i * 2..3 + i * 2].copy_from_slice(&sample.to_be_bytes());
+    }
+    1 + samples.len() * 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the samples starting at the word boundary, so the slice from the odd index has the unaligned head
+    #[repr(align(4))]
+    struct Aligned([u16; 8]);
+
+    const SAMPLES: [u16; 8] = [0x0102, 0x0304, 0x0506, 0x0708, 0x090A, 0x0B0C, 0x0D0E, 0x0F10];
+
+    fn bigEndian(samples: &[u16]) -> Vec<u8> {
+        samples.iter().flat_map(|sample| sample.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn rev16_swaps_both_halves() {
+        assert_eq!(rev16(0x0102_0304), 0x0201_0403);
+        assert_eq!(rev16(rev16(0xDEAD_BEEF)), 0xDEAD_BEEF);
+    }
+
+    #[test]
+    fn pack_raw_after_odd_header_is_big_endian() {
+        let mut samples = Aligned(SAMPLES);
+        let mut buf = [0xAA; HEADER_SIZE + 16];
+        let len = packRaw(&mut samples.0, false, &mut buf[HEADER_SIZE..]);
+        assert_eq!(len, 16);
+        assert_eq!(&buf[HEADER_SIZE..], &bigEndian(&SAMPLES)[..]);
+        assert!(buf[..HEADER_SIZE].iter().all(|byte| *byte == 0xAA));
+    }
+
+    #[test]
+    fn pack_raw_unaligned_head_and_tail_fall_back_to_swap() {
+        let mut samples = Aligned(SAMPLES);
+        // from the odd sample: one sample in the head, two words, one sample in the tail
+        let unaligned = &mut samples.0[1..7];
+        let (head, words, tail) = unsafe { unaligned.align_to_mut::<u32>() };
+        assert_eq!((head.len(), words.len(), tail.len()), (1, 2, 1));
+        let mut buf = [0; HEADER_SIZE + 12];
+        let len = packRaw(unaligned, false, &mut buf[HEADER_SIZE..]);
+        assert_eq!(len, 12);
+        assert_eq!(&buf[HEADER_SIZE..], &bigEndian(&SAMPLES[1..7])[..]);
+    }
+
+    #[test]
+    fn pack_raw_matches_pack() {
+        let mut samples = Aligned(SAMPLES);
+        let mut packed = [0; 14];
+        pack(&SAMPLES[1..], SampleFormat::default(), 0, UNITY_GAIN, &mut packed);
+        let mut raw = [0; 14];
+        packRaw(&mut samples.0[1..], false, &mut raw);
+        assert_eq!(raw, packed);
+    }
+
+    #[test]
+    fn pack_raw_little_endian_is_copied() {
+        let mut samples = Aligned(SAMPLES);
+        let mut buf = [0; HEADER_SIZE + 16];
+        packRaw(&mut samples.0, true, &mut buf[HEADER_SIZE..]);
+        let expected: Vec<u8> = SAMPLES.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        assert_eq!(&buf[HEADER_SIZE..], &expected[..]);
+        assert_eq!(samples.0, SAMPLES);
+    }
+
+    #[test]
+    fn pack_raw_fits_the_buffer() {
+        let mut samples = Aligned(SAMPLES);
+        let mut buf = [0; 7];
+        assert_eq!(packRaw(&mut samples.0, false, &mut buf), 6);
+        assert_eq!(&buf[..6], &bigEndian(&SAMPLES[..3])[..]);
+    }
+
+    #[test]
+    fn pack_byte_order() {
+        let mut buf = [0; HEADER_SIZE + 4];
+        let format = SampleFormat::default();
+        assert_eq!(pack(&[0x0123, 0x4567], format, 0, UNITY_GAIN, &mut buf[HEADER_SIZE..]), 4);
+        assert_eq!(&buf[HEADER_SIZE..], &[0x01, 0x23, 0x45, 0x67]);
+        let format = SampleFormat { littleEndian: true, ..format };
+        pack(&[0x0123, 0x4567], format, 0, UNITY_GAIN, &mut buf[HEADER_SIZE..]);
+        assert_eq!(&buf[HEADER_SIZE..], &[0x23, 0x01, 0x67, 0x45]);
+    }
+
+    #[test]
+    fn pack_zero_gain_and_clipping() {
+        let signed = SampleFormat { signed: true, ..SampleFormat::default() };
+        let mut buf = [0; 6];
+        pack(&[2048, 1048, 65535], signed, 2048, UNITY_GAIN, &mut buf);
+        assert_eq!(buf, [0, 0, 0xFC, 0x18, 0x7F, 0xFF]);
+        // the gain of 2 over the full scale clips, never wraps
+        pack(&[100, 40000, 0], SampleFormat::default(), 0, UNITY_GAIN * 2, &mut buf);
+        assert_eq!(buf, [0, 200, 0xFF, 0xFF, 0, 0]);
+        // the sample below the zero is clipped to 0 unsigned
+        pack(&[10], SampleFormat::default(), 20, UNITY_GAIN, &mut buf);
+        assert_eq!(&buf[..2], &[0, 0]);
+    }
+
+    #[test]
+    fn pack_leaves_out_samples_not_fitting() {
+        let mut buf = [0; 5];
+        assert_eq!(pack(&SAMPLES, SampleFormat::default(), 0, UNITY_GAIN, &mut buf), 4);
+    }
+
+    #[test]
+    fn pack_bytes_shift_and_clip() {
+        let mut buf = [0; 3];
+        assert_eq!(packBytes(&[0x0FF0, 0x0010, 0xFFFF], SampleFormat::default(), 0, UNITY_GAIN, 4, &mut buf), 3);
+        assert_eq!(buf, [0xFF, 0x01, 0xFF]);
+        let signed = SampleFormat { signed: true, ..SampleFormat::default() };
+        packBytes(&[4096 + 160, 96, 4096], signed, 4096, UNITY_GAIN, 4, &mut buf);
+        assert_eq!(buf, [10, i8::MIN as u8, 0]);
+    }
+
+    #[test]
+    fn pack_envelope_pairs() {
+        let mut buf = [0; 8];
+        let len = packEnvelope(&[5, 1, 9, 7, 3], 3, SampleFormat::default(), 0, UNITY_GAIN, None, &mut buf);
+        assert_eq!(len, 8);
+        assert_eq!(buf, [0, 1, 0, 9, 0, 3, 0, 7]);
+        let len = packEnvelope(&[5, 1, 9, 7, 3], 3, SampleFormat::default(), 0, UNITY_GAIN, Some(0), &mut buf);
+        assert_eq!((len, &buf[..4]), (4, &[1, 9, 3, 7][..]));
+    }
+
+    #[test]
+    fn pack_summary_rms_and_peak_to_peak() {
+        let mut buf = [0; SUMMARY_SIZE];
+        packSummary(&[97, 103, 97, 103], 100, UNITY_GAIN, &mut buf);
+        assert_eq!(buf, [0, 3, 0, 6]);
+        packSummary(&[], 0, UNITY_GAIN, &mut buf);
+        assert_eq!(buf, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn isqrt_rounds_down() {
+        let cases = [(0, 0), (1, 1), (2, 1), (3, 1), (4, 2), (15, 3), (16, 4), (17, 4), (1 << 32, 1 << 16)];
+        for (value, root) in cases {
+            assert_eq!(isqrt(value), root, "isqrt({})", value);
+        }
+        assert_eq!(isqrt(u64::MAX), u32::MAX as u64);
+    }
+
+    #[test]
+    fn negotiate_picks_supported_priority() {
+        assert_eq!(negotiate(CAP_WORD_BE | CAP_WORD_LE | CAP_PACKED12, CAP_SUPPORTED), CAP_WORD_LE);
+        assert_eq!(negotiate(CAP_PACKED12, CAP_SUPPORTED), CAP_WORD_BE);
+        assert_eq!(negotiate(CAP_WORD_LE, CAP_WORD_BE), CAP_WORD_BE);
+        assert_eq!(negotiate(0, CAP_SUPPORTED), CAP_WORD_BE);
+    }
+
+    #[test]
+    fn write_decimal() {
+        let mut buf = [0; 20];
+        for (value, text) in [(0, "0"), (7, "7"), (-1, "-1"), (1234567890, "1234567890"), (-42, "-42")] {
+            let len = writeDecimal(value, &mut buf);
+            assert_eq!(&buf[..len], text.as_bytes());
+        }
+        let len = writeDecimal(i64::MIN, &mut buf);
+        assert_eq!(&buf[..len], b"-9223372036854775808");
+    }
+
+    #[test]
+    fn layout_whole_buffer_by_default() {
+        let layout = Layout::new(0, 0, 1000, 512, &Contents::default(), 1472, 1472);
+        assert_eq!(layout, Layout { blockSamples: 512, blocks: 1, logicalSamples: 512 });
+        assert_eq!(layout.pages(), 1);
+        assert!(!layout.hasSubheaders());
+    }
+
+    #[test]
+    fn layout_blocks_reach_min_bytes() {
+        let layout = Layout::new(100, 0, 1000, 4096, &Contents::default(), 1472, 1472);
+        assert_eq!(layout, Layout { blockSamples: 100, blocks: 5, logicalSamples: 100 });
+        assert!(layout.hasSubheaders());
+        assert_eq!(layout.samples(), 500);
+    }
+
+    #[test]
+    fn layout_blocks_fit_the_datagram() {
+        let contents = Contents::default();
+        let layout = Layout::new(100, 40, 0, 4096, &contents, 1472, 1472);
+        assert_eq!(layout.blocks, 7);
+        assert!(contents.size(100, 7) <= 1472 && contents.size(100, 8) > 1472);
+    }
+
+    #[test]
+    fn layout_pages_the_large_block() {
+        let layout = Layout::new(2000, 4, 0, 4096, &Contents::default(), 1472, 1472);
+        assert_eq!(layout, Layout { blockSamples: 732, blocks: 1, logicalSamples: 2000 });
+        assert_eq!(layout.pages(), 3);
+        assert_eq!((layout.pageSamples(0), layout.pageSamples(2)), (732, 536));
+    }
+}
@@ -0,0 +1,139 @@
+//! Ethernet PHY (LAN8742A on the Nucleo-F767ZI) with the optional forcing of 100M full duplex
+//!
+//! `GenericSMI` relies on the auto-negotiation, some switches negotiate poorly
+//! leaving the link at 10M half duplex, while the MAC is always configured for 100M full duplex.
+//! When forcing is enabled and the negotiated mode differs, the PHY is switched to 100M full duplex,
+//! if the link doesn't come up in the forced mode within `FORCE_TIMEOUT`
+//! the auto-negotiation is restored and never forced again.
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::Context;
+
+use defmt::*;
+use embassy_stm32::eth::generic_smi::GenericSMI;
+use embassy_stm32::eth::{StationManagement, PHY};
+use embassy_time::{Duration, Instant};
+
+/// basic control register
+const PHY_REG_BCR: u8 = 0x00;
+/// special control / status register
+const PHY_REG_PSCSR: u8 = 0x1F;
+const PHY_REG_BCR_SPEED_100: u16 = 1 << 13;
+const PHY_REG_BCR_AN: u16 = 1 << 12;
+const PHY_REG_BCR_AN_RESTART: u16 = 1 << 9;
+const PHY_REG_BCR_FULL_DUPLEX: u16 = 1 << 8;
+const PHY_REG_PSCSR_SPEED_SHIFT: u16 = 2;
+const PHY_REG_PSCSR_SPEED_MASK: u16 = 0b111;
+
+/// how long to wait the link in the forced mode before restoring the auto-negotiation
+const FORCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+#[repr(u8)]
+pub enum LinkMode {
+    Unknown = 0,
+    Half10 = 1,
+    Full10 = 2,
+    Half100 = 3,
+    Full100 = 4,
+}
+
+impl LinkMode {
+    /// reads the current speed / duplex from the PHY
+    fn read<S: StationManagement>(sm: &mut S) -> Self {
+        let pscsr = sm.smi_read(PHY_REG_PSCSR);
+        match (pscsr >> PHY_REG_PSCSR_SPEED_SHIFT) & PHY_REG_PSCSR_SPEED_MASK {
+            0b001 => LinkMode::Half10,
+            0b101 => LinkMode::Full10,
+            0b010 => LinkMode::Half100,
+            0b110 => LinkMode::Full100,
+            _ => LinkMode::Unknown,
+        }
+    }
+    fn fromU8(value: u8) -> Self {
+        match value {
+            1 => LinkMode::Half10,
+            2 => LinkMode::Full10,
+            3 => LinkMode::Half100,
+            4 => LinkMode::Full100,
+            _ => LinkMode::Unknown,
+        }
+    }
+}
+
+/// the mode of the current link, Unknown while the link is down
+static LINK_MODE: AtomicU8 = AtomicU8::new(LinkMode::Unknown as u8);
+
+/// returns the negotiated (or forced) mode of the current link
+pub fn linkMode() -> LinkMode {
+    LinkMode::fromU8(LINK_MODE.load(Ordering::Relaxed))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Force {
+    Auto,
+    Forced(Instant),
+    Kept,
+    Failed,
+}
+
+/// `GenericSMI` logging the link mode at link-up and forcing 100M full duplex if enabled
+pub struct ForcedPhy {
+    inner: GenericSMI,
+    enabled: bool,
+    force: Force,
+    linkUp: bool,
+}
+
+impl ForcedPhy {
+    /// `force100Full` - force 100M full duplex if the auto-negotiation yields the other mode
+    pub fn new(force100Full: bool) -> Self {
+        Self {
+            inner: GenericSMI,
+            enabled: force100Full,
+            force: Force::Auto,
+            linkUp: false,
+        }
+    }
+}
+
+unsafe impl PHY for ForcedPhy {
+    fn phy_reset<S: StationManagement>(&mut self, sm: &mut S) {
+        self.inner.phy_reset(sm)
+    }
+
+    fn phy_init<S: StationManagement>(&mut self, sm: &mut S) {
+        self.inner.phy_init(sm)
+    }
+
+    fn poll_link<S: StationManagement>(&mut self, sm: &mut S, cx: &mut Context) -> bool {
+        let up = self.inner.poll_link(sm, cx);
+        if up && !self.linkUp {
+            let mode = LinkMode::read(sm);
+            LINK_MODE.store(mode as u8, Ordering::Relaxed);
+            info!("[phy] link up: {:?}", mode);
+            if let Force::Forced(_) = self.force {
+                self.force = Force::Kept;
+            }
+            if self.enabled && self.force == Force::Auto && mode != LinkMode::Full100 {
+                warn!("[phy] negotiated {:?}, forcing 100M full duplex", mode);
+                sm.smi_write(PHY_REG_BCR, PHY_REG_BCR_SPEED_100 | PHY_REG_BCR_FULL_DUPLEX);
+                self.force = Force::Forced(Instant::now());
+            }
+        }
+        if !up {
+            if self.linkUp {
+                info!("[phy] link down");
+                LINK_MODE.store(LinkMode::Unknown as u8, Ordering::Relaxed);
+            }
+            if let Force::Forced(since) = self.force {
+                if Instant::now().duration_since(since) > FORCE_TIMEOUT {
+                    warn!("[phy] no link in forced mode, falling back to auto-negotiation");
+                    sm.smi_write(PHY_REG_BCR, PHY_REG_BCR_AN | PHY_REG_BCR_AN_RESTART);
+                    self.force = Force::Failed;
+                }
+            }
+        }
+        self.linkUp = up;
+        up
+    }
+}
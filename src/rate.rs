@@ -0,0 +1,98 @@
+//! Sample rate measured from the datagram stamps, reported by `STATS`
+//!
+//! Each datagram of the session records the timestamp counter at its first sample and its samples,
+//! the rate is the samples of the recent `WINDOW` datagrams over the ticks from the first of them to the next one,
+//! so it's the achieved rate including the gaps between the datagrams (the blocking acquisition pauses
+//! while sending), the slow conversions or the missed external trigger edges show up as the lower rate.
+//! Integer math, one division per datagram.
+//! With the expected rate the deviation beyond the tolerance raises `FLAG_RATE_DEVIATION` in STATS
+//! and is logged once, the flag is cleared when the rate is back, checked once the window is full.
+//! The triggered datagrams start at the triggers, they aren't measured.
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use defmt::{info, warn};
+
+use crate::stats;
+use crate::timestamp::TICK_HZ;
+
+/// datagrams the rate is measured over
+pub const WINDOW: usize = 16;
+/// the ring holds the stamp of the datagram following the window
+const RING: usize = WINDOW + 1;
+
+/// the last measured rate, samples per second, 0 - not measured yet
+static RATE: AtomicU32 = AtomicU32::new(0);
+
+/// the last measured rate, samples per second, 0 - not measured in this session yet
+pub fn rate() -> u32 {
+    RATE.load(Ordering::Relaxed)
+}
+
+/// Sliding window of the recent datagrams of the session
+pub struct RateMeter {
+    stamps: [u32; RING],
+    samples: [u32; RING],
+    /// the slot of the next datagram
+    head: usize,
+    /// the datagrams in the ring
+    count: usize,
+    /// the expected rate, samples per second, None - not checked
+    expected: Option<u32>,
+    tolerancePpm: u32,
+    deviating: bool,
+}
+
+impl RateMeter {
+    /// starts the measurement of the session, the rate is checked against the `expected`
+    /// within the `tolerancePpm` parts per million
+    pub fn new(expected: Option<u32>, tolerancePpm: u32) -> Self {
+        RATE.store(0, Ordering::Relaxed);
+        stats::clearFlag(stats::FLAG_RATE_DEVIATION);
+        Self { stamps: [0; RING], samples: [0; RING], head: 0, count: 0, expected, tolerancePpm, deviating: false }
+    }
+    /// records the datagram of the `samples` started at the timestamp counter `stamp`
+    pub fn push(&mut self, stamp: u32, samples: usize) {
+        self.stamps[self.head] = stamp;
+        self.samples[self.head] = samples as u32;
+        self.head = (self.head + 1) % RING;
+        self.count = (self.count + 1).min(RING);
+        if self.count < 2 {
+            return;
+        }
+        let oldest = (self.head + RING - self.count) % RING;
+        let ticks = stamp.wrapping_sub(self.stamps[oldest]);
+        if ticks == 0 {
+            return;
+        }
+        // the samples of all but the just started datagram
+        let total: u64 = (0..self.count - 1).map(|i| self.samples[(oldest + i) % RING] as u64).sum();
+        let rate = (total * TICK_HZ as u64 / ticks as u64) as u32;
+        RATE.store(rate, Ordering::Relaxed);
+        if self.count == RING {
+            self.check(rate);
+        }
+    }
+    /// flags the `rate` deviating from the expected one, logs the change
+    fn check(&mut self, rate: u32) {
+        let expected = match self.expected {
+            Some(expected) if expected > 0 => expected,
+            _ => return,
+        };
+        let deviationPpm = (rate as i64 - expected as i64).unsigned_abs() * 1_000_000 / expected as u64;
+        let deviating = deviationPpm > self.tolerancePpm as u64;
+        if deviating == self.deviating {
+            return;
+        }
+        self.deviating = deviating;
+        match deviating {
+            true => {
+                warn!("sample rate {} S/s deviates from {} S/s by {} ppm", rate, expected, deviationPpm);
+                stats::setFlag(stats::FLAG_RATE_DEVIATION);
+            }
+            false => {
+                info!("sample rate {} S/s is back within {} ppm", rate, self.tolerancePpm);
+                stats::clearFlag(stats::FLAG_RATE_DEVIATION);
+            }
+        }
+    }
+}
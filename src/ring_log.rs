@@ -0,0 +1,101 @@
+//! Recent events kept in RAM for the post-mortem, read back by the `LOG` command
+//!
+//! defmt sends its logs encoded to the probe, the headless board in the field has none, so the notable events
+//! (the session start and end, the faults and the errors) are also formatted as text into the ring
+//! of the last `CAPACITY` lines, the oldest line is overwritten when it's full. The line longer than `LINE_SIZE`
+//! bytes is cut. The ring takes ~1.9 KB of `.bss`, it's lost on the reset.
+//! Each line has its running number, so the reader sees the overwritten lines by the gap:
+//! ```not_rust
+//! | seq: u32 | uptime: u32 (ms) | level: u8 | text: [u8; len] |
+//! ```
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+
+use crate::log_level::Level;
+
+/// lines kept
+pub const CAPACITY: usize = 32;
+/// the longest text of the line
+pub const LINE_SIZE: usize = 48;
+/// the largest line written by `read`
+pub const RECORD_SIZE: usize = 9 + LINE_SIZE;
+
+#[derive(Clone, Copy)]
+struct Line {
+    seq: u32,
+    uptimeMs: u32,
+    level: Level,
+    len: u8,
+    text: [u8; LINE_SIZE],
+}
+
+impl Line {
+    const EMPTY: Self = Self { seq: 0, uptimeMs: 0, level: Level::Info, len: 0, text: [0; LINE_SIZE] };
+}
+
+struct Ring {
+    lines: [Line; CAPACITY],
+    /// the number of the next line, the lines before it are in the ring down to `next - CAPACITY`
+    next: u32,
+}
+
+static RING: Mutex<CriticalSectionRawMutex, RefCell<Ring>> =
+    Mutex::new(RefCell::new(Ring { lines: [Line::EMPTY; CAPACITY], next: 0 }));
+
+/// The text of the line, the rest is cut
+struct Text {
+    buf: [u8; LINE_SIZE],
+    len: usize,
+}
+
+impl Write for Text {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(LINE_SIZE - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// appends the line formatted from `args`, e.g. `ring_log::push(Level::Warn, format_args!("..."))`,
+/// formatted before the ring is locked
+pub fn push(level: Level, args: fmt::Arguments<'_>) {
+    let mut text = Text { buf: [0; LINE_SIZE], len: 0 };
+    let _ = text.write_fmt(args);
+    let uptimeMs = Instant::now().as_millis() as u32;
+    RING.lock(|ring| {
+        let mut ring = ring.borrow_mut();
+        let seq = ring.next;
+        ring.lines[seq as usize % CAPACITY] = Line { seq, uptimeMs, level, len: text.len as u8, text: text.buf };
+        ring.next = seq.wrapping_add(1);
+    });
+}
+
+/// the number of the next line
+pub fn next() -> u32 {
+    RING.lock(|ring| ring.borrow().next)
+}
+
+/// writes the line `from` (the oldest kept one if it's overwritten) into `buf`,
+/// returns its length and the number of the line following it, None if there is no line from `from` on
+pub fn read(from: u32, buf: &mut [u8]) -> Option<(usize, u32)> {
+    RING.lock(|ring| {
+        let ring = ring.borrow();
+        let oldest = ring.next.saturating_sub(CAPACITY as u32);
+        let seq = from.max(oldest);
+        if seq >= ring.next {
+            return None;
+        }
+        let line = &ring.lines[seq as usize % CAPACITY];
+        let len = line.len as usize;
+        buf[0..4].copy_from_slice(&line.seq.to_be_bytes());
+        buf[4..8].copy_from_slice(&line.uptimeMs.to_be_bytes());
+        buf[8] = line.level as u8;
+        buf[9..9 + len].copy_from_slice(&line.text[..len]);
+        Some((9 + len, seq + 1))
+    })
+}
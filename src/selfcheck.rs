@@ -0,0 +1,110 @@
+//! Noise and accuracy self-check requested by the `SELFCHECK` command
+//!
+//! The board has no switch shorting the inputs to a known voltage, so the burst of `SAMPLES` conversions
+//! of the internal reference (VREFINT, ~1.21 V) is taken instead. Its factory calibration `VREFINT_CAL`
+//! (the counts measured at VDDA = 3.3 V) gives the supply the counts are relative to:
+//! `VDDA = 3300 mV * VREFINT_CAL / mean`. The spread of the burst is the noise of the ADC and the supply.
+//! The streamed channels and their tare offsets aren't touched, the stream pauses for the burst (~5 ms).
+//!
+//! The report is the reply payload:
+//! ```not_rust
+//! | samples: u16 | timeouts: u16 | mean: u32 | std dev: u32 | min: u16 | max: u16 | cal: u16 | vdda: u16 |
+//! ```
+//! samples - converted, timeouts - failed conversions, mean and std dev in 1/100 of the count,
+//! min / max / cal in counts, vdda in millivolts (0 - nothing converted).
+use defmt::{info, Format};
+
+use crate::inputs::Inputs;
+
+/// conversions of the burst
+pub const SAMPLES: u16 = 256;
+/// the VDDA of the factory calibration, mV
+const CAL_VDDA_MV: u32 = 3300;
+/// VREFINT_CAL, the reference counts at 3.3 V and 30 °C, 12 bit
+const VREFINT_CAL: *const u16 = 0x1FF0_F44A as *const u16;
+/// size of the written report
+pub const REPORT_SIZE: usize = 20;
+
+/// Statistics of the reference burst
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Format)]
+pub struct SelfCheckReport {
+    pub samples: u16,
+    pub timeouts: u16,
+    /// 1/100 of the count
+    pub mean: u32,
+    /// 1/100 of the count
+    pub stdDev: u32,
+    pub min: u16,
+    pub max: u16,
+    pub cal: u16,
+    /// the supply computed from the mean, 0 - unknown
+    pub vddaMv: u16,
+}
+
+impl SelfCheckReport {
+    /// writes the report into the beginning of `buf`, returns its size
+    pub fn write(&self, buf: &mut [u8]) -> usize {
+        buf[0..2].copy_from_slice(&self.samples.to_be_bytes());
+        buf[2..4].copy_from_slice(&self.timeouts.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.mean.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.stdDev.to_be_bytes());
+        buf[12..14].copy_from_slice(&self.min.to_be_bytes());
+        buf[14..16].copy_from_slice(&self.max.to_be_bytes());
+        buf[16..18].copy_from_slice(&self.cal.to_be_bytes());
+        buf[18..20].copy_from_slice(&self.vddaMv.to_be_bytes());
+        REPORT_SIZE
+    }
+}
+
+/// converts the burst of the internal reference, returns its statistics
+pub fn runSelfcheck(inputs: &mut Inputs) -> SelfCheckReport {
+    let cal = unsafe { VREFINT_CAL.read_volatile() };
+    let mut report = SelfCheckReport { min: u16::MAX, cal, ..Default::default() };
+    let (mut sum, mut sumSquares) = (0u64, 0u64);
+    inputs.startReference();
+    for _ in 0..SAMPLES {
+        match inputs.readReference() {
+            Some(value) => {
+                report.samples += 1;
+                report.min = report.min.min(value);
+                report.max = report.max.max(value);
+                sum += value as u64;
+                sumSquares += value as u64 * value as u64;
+            }
+            None => report.timeouts += 1,
+        }
+    }
+    inputs.stopReference();
+    let n = report.samples as u64;
+    if n == 0 {
+        report.min = 0;
+        return report;
+    }
+    report.mean = (sum * 100 / n) as u32;
+    // n² * variance, scaled by 100² for the hundredths of the std dev
+    let variance = (n * sumSquares - sum * sum) * 10_000 / (n * n);
+    report.stdDev = isqrt(variance) as u32;
+    report.vddaMv = (CAL_VDDA_MV as u64 * report.cal as u64 * n / sum.max(1)) as u16;
+    info!("[selfcheck] {:?}", report);
+    report
+}
+
+/// the integer square root, rounded down
+fn isqrt(value: u64) -> u64 {
+    let mut root = 0u64;
+    let mut bit = 1u64 << 62;
+    while bit > value {
+        bit >>= 2;
+    }
+    let mut rest = value;
+    while bit != 0 {
+        if rest >= root + bit {
+            rest -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
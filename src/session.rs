@@ -0,0 +1,407 @@
+//! Streaming session state
+//!
+//! The state of the ended session is kept for `RESUME_WINDOW`.
+//! The same client reconnected within the window with the resume handshake
+//! continues the sequence numbering instead of starting from zero,
+//! the blocks which would be produced while it was away are skipped
+//! and reported in the handshake acknowledgment,
+//! the sample index (see `FORMAT_SAMPLE_INDEX`) skips their samples as well.
+//!
+//! The data socket goes through the lifecycle driven by the single loop in `main`:
+//! ```not_rust
+//! Unbound --bound--> Idle --handshake--> Streaming --ended--> Idle
+//!    ^                 |                     |
+//!    +-----------------+--address changed----+
+//! ```
+//! The phase moved to is decided by `lifecycle::step` (host tested), `SessionState` carries the payloads,
+//! every transition of `main` goes through it. The bind error keeps it `Unbound`, the command received
+//! while streaming (new handshake, SET_IP) is carried to `Idle` to be handled there.
+//! The handshake arriving within `SESSION_COOLDOWN` of `main` after the session end waits for it to pass,
+//! so the datagrams of the ended session still in the TX buffer leave before the new acknowledgment.
+//! The socket is created once, entering `Unbound` closes it before binding again.
+//!
+//! `PAUSE` holds the running session without tearing it down: the datagrams aren't sent
+//! (with the acquisition halted or kept running), `RESUME` continues it, the sequence numbering goes on
+//! and the first datagram after the pause carries `FLAG_RESUMED`. With the acquisition kept running
+//! the datagrams of the pause are dropped, their sequence numbers are skipped, so the gap shows in the sequence.
+//! The pause longer than `PAUSE_TIMEOUT` of `main` is taken as the client gone, the session ends
+//! (it can be resumed by the resume handshake as any ended one).
+//!
+//! `SET_BLOCK_SIZE` changes the samples per block of the running session from the next logical block,
+//! the block being acquired is completed first. The blocks per datagram are kept, the new block is never paged
+//! or fragmented: it's accepted within `1..=blockLimit()` published at the session start (the sample buffer
+//! and the MTU with the parts of the session datagram).
+//!
+//! The client endpoint is validated before the session starts sending to it (see `isValidClient`, `client`):
+//! the handshake from the unspecified, broadcast or multicast address or the zero port
+//! (the corrupted autostart client too) is dropped, logged once.
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering};
+
+use defmt::{error, warn, Format};
+use embassy_net::udp::UdpSocket;
+use embassy_net::{IpAddress, IpEndpoint};
+use embassy_time::{Duration, Instant};
+
+use crate::client;
+use crate::control::{Command, Handshake};
+use crate::lifecycle::{self, Event, Phase};
+use crate::packet::{PacketHeader, StreamFormat, FLAG_RESUMED};
+
+/// how long the ended session can be resumed
+pub const RESUME_WINDOW: Duration = Duration::from_secs(10);
+
+/// the invalid client is logged
+static INVALID_CLIENT_LOGGED: AtomicBool = AtomicBool::new(false);
+
+/// return true if the data can be sent to the `client`: the unicast address and the non-zero port,
+/// the first invalid one is logged
+pub fn isValidClient(client: IpEndpoint) -> bool {
+    let valid = match client.addr {
+        IpAddress::Ipv4(addr) => client::isValid(addr.as_bytes(), client.port),
+        #[cfg(feature = "ipv6")]
+        IpAddress::Ipv6(addr) => client::isValid(addr.as_bytes(), client.port),
+        #[allow(unreachable_patterns)]
+        _ => false,
+    };
+    if !valid && !INVALID_CLIENT_LOGGED.swap(true, Ordering::Relaxed) {
+        warn!("invalid client {:?} dropped, the further ones aren't logged", client);
+    }
+    valid
+}
+
+/// no units switch is requested
+const UNITS_KEPT: u8 = 0xFF;
+/// the units requested by `SET_UNITS`, switched by the streaming loop at the next datagram
+static UNITS: AtomicU8 = AtomicU8::new(UNITS_KEPT);
+
+/// requests the switch of the running session to the millivolts (true) or the counts from the next datagram
+pub fn requestUnits(millivolts: bool) {
+    UNITS.store(millivolts as u8, Ordering::Relaxed);
+}
+
+/// the units requested since the last call if any, true - millivolts
+pub fn takeUnits() -> Option<bool> {
+    match UNITS.swap(UNITS_KEPT, Ordering::Relaxed) {
+        UNITS_KEPT => None,
+        units => Some(units != 0),
+    }
+}
+
+/// the session isn't paused
+const PAUSE_NONE: u8 = 0;
+/// paused, the acquisition is halted
+const PAUSE_HALTED: u8 = 1;
+/// paused, the acquisition keeps running, the datagrams are dropped
+const PAUSE_ACQUIRING: u8 = 2;
+/// the pause of the running session requested by `PAUSE` / `RESUME`
+static PAUSE: AtomicU8 = AtomicU8::new(PAUSE_NONE);
+
+/// pauses the running session from the next datagram, `acquiring` - the acquisition keeps running
+pub fn pause(acquiring: bool) {
+    PAUSE.store(if acquiring { PAUSE_ACQUIRING } else { PAUSE_HALTED }, Ordering::Relaxed);
+}
+
+/// resumes the paused session
+pub fn resume() {
+    PAUSE.store(PAUSE_NONE, Ordering::Relaxed);
+}
+
+/// Some if the session is paused, true - the acquisition keeps running
+pub fn paused() -> Option<bool> {
+    match PAUSE.load(Ordering::Relaxed) {
+        PAUSE_NONE => None,
+        pause => Some(pause == PAUSE_ACQUIRING),
+    }
+}
+
+/// the largest block `SET_BLOCK_SIZE` accepts for the running session, 0 - no session
+static BLOCK_LIMIT: AtomicU16 = AtomicU16::new(0);
+/// the block size requested by `SET_BLOCK_SIZE`, 0 - none
+static BLOCK_SIZE: AtomicU16 = AtomicU16::new(0);
+
+/// the running session accepts the blocks of up to `max` samples, 0 - the session ended
+pub fn setBlockLimit(max: u16) {
+    BLOCK_LIMIT.store(max, Ordering::Relaxed);
+    BLOCK_SIZE.store(0, Ordering::Relaxed);
+}
+
+/// the largest block the running session accepts, 0 - no session
+pub fn blockLimit() -> u16 {
+    BLOCK_LIMIT.load(Ordering::Relaxed)
+}
+
+/// requests the blocks of `samples` from the next logical block, returns false if it's out of `1..=blockLimit()`
+pub fn requestBlockSize(samples: u16) -> bool {
+    let valid = samples >= 1 && samples <= blockLimit();
+    if valid {
+        BLOCK_SIZE.store(samples, Ordering::Relaxed);
+    }
+    valid
+}
+
+/// the block size requested since the last call if any
+pub fn takeBlockSize() -> Option<u16> {
+    match BLOCK_SIZE.swap(0, Ordering::Relaxed) {
+        0 => None,
+        samples => Some(samples),
+    }
+}
+
+/// The running session
+pub struct Session {
+    pub remote: IpEndpoint,
+    /// the wire format of the datagrams, its flags are raised in every packet
+    pub stream: StreamFormat,
+    seq: u32,
+    flags: u8,
+    startSeq: u32,
+    started: Instant,
+    /// the index of the first sample of the next packet
+    sampleIndex: u64,
+    startIndex: u64,
+}
+
+impl Session {
+    /// new session starting from zero sequence
+    pub fn new(remote: IpEndpoint) -> Self {
+        Self::resumed(remote, 0, 0, 0)
+    }
+    /// session continuing the sequence `seq` and the sample index `sampleIndex`
+    pub fn resumed(remote: IpEndpoint, seq: u32, sampleIndex: u64, flags: u8) -> Self {
+        Self {
+            remote,
+            stream: StreamFormat::default(),
+            seq,
+            flags,
+            startSeq: seq,
+            started: Instant::now(),
+            sampleIndex,
+            startIndex: sampleIndex,
+        }
+    }
+    /// sequence number of the next packet
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+    /// the session index of the first sample of the next packet
+    pub fn sampleIndex(&self) -> u64 {
+        self.sampleIndex
+    }
+    /// header of the next packet
+    pub fn header(&self) -> PacketHeader {
+        PacketHeader {
+            flags: self.flags | self.stream.flags,
+            seq: self.seq,
+            ..Default::default()
+        }
+    }
+    /// raises the `flag` in the next packet
+    pub fn flag(&mut self, flag: u8) {
+        self.flags |= flag;
+    }
+    /// moves to the next packet after the current one of `samples` was sent
+    pub fn next(&mut self, samples: usize) {
+        self.seq = self.seq.wrapping_add(1);
+        self.sampleIndex += samples as u64;
+        self.flags = 0;
+    }
+    /// ends the session, returns its state to be resumed later
+    pub fn suspend(&self) -> Suspended {
+        let endedAt = Instant::now();
+        let blocks = self.seq.wrapping_sub(self.startSeq) as u64;
+        let blockPeriodUs = match blocks {
+            0 => 0,
+            _ => endedAt.duration_since(self.started).as_micros() / blocks,
+        };
+        let blockSamples = match blocks {
+            0 => 0,
+            _ => (self.sampleIndex - self.startIndex) / blocks,
+        };
+        Suspended {
+            addr: self.remote.addr,
+            nextSeq: self.seq,
+            nextIndex: self.sampleIndex,
+            blockPeriodUs,
+            blockSamples,
+            endedAt,
+        }
+    }
+}
+
+/// The ended session which can be resumed within `RESUME_WINDOW`
+pub struct Suspended {
+    addr: IpAddress,
+    nextSeq: u32,
+    nextIndex: u64,
+    blockPeriodUs: u64,
+    /// the average samples per packet
+    blockSamples: u64,
+    endedAt: Instant,
+}
+
+impl Suspended {
+    /// resumes the session for the client `remote`,
+    /// returns None if the client is different or the window is passed
+    pub fn resume(&self, remote: IpEndpoint, now: Instant) -> Option<(Session, u32)> {
+        if remote.addr != self.addr {
+            return None;
+        }
+        let elapsed = now.duration_since(self.endedAt);
+        if elapsed > RESUME_WINDOW {
+            return None;
+        }
+        let missed = match self.blockPeriodUs {
+            0 => 0,
+            period => (elapsed.as_micros() / period) as u32,
+        };
+        let seq = self.nextSeq.wrapping_add(missed);
+        let sampleIndex = self.nextIndex + missed as u64 * self.blockSamples;
+        Some((Session::resumed(remote, seq, sampleIndex, FLAG_RESUMED), missed))
+    }
+}
+
+/// Lifecycle of the data socket
+pub enum SessionState {
+    /// waiting for the address to bind the socket
+    Unbound,
+    /// bound, handling the commands, `pending` - the command to be handled before the next one is received
+    Idle { pending: Option<(Command, IpEndpoint)> },
+    /// streaming to the client of the handshake received at `received`
+    Streaming { handshake: Handshake, remote: IpEndpoint, received: Instant },
+}
+
+impl lifecycle::Socket for UdpSocket<'_> {
+    fn close(&mut self) {
+        UdpSocket::close(self)
+    }
+}
+
+/// What the phase entered starts with
+enum Entry {
+    Empty,
+    /// the command handled first in `Idle`
+    Pending(Command, IpEndpoint),
+    /// the handshake of the `Streaming` client
+    Stream(Handshake, IpEndpoint),
+}
+
+impl SessionState {
+    /// the state after the `socket` is bound, `autostart` - the client to stream to at once
+    pub fn bound(socket: &mut impl lifecycle::Socket, autostart: Option<IpEndpoint>) -> Self {
+        let entry = match autostart {
+            Some(remote) => Entry::Pending(Command::Handshake(Handshake::default()), remote),
+            None => Entry::Empty,
+        };
+        Self::next(socket, Phase::Unbound, Event::Bound, entry)
+    }
+    /// the state after the `socket` isn't bound: no address yet, the address conflicts or the bind failed
+    pub fn bindFailed(socket: &mut impl lifecycle::Socket) -> Self {
+        Self::next(socket, Phase::Unbound, Event::BindFailed, Entry::Empty)
+    }
+    /// the state after the `handshake` received from `remote` while idle
+    pub fn streaming(socket: &mut impl lifecycle::Socket, handshake: Handshake, remote: IpEndpoint) -> Self {
+        Self::next(socket, Phase::Idle, Event::Handshake, Entry::Stream(handshake, remote))
+    }
+    /// the state after the local address changed while idle
+    pub fn readdressed(socket: &mut impl lifecycle::Socket) -> Self {
+        Self::next(socket, Phase::Idle, Event::AddressChanged, Entry::Empty)
+    }
+    /// the state after the handshake is rejected before the session started, nothing is pending
+    pub fn rejected(socket: &mut impl lifecycle::Socket) -> Self {
+        Self::next(socket, Phase::Streaming, Event::Ended, Entry::Empty)
+    }
+    /// the state after the session ended by `end`
+    pub fn ended(socket: &mut impl lifecycle::Socket, end: Break) -> Self {
+        let event = end.event();
+        let entry = match end {
+            Break::Pending(command, remote) => Entry::Pending(command, remote),
+            _ => Entry::Empty,
+        };
+        Self::next(socket, Phase::Streaming, event, entry)
+    }
+    /// the state after the `event` in the `phase`, see `lifecycle::step`, `entry` - what the new phase starts with,
+    /// the event which can't happen there (never expected) binds the socket again
+    fn next(socket: &mut impl lifecycle::Socket, phase: Phase, event: Event, entry: Entry) -> Self {
+        let next = match lifecycle::step(socket, phase, event) {
+            Some(next) => next,
+            None => {
+                error!("[session] {:?} can't happen in {:?}, binding again", event, phase);
+                socket.close();
+                Phase::Unbound
+            }
+        };
+        match (next, entry) {
+            (Phase::Unbound, _) => Self::Unbound,
+            (Phase::Idle, Entry::Pending(command, remote)) => Self::Idle { pending: Some((command, remote)) },
+            (Phase::Streaming, Entry::Stream(handshake, remote)) => {
+                Self::Streaming { handshake, remote, received: Instant::now() }
+            }
+            // only the handshake starts streaming
+            (Phase::Idle | Phase::Streaming, _) => Self::Idle { pending: None },
+        }
+    }
+    /// the phase of the state, see `lifecycle`
+    pub fn phase(&self) -> Phase {
+        match self {
+            Self::Unbound => Phase::Unbound,
+            Self::Idle { .. } => Phase::Idle,
+            Self::Streaming { .. } => Phase::Streaming,
+        }
+    }
+}
+
+/// Reason to leave the session loop
+pub enum Break {
+    /// the session can't go on, `reason` - why
+    Closed(StreamEndReason),
+    /// stop received from the client
+    Stop,
+    /// command to be handled after the session ended (new handshake, SET_IP)
+    Pending(Command, IpEndpoint),
+    /// the local address has changed, the socket has to be bound again
+    Rebind,
+}
+
+impl Break {
+    /// why the session ended, reported to the client by `ENDED`
+    pub fn reason(&self) -> StreamEndReason {
+        match self {
+            Break::Closed(reason) => *reason,
+            Break::Stop => StreamEndReason::Stop,
+            Break::Pending(Command::Handshake(_), _) => StreamEndReason::Replaced,
+            Break::Pending(..) => StreamEndReason::Command,
+            Break::Rebind => StreamEndReason::AddressChanged,
+        }
+    }
+    /// the lifecycle event of the session end
+    pub fn event(&self) -> Event {
+        match self {
+            Break::Rebind => Event::AddressChanged,
+            _ => Event::Ended,
+        }
+    }
+}
+
+/// Why the session ended, sent to the client in the `ENDED` reply
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+#[repr(u8)]
+pub enum StreamEndReason {
+    /// stopped by the client, the clean end
+    Stop = 0,
+    /// the new handshake (of the same or the other client) replaced the session
+    Replaced = 1,
+    /// SET_IP or REBOOT is applied
+    Command = 2,
+    /// the local address has changed
+    AddressChanged = 3,
+    /// the data socket is closed
+    SocketClosed = 4,
+    /// the acknowledgment of the `CONFIRM_ACK` handshake isn't confirmed
+    NotConfirmed = 5,
+    /// the acknowledgment (pre-filled) can't be sent
+    AckFailed = 6,
+    /// paused for over `PAUSE_TIMEOUT`, the client is taken as gone
+    PauseTimeout = 7,
+    /// the link is down for over `LINK_FLAP_HOLD` (at once without the hold)
+    LinkLost = 8,
+}
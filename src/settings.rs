@@ -0,0 +1,236 @@
+//! Settings persisted in the last flash sector
+//!
+//! The sector 11 (256K at 0x081C0000) is reserved for the settings in `memory.x`.
+//! Layout, padded to `RECORD_SIZE`:
+//! ```not_rust
+//! | MAGIC: u32 | version: u8 | len: u8 | fields: [u8; len] | crc: u16 |
+//! fields of the version 2: | ip: [u8; 4] | prefix: u8 | gateway: [u8; 4] |
+//! ```
+//! crc - CRC-16 (see `crc`) of all the preceding bytes. The new version only appends the fields
+//! and raises `VERSION`, so the migration is positional: the record of the older version (the shorter `len`)
+//! gets the defaults for the fields it lacks, the newer one is read up to the known fields.
+//! The version 1 record (`MAGIC_V1 | ip | prefix | gateway`, no CRC) is migrated on load,
+//! the next `store` writes the current layout; the sector isn't rewritten at the boot (the erase takes seconds).
+//! The absent or corrupted record (the magic, the CRC, the length) or the invalid settings
+//! leave the compile-time defaults.
+//! The all zeros `ip` selects DHCP, the prefix and gateway are ignored then.
+//! The record codec runs on the host with the flash access left out (`crc` is compiled in):
+//! `rustc --edition 2021 --test src/settings.rs -o /tmp/settings && /tmp/settings`
+#[cfg(not(test))]
+use defmt::warn;
+#[cfg(not(test))]
+use embassy_stm32::flash::{Error, Flash};
+
+#[cfg(not(test))]
+use crate::crc;
+#[cfg(test)]
+#[path = "crc.rs"]
+mod crc;
+/// the log isn't checked by the host tests
+#[cfg(test)]
+macro_rules! warn {
+    ($($arg:tt)*) => {};
+}
+
+/// offset of the settings sector from the flash start
+#[cfg(not(test))]
+const SECTOR_OFFSET: u32 = 0x1C_0000;
+#[cfg(not(test))]
+const SECTOR_SIZE: u32 = 256 * 1024;
+/// whole record written at once, multiple of the flash write size
+const RECORD_SIZE: usize = 32;
+/// the versioned record
+const MAGIC: u32 = 0x5345_5453;
+/// the record before the versioned layout
+const MAGIC_V1: u32 = 0x5345_5431;
+/// the layout written by `store`
+const VERSION: u8 = 2;
+/// magic, version and len
+const PREFIX_SIZE: usize = 6;
+/// the fields of the current version
+const FIELDS_SIZE: usize = 9;
+const CRC_SIZE: usize = 2;
+const _: () = assert!(PREFIX_SIZE + FIELDS_SIZE + CRC_SIZE <= RECORD_SIZE);
+
+/// Network settings overriding the compile-time defaults
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Settings {
+    /// all zeros - DHCP
+    pub ip: [u8; 4],
+    pub prefix: u8,
+    /// all zeros - no gateway
+    pub gateway: [u8; 4],
+}
+
+impl Settings {
+    /// reads the settings from flash, returns None if absent, corrupted or invalid
+    #[cfg(not(test))]
+    pub fn load(flash: &mut Flash<'_>) -> Option<Self> {
+        let mut buf = [0u8; RECORD_SIZE];
+        flash.blocking_read(SECTOR_OFFSET, &mut buf).ok()?;
+        let settings = Self::decode(&buf)?;
+        match settings.isValid() {
+            true => Some(settings),
+            false => {
+                warn!("[settings] stored settings are invalid, the defaults are used");
+                None
+            }
+        }
+    }
+    /// writes the settings into flash in the current layout, erasing the settings sector
+    #[cfg(not(test))]
+    pub fn store(&self, flash: &mut Flash<'_>) -> Result<(), Error> {
+        let mut buf = [0xFFu8; RECORD_SIZE];
+        self.encode(&mut buf);
+        flash.blocking_erase(SECTOR_OFFSET, SECTOR_OFFSET + SECTOR_SIZE)?;
+        flash.blocking_write(SECTOR_OFFSET, &buf)
+    }
+    /// writes the record of the current version into `buf`
+    fn encode(&self, buf: &mut [u8; RECORD_SIZE]) {
+        buf[0..4].copy_from_slice(&MAGIC.to_be_bytes());
+        buf[4] = VERSION;
+        buf[5] = FIELDS_SIZE as u8;
+        let fields = &mut buf[PREFIX_SIZE..PREFIX_SIZE + FIELDS_SIZE];
+        fields[0..4].copy_from_slice(&self.ip);
+        fields[4] = self.prefix;
+        fields[5..9].copy_from_slice(&self.gateway);
+        let end = PREFIX_SIZE + FIELDS_SIZE;
+        let crc = crc::crc16(&buf[..end]);
+        buf[end..end + CRC_SIZE].copy_from_slice(&crc.to_be_bytes());
+    }
+    /// reads the record of any known version from `buf`, migrating it to the current one
+    fn decode(buf: &[u8; RECORD_SIZE]) -> Option<Self> {
+        match u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) {
+            MAGIC_V1 => Some(Self {
+                ip: [buf[4], buf[5], buf[6], buf[7]],
+                prefix: buf[8],
+                gateway: [buf[9], buf[10], buf[11], buf[12]],
+            }),
+            MAGIC => {
+                let (version, len) = (buf[4], buf[5] as usize);
+                let end = PREFIX_SIZE + len;
+                let Some(stored) = buf.get(end..end + CRC_SIZE) else {
+                    warn!("[settings] record length {} is out of the record", len);
+                    return None;
+                };
+                if crc::crc16(&buf[..end]) != u16::from_be_bytes([stored[0], stored[1]]) {
+                    warn!("[settings] record CRC mismatch, the defaults are used");
+                    return None;
+                }
+                if version != VERSION {
+                    warn!("[settings] record version {} migrated to {}", version, VERSION);
+                }
+                // the version 2 fields, present in all the versions so far
+                let fields = &buf[PREFIX_SIZE..end];
+                match fields {
+                    [i0, i1, i2, i3, prefix, g0, g1, g2, g3, ..] => Some(Self {
+                        ip: [*i0, *i1, *i2, *i3],
+                        prefix: *prefix,
+                        gateway: [*g0, *g1, *g2, *g3],
+                    }),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+    /// return true if the address is obtained by DHCP
+    pub fn isDhcp(&self) -> bool {
+        self.ip == [0, 0, 0, 0]
+    }
+    /// return true if DHCP is selected or the address is a unicast host address within the prefix
+    /// and the gateway (if any) is another host of the same subnet
+    pub fn isValid(&self) -> bool {
+        if self.isDhcp() {
+            return true;
+        }
+        if !(8..=30).contains(&self.prefix) {
+            return false;
+        }
+        let mask = u32::MAX << (32 - self.prefix);
+        let ip = u32::from_be_bytes(self.ip);
+        let isHost = |addr: u32| {
+            let host = addr & !mask;
+            host != 0 && host != !mask && (1..224).contains(&(addr >> 24)) && addr >> 24 != 127
+        };
+        if !isHost(ip) {
+            return false;
+        }
+        let gateway = u32::from_be_bytes(self.gateway);
+        gateway == 0 || (gateway != ip && gateway & mask == ip & mask && isHost(gateway))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATIC: Settings = Settings { ip: [192, 168, 100, 173], prefix: 24, gateway: [192, 168, 100, 1] };
+
+    fn encoded(settings: &Settings) -> [u8; RECORD_SIZE] {
+        let mut buf = [0xFF; RECORD_SIZE];
+        settings.encode(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn record_round_trip() {
+        assert_eq!(Settings::decode(&encoded(&STATIC)), Some(STATIC));
+    }
+
+    #[test]
+    fn corrupted_record_is_rejected() {
+        let mut buf = encoded(&STATIC);
+        buf[PREFIX_SIZE + 2] ^= 0x01;
+        assert_eq!(Settings::decode(&buf), None);
+        let mut buf = encoded(&STATIC);
+        buf[PREFIX_SIZE + FIELDS_SIZE] ^= 0x80;
+        assert_eq!(Settings::decode(&buf), None);
+        let mut buf = encoded(&STATIC);
+        buf[5] = (RECORD_SIZE - PREFIX_SIZE) as u8;
+        assert_eq!(Settings::decode(&buf), None);
+        let mut buf = encoded(&STATIC);
+        buf[0] = 0;
+        assert_eq!(Settings::decode(&buf), None);
+        assert_eq!(Settings::decode(&[0xFF; RECORD_SIZE]), None);
+    }
+
+    #[test]
+    fn v1_record_migrates_to_current_layout() {
+        let mut v1 = [0xFF; RECORD_SIZE];
+        v1[0..4].copy_from_slice(&MAGIC_V1.to_be_bytes());
+        v1[4..8].copy_from_slice(&STATIC.ip);
+        v1[8] = STATIC.prefix;
+        v1[9..13].copy_from_slice(&STATIC.gateway);
+        let settings = Settings::decode(&v1).unwrap();
+        assert_eq!(settings, STATIC);
+        let stored = encoded(&settings);
+        assert_eq!(u32::from_be_bytes([stored[0], stored[1], stored[2], stored[3]]), MAGIC);
+        assert_eq!((stored[4], stored[5]), (VERSION, FIELDS_SIZE as u8));
+        assert_eq!(Settings::decode(&stored), Some(STATIC));
+    }
+
+    #[test]
+    fn newer_record_is_read_up_to_known_fields() {
+        let mut buf = encoded(&STATIC);
+        let len = FIELDS_SIZE + 2;
+        buf[4] = VERSION + 1;
+        buf[5] = len as u8;
+        buf[PREFIX_SIZE + FIELDS_SIZE..PREFIX_SIZE + len].copy_from_slice(&[0xAB, 0xCD]);
+        let crc = crc::crc16(&buf[..PREFIX_SIZE + len]);
+        buf[PREFIX_SIZE + len..PREFIX_SIZE + len + CRC_SIZE].copy_from_slice(&crc.to_be_bytes());
+        assert_eq!(Settings::decode(&buf), Some(STATIC));
+    }
+
+    #[test]
+    fn validity() {
+        assert!(STATIC.isValid());
+        assert!(Settings { ip: [0; 4], prefix: 0, gateway: [1, 2, 3, 4] }.isValid());
+        assert!(Settings { gateway: [0; 4], ..STATIC }.isValid());
+        assert!(!Settings { prefix: 31, ..STATIC }.isValid());
+        assert!(!Settings { ip: [192, 168, 100, 255], ..STATIC }.isValid());
+        assert!(!Settings { ip: [127, 0, 0, 1], prefix: 8, gateway: [0; 4] }.isValid());
+        assert!(!Settings { gateway: [192, 168, 101, 1], ..STATIC }.isValid());
+        assert!(!Settings { gateway: STATIC.ip, ..STATIC }.isValid());
+    }
+}
@@ -0,0 +1,80 @@
+//! Output transport of the data packets
+//!
+//! `UdpSink` sends each packet as the datagram to the session client,
+//! `UartSink` writes it to the USART framed with the length prefix, for deployments
+//! bridging the stream via a companion MCU instead of the Ethernet:
+//! ```not_rust
+//! | len: u16 | packet... |
+//! ```
+//! `UdpSink` counts the sends which had to wait for the room in the socket TX buffer - it fills up
+//! when the Ethernet TX descriptors run dry - and the failed sends, see `stats`.
+//! The wait is detected by the first poll of the send being pending, nothing is timed.
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+
+use defmt::Format;
+use embassy_net::udp::{self, UdpSocket};
+use embassy_net::IpEndpoint;
+use embassy_stm32::peripherals::{DMA2_CH6, USART6};
+use embassy_stm32::usart::{self, UartTx};
+
+use crate::stats;
+
+/// Destination of the data packets
+pub trait Sink {
+    type Error: Format;
+    /// sends the whole `packet`
+    async fn send(&mut self, packet: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Sends the packets to the session client over UDP
+pub struct UdpSink<'s, 'a> {
+    socket: &'s UdpSocket<'a>,
+    remote: IpEndpoint,
+}
+
+impl<'s, 'a> UdpSink<'s, 'a> {
+    pub fn new(socket: &'s UdpSocket<'a>, remote: IpEndpoint) -> Self {
+        Self { socket, remote }
+    }
+}
+
+impl<'s, 'a> Sink for UdpSink<'s, 'a> {
+    type Error = udp::Error;
+    async fn send(&mut self, packet: &[u8]) -> Result<(), Self::Error> {
+        let mut sending = pin!(self.socket.send_to(packet, self.remote));
+        let mut first = true;
+        let result = poll_fn(|cx| {
+            let poll = sending.as_mut().poll(cx);
+            if first && poll.is_pending() {
+                stats::inc(&stats::TX_WAITS);
+            }
+            first = false;
+            poll
+        })
+        .await;
+        if result.is_err() {
+            stats::inc(&stats::TX_ERRORS);
+        }
+        result
+    }
+}
+
+/// Writes the length prefixed packets to USART6 (TX - PG14, Zio D1)
+pub struct UartSink<'d> {
+    uart: UartTx<'d, USART6, DMA2_CH6>,
+}
+
+impl<'d> UartSink<'d> {
+    pub fn new(uart: UartTx<'d, USART6, DMA2_CH6>) -> Self {
+        Self { uart }
+    }
+}
+
+impl<'d> Sink for UartSink<'d> {
+    type Error = usart::Error;
+    async fn send(&mut self, packet: &[u8]) -> Result<(), Self::Error> {
+        self.uart.write(&(packet.len() as u16).to_be_bytes()).await?;
+        self.uart.write(packet).await
+    }
+}
@@ -0,0 +1,185 @@
+//! Acquisition backends of the plain stream
+//!
+//! The plain stream of the single channel (no trigger, timestamps unless stamped by the source, injected channel,
+//! paging or alignment)
+//! is acquired by the `SampleSource` selected at build time:
+//! - `BlockingSource` (default) - the CPU starts each conversion and waits for it (see `Inputs::read`),
+//!   the sample rate is the loop rate, the block offsets are measured. The smallest code, no DMA stream
+//!   and no extra buffer, but the executor doesn't run while the datagram is acquired
+//!   and the acquisition stops while the datagram is sent.
+//!   `BlockingSource::lowJitter` masks the interrupts while the block is converted, so the conversions
+//!   aren't preempted (the Ethernet, the timer, the trigger EXTI), the window is bounded, between the windows
+//!   and while the datagram is sent the pending interrupts are served. The Ethernet DMA keeps receiving
+//!   into its descriptors meanwhile, the frames arriving after they are full are dropped (the control commands too),
+//!   the tasks including the watchdog feeding don't run, the bound is far below `WATCHDOG_TIMEOUT_US`.
+//! - `DmaSource` (the `adc-dma` feature) - the ADC converts continuously into the circular DMA buffer,
+//!   see `adc_dma`. The sample rate is the conversion rate (~173 kS/s at 27 MHz) without gaps between
+//!   the datagrams, the CPU is free while the half fills, the samples wait one datagram before sending.
+//!   Costs the DMA stream, the 2 KB buffer and ~1 KB of code, the offsets come from the nominal conversion time.
+//!   With `adc-dual` ADC2 interleaves its conversions of the same pin, doubling the rate, see `adc_dma`.
+//!   With `dma-stamps` the conversions are triggered by TIM4 and stamped by the TIM5 capture (`isStamped`),
+//!   so the timestamps format is acquired by it as well, the base stamp is the captured one, see `timestamp`.
+//! - `LoopbackSource` (the `loopback` feature, over the other two) - no conversions, the reference waveform
+//!   uploaded by the client is sent at the configured rate, see `loopback`.
+//!
+//! The other modes acquire sample by sample in the session loop.
+use embassy_stm32::adc::Adc;
+use embassy_stm32::peripherals::ADC1;
+use embassy_time::{Duration, Instant};
+
+#[cfg(feature = "adc-dma")]
+use crate::adc_dma::{self, AdcDma};
+#[cfg(feature = "adc-dma")]
+use crate::clocks;
+use crate::inputs::Inputs;
+use crate::timestamp;
+
+/// Acquisition of the consecutive samples of the single channel
+pub trait SampleSource {
+    /// fills `samples` with the consecutive conversions of `channel`, writes the start offsets
+    /// of its blocks of `blockSamples` in microseconds from the first one,
+    /// returns the timestamp counter at the first sample, None if the acquisition was interrupted
+    async fn fill(
+        &mut self,
+        inputs: &mut Inputs,
+        adc: &mut Adc<'_, ADC1>,
+        channel: u8,
+        samples: &mut [u16],
+        blockSamples: usize,
+        offsets: &mut [u32],
+    ) -> Option<u32>;
+    /// return true if the source captures the sample timestamps itself, see `stamps`
+    fn isStamped(&self) -> bool {
+        false
+    }
+    /// writes the stamps of every `TIMESTAMP_STRIDE`-th sample of the last `fill` into `out` if `isStamped`
+    fn stamps(&self, _out: &mut [u32]) {}
+    /// stops the acquisition at the session end
+    fn stop(&mut self);
+}
+
+/// Conversions started and waited for by the CPU
+#[derive(Default)]
+pub struct BlockingSource {
+    /// the longest window the interrupts are masked for, None - never masked
+    maxMasked: Option<Duration>,
+}
+
+impl BlockingSource {
+    pub fn new() -> Self {
+        Self { maxMasked: None }
+    }
+    /// the conversions of the block are taken with the interrupts masked for at most `maxMasked` at once
+    pub fn lowJitter(maxMasked: Duration) -> Self {
+        Self { maxMasked: Some(maxMasked) }
+    }
+}
+
+impl SampleSource for BlockingSource {
+    async fn fill(
+        &mut self,
+        inputs: &mut Inputs,
+        adc: &mut Adc<'_, ADC1>,
+        channel: u8,
+        samples: &mut [u16],
+        blockSamples: usize,
+        offsets: &mut [u32],
+    ) -> Option<u32> {
+        let started = Instant::now();
+        let stamp = timestamp::now();
+        for (block, offset) in samples.chunks_mut(blockSamples).zip(offsets.iter_mut()) {
+            *offset = Instant::now().duration_since(started).as_micros() as u32;
+            match self.maxMasked {
+                Some(maxMasked) => {
+                    let mut taken = 0;
+                    while taken < block.len() {
+                        // at least one sample per window, the pending interrupts are served between the windows
+                        taken += critical_section::with(|_| {
+                            let masked = Instant::now();
+                            let mut n = 0;
+                            for sample in block[taken..].iter_mut() {
+                                if n > 0 && masked.elapsed() >= maxMasked {
+                                    break;
+                                }
+                                *sample = inputs.read(adc, channel);
+                                n += 1;
+                            }
+                            n
+                        });
+                    }
+                }
+                None => {
+                    for sample in block.iter_mut() {
+                        *sample = inputs.read(adc, channel);
+                    }
+                }
+            }
+        }
+        Some(stamp)
+    }
+    fn stop(&mut self) {}
+}
+
+/// Continuous conversions moved by the circular DMA
+#[cfg(feature = "adc-dma")]
+pub struct DmaSource {
+    dma: AdcDma,
+    samplePeriodNs: u64,
+}
+
+#[cfg(feature = "adc-dma")]
+impl DmaSource {
+    /// `conversionCycles` - ADC clock cycles per conversion (of each ADC in the dual mode)
+    pub fn new(dma: AdcDma, conversionCycles: u32) -> Self {
+        let conversionsHz = clocks::adcHz() as u64 * dma.samplesPerConversion() as u64;
+        #[cfg(feature = "dma-stamps")]
+        let conversionCycles = match dma.isStamped() {
+            true => conversionCycles + adc_dma::PACE_MARGIN_CYCLES,
+            false => conversionCycles,
+        };
+        Self { samplePeriodNs: conversionCycles as u64 * 1_000_000_000 / conversionsHz, dma }
+    }
+}
+
+#[cfg(feature = "adc-dma")]
+impl SampleSource for DmaSource {
+    async fn fill(
+        &mut self,
+        inputs: &mut Inputs,
+        adc: &mut Adc<'_, ADC1>,
+        channel: u8,
+        samples: &mut [u16],
+        blockSamples: usize,
+        offsets: &mut [u32],
+    ) -> Option<u32> {
+        if !adc_dma::isRunning() || self.dma.half() != samples.len() {
+            // configures the channel, the sample is dropped
+            inputs.read(adc, channel);
+            self.dma.start(samples.len());
+        }
+        if !self.dma.next(samples).await {
+            return None;
+        }
+        // the half has just ended
+        let halfNs = samples.len() as u64 * self.samplePeriodNs;
+        let halfTicks = halfNs * timestamp::TICK_HZ as u64 / 1_000_000_000;
+        let stamp = match self.dma.takeStamps(&mut []) {
+            Some(captured) => captured,
+            None => timestamp::now().wrapping_sub(halfTicks as u32),
+        };
+        let blocks = (samples.len() + blockSamples - 1) / blockSamples;
+        for (n, offset) in offsets[..blocks].iter_mut().enumerate() {
+            *offset = ((n * blockSamples) as u64 * self.samplePeriodNs / 1000) as u32;
+        }
+        Some(stamp)
+    }
+    fn isStamped(&self) -> bool {
+        self.dma.isStamped()
+    }
+    fn stamps(&self, out: &mut [u32]) {
+        self.dma.takeStamps(out);
+    }
+    fn stop(&mut self) {
+        adc_dma::stop();
+    }
+}
@@ -0,0 +1,58 @@
+//! Stack usage high-water mark reported by the `STATS` command
+//!
+//! The stack is the RAM between the end of the statics (`__sheap` of `cortex-m-rt`, the board has no heap)
+//! and `_stack_start` (the RAM top, see `memory.x`), it grows down:
+//! ```not_rust
+//! | .data | .bss | .uninit | painted ... | used by the calls and the interrupts | _stack_start
+//!                          ^ __sheap      ^ high-water mark
+//! ```
+//! `paint` fills the free part below the current stack pointer with `PAINT` at the boot,
+//! the frames never reach it unless it's overwritten, so the lowest overwritten word is the deepest stack use.
+//! The embassy tasks (the `main` buffers too) live in the statics, not on the stack,
+//! the stack holds the synchronous call frames and the interrupt handlers.
+//! The stack overflow itself isn't detected, it silently overwrites the statics.
+use core::ptr;
+
+use cortex_m::register::msp;
+
+/// the word the free stack is filled with
+const PAINT: u32 = 0xC0DE_C0DE;
+/// the bytes below the stack pointer left unpainted for the frame of `paint` itself
+const MARGIN: u32 = 256;
+
+extern "C" {
+    static mut __sheap: u32;
+    static mut _stack_start: u32;
+}
+
+/// paints the free stack, called at the boot before the deep calls,
+/// the interrupts are masked meanwhile, their frames would be painted over
+pub fn paint() {
+    critical_section::with(|_| {
+        let bottom = unsafe { ptr::addr_of_mut!(__sheap) };
+        let top = msp::read().saturating_sub(MARGIN) as *mut u32;
+        let mut word = bottom;
+        while word < top {
+            unsafe {
+                ptr::write_volatile(word, PAINT);
+                word = word.add(1);
+            }
+        }
+    });
+}
+
+/// the stack size in bytes
+pub fn size() -> u32 {
+    unsafe { ptr::addr_of_mut!(_stack_start) as u32 - ptr::addr_of_mut!(__sheap) as u32 }
+}
+
+/// the deepest stack use since the boot in bytes, scans the painted words from the bottom
+pub fn used() -> u32 {
+    let bottom = unsafe { ptr::addr_of_mut!(__sheap) };
+    let top = unsafe { ptr::addr_of_mut!(_stack_start) };
+    let mut word = bottom;
+    while word < top && unsafe { ptr::read_volatile(word) } == PAINT {
+        word = unsafe { word.add(1) };
+    }
+    top as u32 - word as u32
+}
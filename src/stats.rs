@@ -0,0 +1,75 @@
+//! Board state reported by the `STATS` command
+//!
+//! Flags and counters are statics updated in place by the subsystems,
+//! the reply is a snapshot of them:
+//! ```not_rust
+//! | flags: u32 | adc faults: u32 | capture state: u8 | tx waits: u32 | tx errors: u32 | dma overruns: u32 |
+//! stack used: u32 | stack size: u32 | handshakes rejected: u32 | sample rate: u32 | rate limit: u32 |
+//! link drops: u32 |
+//! ```
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{pacer, rate, stack, trigger};
+
+/// the RNG failed to produce the non-trivial seed, the network stack seed is weak
+pub const FLAG_RNG_WEAK: u32 = 1 << 0;
+/// the measured sample rate deviates from the expected one, see `rate`
+pub const FLAG_RATE_DEVIATION: u32 = 1 << 1;
+/// size of the reply payload
+pub const SIZE: usize = 45;
+
+static FLAGS: AtomicU32 = AtomicU32::new(0);
+/// ADC conversions timed out
+pub static ADC_FAULTS: AtomicU32 = AtomicU32::new(0);
+/// data packets which waited for the room in the TX buffer (Ethernet TX descriptors exhausted)
+pub static TX_WAITS: AtomicU32 = AtomicU32::new(0);
+/// data packets failed to be sent
+pub static TX_ERRORS: AtomicU32 = AtomicU32::new(0);
+/// DMA acquisition halves overwritten before they were taken, see `adc_dma`
+pub static DMA_OVERRUNS: AtomicU32 = AtomicU32::new(0);
+/// handshakes dropped by the rate limit, see `churn`
+pub static HANDSHAKES_REJECTED: AtomicU32 = AtomicU32::new(0);
+/// data packets dropped while the link was down, see `link_hold`
+pub static LINK_DROPS: AtomicU32 = AtomicU32::new(0);
+
+/// raises the `flag`
+pub fn setFlag(flag: u32) {
+    FLAGS.fetch_or(flag, Ordering::Relaxed);
+}
+
+/// the raised flags
+pub fn flags() -> u32 {
+    FLAGS.load(Ordering::Relaxed)
+}
+
+/// clears the `flag`
+pub fn clearFlag(flag: u32) {
+    FLAGS.fetch_and(!flag, Ordering::Relaxed);
+}
+
+/// increments the `counter`
+pub fn inc(counter: &AtomicU32) {
+    add(counter, 1);
+}
+
+/// adds `count` to the `counter`
+pub fn add(counter: &AtomicU32, count: u32) {
+    counter.fetch_add(count, Ordering::Relaxed);
+}
+
+/// writes the STATS reply payload into `buf`, returns its length
+pub fn write(buf: &mut [u8]) -> usize {
+    buf[0..4].copy_from_slice(&flags().to_be_bytes());
+    buf[4..8].copy_from_slice(&ADC_FAULTS.load(Ordering::Relaxed).to_be_bytes());
+    buf[8] = trigger::state() as u8;
+    buf[9..13].copy_from_slice(&TX_WAITS.load(Ordering::Relaxed).to_be_bytes());
+    buf[13..17].copy_from_slice(&TX_ERRORS.load(Ordering::Relaxed).to_be_bytes());
+    buf[17..21].copy_from_slice(&DMA_OVERRUNS.load(Ordering::Relaxed).to_be_bytes());
+    buf[21..25].copy_from_slice(&stack::used().to_be_bytes());
+    buf[25..29].copy_from_slice(&stack::size().to_be_bytes());
+    buf[29..33].copy_from_slice(&HANDSHAKES_REJECTED.load(Ordering::Relaxed).to_be_bytes());
+    buf[33..37].copy_from_slice(&rate::rate().to_be_bytes());
+    buf[37..41].copy_from_slice(&pacer::limit().to_be_bytes());
+    buf[41..45].copy_from_slice(&LINK_DROPS.load(Ordering::Relaxed).to_be_bytes());
+    SIZE
+}
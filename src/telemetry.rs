@@ -0,0 +1,45 @@
+//! Out-of-band telemetry to the monitoring collector
+//!
+//! With `TELEMETRY_COLLECTOR` set the board sends the report to the collector every `TELEMETRY_PERIOD`
+//! from its own socket, independent of the data client, so the central monitor watches all the boards
+//! without touching their sessions:
+//! ```not_rust
+//! | SYN | TELEMETRY | seq: u32 | ip: [u8; 4] | mac: [u8; 6] | health... | stats... |
+//! ```
+//! seq - the report counter since the boot (the collector sees the lost ones), health - the `HEALTH` reply,
+//! stats - the `STATS` reply (the stream format prefix of the running session, channels 0 - not streaming).
+//! The task runs at the lowest priority with the other housekeeping, the stream isn't held up by it.
+//! The absent collector costs nothing: the report waiting for ARP (the collector or the gateway down)
+//! is given up after the period and dropped, logged once until the collector is back.
+use embassy_net::Ipv4Address;
+
+use crate::control_port::Running;
+use crate::packet::{StreamFormat, STREAM_FORMAT_SIZE};
+use crate::{control, health, stats};
+
+/// the local port the reports are sent from
+pub const PORT: u16 = 15182;
+/// the largest report
+pub const SIZE: usize = 2 + 14 + health::SIZE + STREAM_FORMAT_SIZE + stats::SIZE;
+
+/// writes the report `seq` into `buf`, `running` - the session if any, `linkUp` and `txCapacity` - see `health`,
+/// returns its length
+pub fn write(
+    buf: &mut [u8],
+    seq: u32,
+    addr: Ipv4Address,
+    mac: &[u8; 6],
+    running: Option<Running>,
+    linkUp: bool,
+    txCapacity: usize,
+) -> usize {
+    let mut payload = [0; SIZE - 2];
+    payload[0..4].copy_from_slice(&seq.to_be_bytes());
+    payload[4..8].copy_from_slice(addr.as_bytes());
+    payload[8..14].copy_from_slice(mac);
+    let len = 14 + health::write(&mut payload[14..], linkUp, txCapacity);
+    let stream = running.map_or(StreamFormat::default(), |running| running.stream);
+    let len = len + stream.write(&mut payload[len..]);
+    let len = len + stats::write(&mut payload[len..]);
+    control::reply(buf, control::TELEMETRY, &payload[..len])
+}
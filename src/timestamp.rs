@@ -0,0 +1,152 @@
+//! Free-running sample timestamp counter
+//!
+//! TIM5 (32 bit, TIM2 is taken by the time driver) counts up at `TICK_HZ`
+//! and wraps around every ~159 s, the acquisition reads it right after the conversion.
+//! The tick is a quarter of the 108 MHz APB1 timer clock, the same as the ADC clock.
+//! The timer clock not divisible into `TICK_HZ` (100 MHz at the 200 MHz SYSCLK) is refused at init,
+//! the stamps would run at the other rate than the clients decode.
+//!
+//! With the `dma-stamps` feature the DMA acquisition (see `adc_dma`) is stamped by the hardware:
+//! the conversions are triggered by TIM4 TRGO, the same edge is the internal trigger (ITR2) of TIM5,
+//! TIM5 CH1 (mapped on TRC) captures the counter at it and DMA1 stream 2 (channel 6) moves the captures
+//! into the circular buffer of two halves, the stamp `i` paired with the sample `i` of the ADC buffer:
+//! ```not_rust
+//! TIM4 TRGO --+--> ADC1 conversion --> DMA2 stream 0 --> | samples half 0 | samples half 1 |
+//!             +--> TIM5 CH1 capture --> DMA1 stream 2 --> | stamps half 0  | stamps half 1  |
+//! ```
+//! The stamp is the start of the sampling, exact to the tick, free of the CPU latency.
+//! The capture precedes its conversion, so the stamps half is complete when the samples one is.
+//! Without the feature the counter is read by the CPU, adding a few cycles of the latency to each stamp.
+use defmt::*;
+use embassy_stm32::pac;
+#[cfg(feature = "dma-stamps")]
+use embassy_stm32::pac::dma::vals;
+#[cfg(feature = "dma-stamps")]
+use embassy_stm32::peripherals::DMA1_CH2;
+use embassy_stm32::peripherals::TIM5;
+use embassy_stm32::rcc::low_level::RccPeripheral;
+
+/// timestamp counter rate
+pub const TICK_HZ: u32 = 27_000_000;
+/// DMA1 stream serving TIM5 CH1
+#[cfg(feature = "dma-stamps")]
+const STREAM: usize = 2;
+/// TIM5 CH1 request of the stream
+#[cfg(feature = "dma-stamps")]
+const CHANNEL: u8 = 6;
+/// TIM5 SMCR TS, ITR2 - the TIM4 trigger output
+#[cfg(feature = "dma-stamps")]
+const TS_ITR2: u8 = 0b010;
+/// TIM5 CCMR1 CC1S, IC1 mapped on TRC
+#[cfg(feature = "dma-stamps")]
+const CCS_TRC: u8 = 0b11;
+
+/// starts the counter, the timer is owned by it from now on,
+/// panics if the timer clock isn't a multiple of `TICK_HZ`
+pub fn init(_tim: TIM5) {
+    TIM5::enable();
+    let timerHz = TIM5::frequency().0;
+    if timerHz % TICK_HZ != 0 {
+        panic!("[timestamp] timer clock {} Hz isn't a multiple of {} Hz", timerHz, TICK_HZ);
+    }
+    let regs = pac::TIM5;
+    unsafe {
+        regs.psc().write(|w| w.set_psc((timerHz / TICK_HZ - 1) as u16));
+        regs.arr().write(|w| w.set_arr(u32::MAX));
+        // load the prescaler
+        regs.egr().write(|w| w.set_ug(true));
+        regs.cr1().modify(|w| w.set_cen(true));
+    }
+}
+
+/// the current counter value
+pub fn now() -> u32 {
+    unsafe { pac::TIM5.cnt().read().cnt() }
+}
+
+/// The counter captured at each conversion trigger of the DMA acquisition
+#[cfg(feature = "dma-stamps")]
+pub struct StampCapture {
+    buf: &'static mut [u32],
+    /// the length of the half
+    half: usize,
+}
+
+#[cfg(feature = "dma-stamps")]
+impl StampCapture {
+    /// `buf` - two halves of the longest block, as the samples buffer of `adc_dma`
+    pub fn new(_dma: DMA1_CH2, buf: &'static mut [u32]) -> Self {
+        unsafe { pac::RCC.ahb1enr().modify(|w| w.set_dma1en(true)) };
+        Self { buf, half: 0 }
+    }
+    /// starts capturing into the halves of `half` stamps, before the first trigger
+    pub fn start(&mut self, half: usize) {
+        let half = half.min(self.buf.len() / 2);
+        self.half = half;
+        let stream = pac::DMA1.st(STREAM);
+        let regs = pac::TIM5;
+        unsafe {
+            stream.cr().write(|w| w.set_en(false));
+            while stream.cr().read().en() {}
+            clearFlags();
+            stream.par().write_value(regs.ccr(0).ptr() as u32);
+            stream.m0ar().write_value(self.buf.as_mut_ptr() as u32);
+            stream.ndtr().write_value(pac::dma::regs::Ndtr((half * 2) as u32));
+            stream.cr().write(|w| {
+                w.set_chsel(CHANNEL);
+                w.set_dir(vals::Dir::PERIPHERALTOMEMORY);
+                w.set_psize(vals::Size::BITS32);
+                w.set_msize(vals::Size::BITS32);
+                w.set_pinc(vals::Inc::FIXED);
+                w.set_minc(vals::Inc::INCREMENTED);
+                w.set_circ(vals::Circ::ENABLED);
+                w.set_pl(vals::Pl::VERYHIGH);
+                w.set_en(true);
+            });
+            regs.smcr().modify(|w| w.set_ts(TS_ITR2));
+            // every trigger captured, the prescaler and the filter off
+            regs.ccmr_input(0).modify(|w| {
+                w.set_ccs(0, CCS_TRC);
+                w.set_icpsc(0, 0);
+                w.set_icf(0, 0);
+            });
+            regs.ccer().modify(|w| w.set_cce(0, true));
+            regs.dier().modify(|w| w.set_ccde(0, true));
+        }
+    }
+    /// copies the stamps of every `stride`-th sample of the `index` half (0 or 1) into `out`,
+    /// returns the stamp of its first sample, called as soon as the samples of the half are taken
+    pub fn take(&self, index: usize, stride: usize, out: &mut [u32]) -> u32 {
+        let start = index * self.half;
+        let half = &self.buf[start..start + self.half];
+        for (stamp, captured) in out.iter_mut().zip(half.iter().step_by(stride)) {
+            *stamp = *captured;
+        }
+        half[0]
+    }
+}
+
+/// stops the capture of `StampCapture`, the counter keeps running
+#[cfg(feature = "dma-stamps")]
+pub fn stopCapture() {
+    let regs = pac::TIM5;
+    unsafe {
+        regs.dier().modify(|w| w.set_ccde(0, false));
+        regs.ccer().modify(|w| w.set_cce(0, false));
+        pac::DMA1.st(STREAM).cr().modify(|w| w.set_en(false));
+        clearFlags();
+    }
+}
+
+#[cfg(feature = "dma-stamps")]
+fn clearFlags() {
+    unsafe {
+        pac::DMA1.ifcr(STREAM / 4).write(|w| {
+            w.set_htif(STREAM % 4, true);
+            w.set_tcif(STREAM % 4, true);
+            w.set_teif(STREAM % 4, true);
+            w.set_dmeif(STREAM % 4, true);
+            w.set_feif(STREAM % 4, true);
+        });
+    }
+}
@@ -0,0 +1,155 @@
+//! Type-length-value records extending the control packets
+//!
+//! ```not_rust
+//! | type: u8 | len: u8 | value: [u8; len] | type: u8 | ...
+//! ```
+//! The records follow each other up to the end of the packet,
+//! the reader skips the types it doesn't know, so the new records don't break the older firmware.
+//! The record running past the end of the packet makes the whole stream malformed.
+//! The module depends on `core` only, so its tests run on the host apart from the firmware:
+//! `rustc --edition 2021 --test src/tlv.rs -o /tmp/tlv && /tmp/tlv`
+use core::ops::RangeInclusive;
+
+/// The record is truncated, or breaks the `Rule` of its type
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(test), derive(defmt::Format))]
+pub struct Malformed;
+
+/// The shape of the known record type
+pub struct Rule {
+    pub kind: u8,
+    /// the allowed value length
+    pub len: RangeInclusive<u8>,
+    /// the record may appear more than once
+    pub repeated: bool,
+}
+
+/// Iterator over the records of the buffer
+pub struct Records<'a> {
+    buf: &'a [u8],
+}
+
+/// returns the records of `buf`
+pub fn records(buf: &[u8]) -> Records<'_> {
+    Records { buf }
+}
+
+impl<'a> Iterator for Records<'a> {
+    /// `(type, value)` or `Malformed`, after which the iteration ends
+    type Item = Result<(u8, &'a [u8]), Malformed>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.buf {
+            [] => None,
+            [kind, len, rest @ ..] if rest.len() >= *len as usize => {
+                let (value, tail) = rest.split_at(*len as usize);
+                self.buf = tail;
+                Some(Ok((*kind, value)))
+            }
+            _ => {
+                self.buf = &[];
+                Some(Err(Malformed))
+            }
+        }
+    }
+}
+
+/// checks the records of `buf` against the `rules` (up to 32) of the known types:
+/// the value length out of the rule or the repeated record is `Malformed`, the unknown types are skipped
+pub fn validate(buf: &[u8], rules: &[Rule]) -> Result<(), Malformed> {
+    let mut seen = 0u32;
+    for record in records(buf) {
+        let (kind, value) = record?;
+        if let Some(index) = rules.iter().position(|rule| rule.kind == kind) {
+            let rule = &rules[index];
+            if !rule.len.contains(&(value.len() as u8)) || (!rule.repeated && seen & (1 << index) != 0) {
+                return Err(Malformed);
+            }
+            seen |= 1 << index;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the rules of the handshake records, see `control::HANDSHAKE_RULES`
+    const RULES: [Rule; 3] = [
+        Rule { kind: 1, len: 3..=4, repeated: true },
+        Rule { kind: 3, len: 2..=2, repeated: false },
+        Rule { kind: 4, len: 1..=1, repeated: false },
+    ];
+
+    #[test]
+    fn records_in_order() {
+        let mut iter = records(&[1, 2, 0xAA, 0xBB, 4, 1, 0x01]);
+        assert_eq!(iter.next(), Some(Ok((1, &[0xAA, 0xBB][..]))));
+        assert_eq!(iter.next(), Some(Ok((4, &[0x01][..]))));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn empty_buffer_has_no_records() {
+        assert_eq!(records(&[]).next(), None);
+    }
+
+    #[test]
+    fn length_past_the_buffer_is_malformed() {
+        let mut iter = records(&[1, 3, 0xAA, 0xBB]);
+        assert_eq!(iter.next(), Some(Err(Malformed)));
+    }
+
+    #[test]
+    fn lone_kind_byte_is_malformed() {
+        let mut iter = records(&[4, 1, 0x01, 9]);
+        assert_eq!(iter.next(), Some(Ok((4, &[0x01][..]))));
+        assert_eq!(iter.next(), Some(Err(Malformed)));
+    }
+
+    #[test]
+    fn zero_length_record() {
+        let mut iter = records(&[9, 0, 4, 1, 0x01]);
+        assert_eq!(iter.next(), Some(Ok((9, &[][..]))));
+        assert_eq!(iter.next(), Some(Ok((4, &[0x01][..]))));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn iteration_stops_after_malformed() {
+        let mut iter = records(&[1, 200, 4, 1, 0x01]);
+        assert_eq!(iter.next(), Some(Err(Malformed)));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn unknown_kind_is_skipped() {
+        assert_eq!(validate(&[99, 3, 1, 2, 3, 4, 1, 0x01], &RULES), Ok(()));
+        assert_eq!(validate(&[99, 0, 99, 0], &RULES), Ok(()));
+    }
+
+    #[test]
+    fn repeated_record_is_malformed() {
+        assert_eq!(validate(&[4, 1, 0x00, 4, 1, 0x01], &RULES), Err(Malformed));
+        assert_eq!(validate(&[3, 2, 0, 2, 99, 0, 3, 2, 0, 4], &RULES), Err(Malformed));
+    }
+
+    #[test]
+    fn repeatable_record_may_repeat() {
+        assert_eq!(validate(&[1, 3, 0, 0, 1, 1, 4, 1, 0, 1, 2], &RULES), Ok(()));
+    }
+
+    #[test]
+    fn wrong_value_length_is_malformed() {
+        assert_eq!(validate(&[4, 2, 0x00, 0x01], &RULES), Err(Malformed));
+        assert_eq!(validate(&[4, 0], &RULES), Err(Malformed));
+        assert_eq!(validate(&[1, 2, 0, 0], &RULES), Err(Malformed));
+        assert_eq!(validate(&[1, 5, 0, 0, 0, 0, 0], &RULES), Err(Malformed));
+    }
+
+    #[test]
+    fn truncated_stream_fails_validation() {
+        assert_eq!(validate(&[4, 1, 0x01, 3, 2, 0], &RULES), Err(Malformed));
+    }
+}
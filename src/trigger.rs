@@ -0,0 +1,221 @@
+//! Threshold triggered capture with the pre-trigger history
+//!
+//! While armed the acquisition runs continuously into the circular buffer,
+//! when the sample crosses the threshold upward the last `pretrigger` samples are
+//! taken from the ring and the rest of the block is filled with the post-trigger samples,
+//! like the oscilloscope does.
+//! The trigger is not accepted until the ring holds `pretrigger` samples,
+//! so the trigger point is always at the same position in the block.
+//! The ring is written and snapshotted by the same (acquisition) loop,
+//! so the snapshot is always consistent.
+//! The crossings within the holdoff after the last trigger are ignored, so the noisy crossing
+//! bouncing around the threshold doesn't fire a flood of captures. The holdoff runs from the trigger point,
+//! the capture and the transfer count towards it, FORCE_TRIGGER isn't held off.
+//!
+//! The capture of the triggered session is controlled by the client:
+//! ```not_rust
+//! Idle --ARM--> Armed --crossing / FORCE_TRIGGER--> Triggered --> Transferring --> Armed
+//!   ^             |
+//!   +---DISARM----+
+//! ```
+//! The session starts armed, the capture re-arms itself after the transfer until disarmed,
+//! then it returns to `Idle`. DISARM during the capture lets it complete.
+//! The repeated commands change nothing. The state is kept in statics,
+//! the commands are handled between the acquisition batches of the same task.
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+
+/// State of the capture, reported in STATS
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+#[repr(u8)]
+pub enum CaptureState {
+    /// no triggered session or disarmed, the triggers are ignored
+    Idle = 0,
+    /// waiting for the trigger
+    Armed = 1,
+    /// acquiring the post-trigger samples
+    Triggered = 2,
+    /// sending the captured blocks
+    Transferring = 3,
+}
+
+static STATE: AtomicU8 = AtomicU8::new(CaptureState::Idle as u8);
+/// the triggered session is running
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// the capture is re-armed after the transfer
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// the next sample fires the trigger
+static FORCED: AtomicBool = AtomicBool::new(false);
+
+/// the current capture state
+pub fn state() -> CaptureState {
+    match STATE.load(Ordering::Relaxed) {
+        1 => CaptureState::Armed,
+        2 => CaptureState::Triggered,
+        3 => CaptureState::Transferring,
+        _ => CaptureState::Idle,
+    }
+}
+
+fn setState(state: CaptureState) {
+    STATE.store(state as u8, Ordering::Relaxed);
+}
+
+/// the triggered session started, armed
+pub fn begin() {
+    ACTIVE.store(true, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+    FORCED.store(false, Ordering::Relaxed);
+    setState(CaptureState::Armed);
+}
+
+/// the triggered session ended
+pub fn end() {
+    ACTIVE.store(false, Ordering::Relaxed);
+    ENABLED.store(false, Ordering::Relaxed);
+    FORCED.store(false, Ordering::Relaxed);
+    setState(CaptureState::Idle);
+}
+
+/// ARM command, returns false if there is no triggered session
+pub fn arm() -> bool {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return false;
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+    if state() == CaptureState::Idle {
+        setState(CaptureState::Armed);
+    }
+    true
+}
+
+/// DISARM command, returns false if there is no triggered session
+pub fn disarm() -> bool {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return false;
+    }
+    ENABLED.store(false, Ordering::Relaxed);
+    FORCED.store(false, Ordering::Relaxed);
+    if state() == CaptureState::Armed {
+        setState(CaptureState::Idle);
+    }
+    true
+}
+
+/// FORCE_TRIGGER command, arms the capture if needed and fires it at the next sample,
+/// ignored while capturing, returns false if there is no triggered session
+pub fn force() -> bool {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return false;
+    }
+    if matches!(state(), CaptureState::Idle | CaptureState::Armed) {
+        FORCED.store(true, Ordering::Relaxed);
+        setState(CaptureState::Armed);
+    }
+    true
+}
+
+/// return true if the trigger fires: the threshold is `crossed` or forced while armed
+pub fn fire(crossed: bool) -> bool {
+    if state() != CaptureState::Armed {
+        return false;
+    }
+    let fired = FORCED.swap(false, Ordering::Relaxed) || crossed;
+    if fired {
+        setState(CaptureState::Triggered);
+    }
+    fired
+}
+
+/// the captured blocks are being sent
+pub fn transferring() {
+    setState(CaptureState::Transferring);
+}
+
+/// the capture is done, re-arms it unless disarmed
+pub fn armNext() {
+    match ENABLED.load(Ordering::Relaxed) {
+        true => setState(CaptureState::Armed),
+        false => setState(CaptureState::Idle),
+    }
+}
+
+/// Circular buffer of the last `N` samples
+pub struct PreTrigger<const N: usize> {
+    ring: [u16; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> PreTrigger<N> {
+    pub fn new() -> Self {
+        Self {
+            ring: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+    /// drops the collected history
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+    /// number of the collected samples
+    pub fn collected(&self) -> usize {
+        self.len
+    }
+    /// appends the sample, overwriting the oldest one if the ring is full
+    pub fn push(&mut self, sample: u16) {
+        self.ring[self.head] = sample;
+        self.head = (self.head + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+    /// copies the last `dst.len()` samples in the acquisition order into `dst`,
+    /// returns the number of copied samples
+    pub fn snapshot(&self, dst: &mut [u16]) -> usize {
+        let count = dst.len().min(self.len);
+        let start = (self.head + N - count) % N;
+        for (i, sample) in dst[..count].iter_mut().enumerate() {
+            *sample = self.ring[(start + i) % N];
+        }
+        count
+    }
+}
+
+impl<const N: usize> Default for PreTrigger<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rising edge threshold trigger
+pub struct Trigger {
+    threshold: u16,
+    prev: u16,
+    /// the crossings ignored after the trigger
+    holdoff: Duration,
+    /// the last trigger point
+    last: Option<Instant>,
+}
+
+impl Trigger {
+    pub fn new(threshold: u16, holdoff: Duration) -> Self {
+        Self { threshold, prev: u16::MAX, holdoff, last: None }
+    }
+    /// the trigger fired at `at`, the holdoff starts
+    pub fn fired(&mut self, at: Instant) {
+        self.last = Some(at);
+    }
+    /// re-arms the trigger, the next crossing is detected from scratch
+    pub fn rearm(&mut self) {
+        self.prev = u16::MAX;
+    }
+    /// return true if the `sample` crosses the threshold upward out of the holdoff
+    pub fn check(&mut self, sample: u16) -> bool {
+        let crossed = self.prev < self.threshold && sample >= self.threshold;
+        self.prev = sample;
+        crossed && self.last.map_or(true, |last| last.elapsed() >= self.holdoff)
+    }
+}
@@ -0,0 +1,39 @@
+//! Wall clock kept by the RTC
+//!
+//! The host sets it by `SET_TIME` as the Unix time in seconds, the RTC keeps the two digit year,
+//! so only 2000..=2099 is accepted. The reply pairs the set time with the sample timestamp counter
+//! (see `timestamp`), so the host maps the stream stamps to the wall clock.
+//! The RTC runs from LSI by default, it drifts by up to a few percent, the host sets it again as needed.
+use chrono::NaiveDateTime;
+use defmt::Format;
+use embassy_stm32::peripherals::RTC;
+use embassy_stm32::rtc::{DateTime, Rtc};
+
+/// 2000-01-01T00:00:00Z
+pub const MIN_SECS: u32 = 946_684_800;
+/// 2100-01-01T00:00:00Z, excluded
+pub const MAX_SECS: u32 = 4_102_444_800;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Error {
+    /// the time is out of the RTC range
+    Invalid,
+    /// the RTC rejected the time
+    Rtc,
+}
+
+/// sets the RTC to the Unix time `secs`, returns the time read back
+pub fn set(rtc: &mut Rtc<'_, RTC>, secs: u32) -> Result<u32, Error> {
+    if !(MIN_SECS..MAX_SECS).contains(&secs) {
+        return Err(Error::Invalid);
+    }
+    let time = NaiveDateTime::from_timestamp_opt(secs as i64, 0).ok_or(Error::Invalid)?;
+    rtc.set_datetime(DateTime::from(time)).map_err(|_| Error::Rtc)?;
+    now(rtc).ok_or(Error::Rtc)
+}
+
+/// the current Unix time, None if the RTC isn't readable
+pub fn now(rtc: &Rtc<'_, RTC>) -> Option<u32> {
+    let time = NaiveDateTime::from(rtc.get_datetime().ok()?);
+    Some(time.timestamp() as u32)
+}